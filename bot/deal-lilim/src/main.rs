@@ -20,8 +20,14 @@ use serde::Deserialize;
 
 use interactive::commands::match_command;
 use interactive::commands::match_text;
+use interactive::matrix::chat_message;
+use interactive::matrix::send_reply;
 use tg_matrix_bridge::bridge_structs::Bridge;
-use tg_matrix_bridge::matrix_handlers::client_event_handler;
+use tg_matrix_bridge::bridge_structs::BridgeConfig;
+use tg_matrix_bridge::bridge_structs::DiscordConfig;
+use tg_matrix_bridge::bridge_structs::EncryptionConfig;
+use tg_matrix_bridge::bridge_structs::IrcConfig;
+use tg_matrix_bridge::bridge_structs::PortalConfig;
 
 #[derive(Deserialize)]
 struct LoginData {
@@ -36,6 +42,11 @@ struct User {
 	webhook_url: String,
 	anilist_ids: Vec<u64>,
 	bridges: Vec<Bridge>,
+	portals: Option<PortalConfig>,
+	#[serde(default)]
+	encryption: EncryptionConfig,
+	discord: Option<DiscordConfig>,
+	irc: Option<IrcConfig>,
 }
 
 fn sync_result_handler(res: Result<(), matrix_sdk::Error>) {
@@ -77,6 +88,7 @@ async fn main() -> anyhow::Result<()> {
 		Client::builder()
 			.sqlite_store("matrix_bot_sqlite", None)
 			.server_name(u.server_name())
+			.with_encryption_settings(tg_matrix_bridge::encryption::encryption_settings())
 			.build()
 			.await?,
 	);
@@ -114,19 +126,18 @@ async fn main() -> anyhow::Result<()> {
 		}
 	});
 
-	let bridges = Arc::new(user.bridges);
-	let webhook_url = Arc::new(user.webhook_url);
+	let bridge_config = BridgeConfig {
+		bridges: user.bridges,
+		webhook_url: user.webhook_url,
+		portals: user.portals,
+		encryption: user.encryption,
+		discord: user.discord,
+		irc: user.irc,
+	};
 
 	let bridge_client_dispatch = bridge_client.clone();
-	join_set.spawn(tg_matrix_bridge::dispatch(
-		bridge_client_dispatch,
-		bridges.clone(),
-		webhook_url,
-	));
+	join_set.spawn(tg_matrix_bridge::dispatch(bridge_client_dispatch, bridge_config));
 	join_set.spawn(async move {
-		bridge_client.add_event_handler(|ev, raw_event, room, client| {
-			client_event_handler(ev, raw_event, room, client, bridges)
-		});
 		loop {
 			let res =
 				bridge_client.sync(SyncSettings::default().timeout(Duration::from_secs(10))).await;
@@ -145,8 +156,12 @@ async fn main() -> anyhow::Result<()> {
 					if let (MessageType::Text(text), room) =
 						(original_message.content.msgtype.clone(), room.clone())
 					{
-						let _ = match_command(&room, &text, &original_message).await;
-						let _ = match_text(&room, &text, &original_message).await;
+						let message = chat_message(&room, &text, &original_message).await;
+						for reply in
+							[match_command(&message), match_text(&message)].into_iter().flatten()
+						{
+							let _ = send_reply(&room, &original_message, reply).await;
+						}
 					};
 				}
 			},
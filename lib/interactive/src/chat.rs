@@ -0,0 +1,24 @@
+use mime::Mime;
+
+// platform-neutral view of a message the commands can answer
+pub struct ChatMessage {
+	pub id: String,
+	pub sender: String,
+	pub text: String,
+	pub reply_text: Option<String>,
+}
+
+pub enum ChatReply {
+	Text(String),
+	File {
+		name: String,
+		mime: Mime,
+		data: Vec<u8>,
+	},
+}
+
+impl ChatReply {
+	pub fn text(text: &str) -> Self {
+		Self::Text(text.to_string())
+	}
+}
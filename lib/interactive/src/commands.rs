@@ -15,23 +15,10 @@ use zip::{
 	self,
 };
 
-use matrix_sdk::deserialized_responses::TimelineEvent;
-use matrix_sdk::room::Room;
-use matrix_sdk::ruma::api::client::message::send_message_event::v3::Response;
-use matrix_sdk::ruma::events::relation::Replacement;
-use matrix_sdk::ruma::events::room::message::MessageType;
-use matrix_sdk::ruma::events::room::message::Relation;
-use matrix_sdk::ruma::events::room::message::RoomMessageEvent;
-use matrix_sdk::ruma::events::room::message::RoomMessageEventContent;
-use matrix_sdk::ruma::events::room::message::TextMessageEventContent;
-use matrix_sdk::ruma::events::OriginalSyncMessageLikeEvent;
-use matrix_sdk::ruma::events::UnsignedRoomRedactionEvent;
-use matrix_sdk::ruma::OwnedRoomId;
-
-use matrix_sdk::ruma::events::room::message::sanitize::remove_plain_reply_fallback;
 use mime::Mime;
 
-use crate::utils::SendMessage;
+use crate::chat::ChatMessage;
+use crate::chat::ChatReply;
 
 fn chars_bytes_usize_sum(vec_str: &[&str]) -> Vec<usize> {
 	let mut list_n: Vec<usize> = vec![0, 0];
@@ -141,60 +128,6 @@ fn cmd(command: &str, args: Vec<&str>, stdin_str: Option<String>) -> String {
 	}
 }
 
-async fn get_original_text(reply_event: &TimelineEvent, room: &Room) -> Option<String> {
-	let unsigned_event =
-		reply_event.kind.raw().deserialize_as::<UnsignedRoomRedactionEvent>().ok()?;
-	let replace = unsigned_event.unsigned.relations.replace;
-	if let Some(replace) = replace {
-		let timeline_event = room.event(&replace.event_id, None).await.ok()?;
-		let room_message = timeline_event.kind.raw().deserialize_as::<RoomMessageEvent>().ok()?;
-		let original_message = room_message.as_original()?;
-		let relation = original_message.content.relates_to.clone()?;
-		let Relation::Replacement(Replacement {
-			new_content,
-			..
-		}) = relation
-		else {
-			return None;
-		};
-		match &new_content.msgtype {
-			MessageType::Text(text_message) => Some(text_message.body.clone()),
-			_ => None,
-		}
-	} else if let Ok(message) = reply_event.kind.raw().deserialize_as::<RoomMessageEvent>() {
-		let original = message.as_original()?;
-		let MessageType::Text(ref text_message) = original.content.msgtype else {
-			return None;
-		};
-		return match original.content.relates_to {
-			Some(Relation::Reply {
-				..
-			}) => Some(remove_plain_reply_fallback(&text_message.body).to_string()),
-			_ => Some(text_message.body.clone()),
-		};
-	} else {
-		return None;
-	}
-}
-
-async fn get_reply_text(
-	original_message: &OriginalSyncMessageLikeEvent<RoomMessageEventContent>,
-	room: &Room,
-) -> Option<String> {
-	let reply_id = match original_message.content.relates_to.clone() {
-		Some(Relation::Reply {
-			in_reply_to,
-		}) => in_reply_to.event_id,
-		Some(_) | None => return None,
-	};
-	let Ok(reply_event) = room.event(&reply_id, None).await else {
-		return None;
-	};
-	let reply_text = get_original_text(&reply_event, room).await?;
-
-	Some(reply_text)
-}
-
 fn ddurandom(arg: &str, limit: u64) -> String {
 	let tr_arg = match arg {
 		"digit" => "\"[:digit:]\"",
@@ -228,68 +161,41 @@ fn ddurandom(arg: &str, limit: u64) -> String {
 	}
 }
 
-pub async fn match_command(
-	room: &Room,
-	command: &TextMessageEventContent,
-	original_message: &OriginalSyncMessageLikeEvent<RoomMessageEventContent>,
-) -> Option<Response> {
-	let is_reply = original_message.content.relates_to.is_some();
-	let reply_text = get_reply_text(original_message, room).await;
-	let text = if is_reply {
-		remove_plain_reply_fallback(&command.body)
-	} else {
-		&command.body
-	};
+pub fn match_command(message: &ChatMessage) -> Option<ChatReply> {
+	let text = message.text.as_str();
 	let mut args = text.split_whitespace();
 
-	let original_message =
-		&original_message.clone().into_full_event(OwnedRoomId::from(room.room_id()));
-
 	let command = args.next()?;
 	match command.to_lowercase().as_str() {
 		"!bin" => {
 			let Some(args) = text.split_once(' ').map(|x| x.1) else {
-				return SendMessage::text(room, "missing arguments!")
-					.reply(original_message)
-					.await
-					.ok();
+				return Some(ChatReply::text("missing arguments!"));
 			};
 
-			let user_id = original_message.sender.as_str();
+			let user_id = message.sender.as_str();
 			let authorized_users = [
 				"@neek:matrix.archneek.me",
 				"@lakeotp:matrix.archneek.me",
 				"@slybianco:matrix.archneek.me",
 			];
-			let text = if authorized_users.iter().any(|&x| x == user_id) {
-				cmd("/bin/bash", vec!["-c", args], None)
-			} else {
-				return SendMessage::text(room, &format!("{user_id} permission denied"))
-					.reply(original_message)
-					.await
-					.ok();
-			};
-			SendMessage::text(room, &text).reply(original_message).await.ok()
+			if !authorized_users.iter().any(|&x| x == user_id) {
+				return Some(ChatReply::text(&format!("{user_id} permission denied")));
+			}
+			Some(ChatReply::text(&cmd("/bin/bash", vec!["-c", args], None)))
 		}
 		"!ddurandom" | "!random" | "!rand" => {
 			let arg = args.next().unwrap_or("alnum");
 			let limit = args.next().unwrap_or("20").parse::<u64>().unwrap_or(20);
-			let output = ddurandom(arg, limit);
-			SendMessage::text(room, &output).reply(original_message).await.ok()
+			Some(ChatReply::text(&ddurandom(arg, limit)))
 		}
-		"!ping" => SendMessage::text(room, "pong").reply(original_message).await.ok(),
+		"!ping" => Some(ChatReply::text("pong")),
 		"!sed" => {
-			let args = match text.split_once(' ') {
-				Some(split) => split.1,
-				None => return None,
-			};
-			let stdin_str = reply_text?;
-			let text = cmd("sed", vec!["--sandbox", args], Some(stdin_str));
-			SendMessage::text(room, &text).reply(original_message).await.ok()
+			let args = text.split_once(' ')?.1;
+			let stdin_str = message.reply_text.clone()?;
+			Some(ChatReply::text(&cmd("sed", vec!["--sandbox", args], Some(stdin_str))))
 		}
 		"!zip" | "!source" => {
-			let tmp_id = format!("{}{}", original_message.room_id, original_message.event_id);
-			let zip_name = format!("source{tmp_id}.zip");
+			let zip_name = format!("source{}.zip", message.id);
 			let Ok(file) = fs::OpenOptions::new()
 				.write(true)
 				.truncate(true)
@@ -313,39 +219,26 @@ pub async fn match_command(
 			let Ok(mime) = "application/zip".parse::<Mime>() else {
 				return None;
 			};
-			let Ok(file_content) = fs::read(&zip_name) else {
+			let Ok(data) = fs::read(&zip_name) else {
 				return None;
 			};
-			SendMessage::file(room.clone(), zip_name, (mime, file_content))
-				.await?
-				.reply(original_message)
-				.await
-				.ok()
+			Some(ChatReply::File {
+				name: zip_name,
+				mime,
+				data,
+			})
 		}
 		_ => None,
 	}
 }
 
-pub async fn match_text(
-	room: &Room,
-	text_message: &TextMessageEventContent,
-	original_message: &OriginalSyncMessageLikeEvent<RoomMessageEventContent>,
-) -> Option<Response> {
-	let is_reply = original_message.content.relates_to.is_some();
-	let text = if is_reply {
-		remove_plain_reply_fallback(&text_message.body)
-	} else {
-		&text_message.body
-	};
-	let reply_text = get_reply_text(original_message, room).await;
-	let original_message =
-		&original_message.clone().into_full_event(OwnedRoomId::from(room.room_id()));
-	match text.to_lowercase().as_str() {
+pub fn match_text(message: &ChatMessage) -> Option<ChatReply> {
+	match message.text.to_lowercase().as_str() {
 		"quanto fa" => {
-			let reply_text = reply_text?;
+			let reply_text = message.reply_text.as_deref()?;
 
 			let signs = vec![" x ", " * ", " + ", " - ", " / ", "*", "+", "-", "/"];
-			let (vec_str, sign) = sign_split_vec_str(&reply_text, &signs)?;
+			let (vec_str, sign) = sign_split_vec_str(reply_text, &signs)?;
 			let vec_usize = chars_bytes_usize_sum(&vec_str);
 
 			let result = match sign.trim() {
@@ -356,7 +249,7 @@ pub async fn match_text(
 				_ => return None,
 			};
 
-			SendMessage::text(room, &result).reply(original_message).await.ok()
+			Some(ChatReply::text(&result))
 		}
 		_ => None,
 	}
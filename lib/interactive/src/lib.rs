@@ -2,5 +2,7 @@
 #![allow(clippy::cast_possible_wrap)]
 #![allow(clippy::cast_precision_loss)]
 #![allow(clippy::must_use_candidate)]
+pub mod chat;
 pub mod commands;
+pub mod matrix;
 pub mod utils;
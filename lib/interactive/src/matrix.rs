@@ -0,0 +1,109 @@
+use matrix_sdk::deserialized_responses::TimelineEvent;
+use matrix_sdk::room::Room;
+use matrix_sdk::ruma::api::client::message::send_message_event::v3::Response;
+use matrix_sdk::ruma::events::relation::Replacement;
+use matrix_sdk::ruma::events::room::message::MessageType;
+use matrix_sdk::ruma::events::room::message::Relation;
+use matrix_sdk::ruma::events::room::message::RoomMessageEvent;
+use matrix_sdk::ruma::events::room::message::RoomMessageEventContent;
+use matrix_sdk::ruma::events::room::message::TextMessageEventContent;
+use matrix_sdk::ruma::events::OriginalSyncMessageLikeEvent;
+use matrix_sdk::ruma::events::UnsignedRoomRedactionEvent;
+use matrix_sdk::ruma::OwnedRoomId;
+
+use matrix_sdk::ruma::events::room::message::sanitize::remove_plain_reply_fallback;
+
+use crate::chat::ChatMessage;
+use crate::chat::ChatReply;
+use crate::utils::SendMessage;
+
+async fn get_original_text(reply_event: &TimelineEvent, room: &Room) -> Option<String> {
+	let unsigned_event =
+		reply_event.kind.raw().deserialize_as::<UnsignedRoomRedactionEvent>().ok()?;
+	let replace = unsigned_event.unsigned.relations.replace;
+	if let Some(replace) = replace {
+		let timeline_event = room.event(&replace.event_id, None).await.ok()?;
+		let room_message = timeline_event.kind.raw().deserialize_as::<RoomMessageEvent>().ok()?;
+		let original_message = room_message.as_original()?;
+		let relation = original_message.content.relates_to.clone()?;
+		let Relation::Replacement(Replacement {
+			new_content,
+			..
+		}) = relation
+		else {
+			return None;
+		};
+		match &new_content.msgtype {
+			MessageType::Text(text_message) => Some(text_message.body.clone()),
+			_ => None,
+		}
+	} else if let Ok(message) = reply_event.kind.raw().deserialize_as::<RoomMessageEvent>() {
+		let original = message.as_original()?;
+		let MessageType::Text(ref text_message) = original.content.msgtype else {
+			return None;
+		};
+		return match original.content.relates_to {
+			Some(Relation::Reply {
+				..
+			}) => Some(remove_plain_reply_fallback(&text_message.body).to_string()),
+			_ => Some(text_message.body.clone()),
+		};
+	} else {
+		return None;
+	}
+}
+
+async fn get_reply_text(
+	original_message: &OriginalSyncMessageLikeEvent<RoomMessageEventContent>,
+	room: &Room,
+) -> Option<String> {
+	let reply_id = match original_message.content.relates_to.clone() {
+		Some(Relation::Reply {
+			in_reply_to,
+		}) => in_reply_to.event_id,
+		Some(_) | None => return None,
+	};
+	let Ok(reply_event) = room.event(&reply_id, None).await else {
+		return None;
+	};
+	let reply_text = get_original_text(&reply_event, room).await?;
+
+	Some(reply_text)
+}
+
+pub async fn chat_message(
+	room: &Room,
+	text_message: &TextMessageEventContent,
+	original_message: &OriginalSyncMessageLikeEvent<RoomMessageEventContent>,
+) -> ChatMessage {
+	let is_reply = original_message.content.relates_to.is_some();
+	let text = if is_reply {
+		remove_plain_reply_fallback(&text_message.body)
+	} else {
+		&text_message.body
+	};
+	ChatMessage {
+		id: format!("{}{}", room.room_id(), original_message.event_id),
+		sender: original_message.sender.to_string(),
+		text: text.to_string(),
+		reply_text: get_reply_text(original_message, room).await,
+	}
+}
+
+pub async fn send_reply(
+	room: &Room,
+	original_message: &OriginalSyncMessageLikeEvent<RoomMessageEventContent>,
+	reply: ChatReply,
+) -> Option<Response> {
+	let original_message =
+		&original_message.clone().into_full_event(OwnedRoomId::from(room.room_id()));
+	let message = match reply {
+		ChatReply::Text(text) => SendMessage::text(room, &text),
+		ChatReply::File {
+			name,
+			mime,
+			data,
+		} => SendMessage::file(room.clone(), name, (mime, data)).await?,
+	};
+	message.reply(original_message).await.ok()
+}
@@ -0,0 +1,165 @@
+use std::sync::Arc;
+
+use anyhow::Context;
+use matrix_sdk::ruma::OwnedUserId;
+use matrix_sdk::ruma::RoomId;
+use matrix_sdk::ruma::UserId;
+use matrix_sdk::Room;
+use matrix_sdk::RoomMemberships;
+use teloxide::payloads::SendMessageSetters;
+use teloxide::prelude::Requester;
+use teloxide::types::ChatId;
+use teloxide::types::Message;
+use teloxide::types::ReplyParameters;
+use teloxide::utils::command::BotCommands;
+
+use crate::bridge_structs::Bridge;
+use crate::bridge_structs::BridgeContext;
+use crate::bridge_utils::tg_sender_id;
+
+const MAX_LISTED_MEMBERS: usize = 50;
+
+#[derive(BotCommands, Clone, Debug)]
+#[command(rename_rule = "lowercase")]
+pub enum BridgeCommand {
+	#[command(description = "show who a matrix user is")]
+	Whois(String),
+	#[command(description = "list the matrix members of this chat")]
+	Members,
+	#[command(description = "show the bridge status")]
+	Status,
+	#[command(description = "stop or resume bridging matrix messages here")]
+	Mute,
+	#[command(description = "stop or resume bridging your messages to matrix")]
+	NoBridge,
+}
+
+// answered on telegram only, commands never reach matrix
+pub async fn tg_command(
+	msg: Message,
+	cmd: BridgeCommand,
+	ctx: Arc<BridgeContext>,
+) -> anyhow::Result<()> {
+	let Some(bridge) = ctx.bridge_by_tg(msg.chat.id) else {
+		return Ok(());
+	};
+	let room =
+		ctx.client.get_room(&RoomId::parse(&bridge.mx_id)?).context("can't get matrix room")?;
+	let text = match cmd {
+		BridgeCommand::Whois(query) => whois(&ctx, &msg, &room, query.trim()).await?,
+		BridgeCommand::Members => members(&room).await?,
+		BridgeCommand::Status => status(&ctx, &bridge, &room),
+		BridgeCommand::Mute => mute(&ctx, &msg).await?,
+		BridgeCommand::NoBridge => no_bridge(&ctx, &msg)?,
+	};
+	ctx.bot
+		.send_message(msg.chat.id, text)
+		.reply_parameters(ReplyParameters::new(msg.id).allow_sending_without_reply())
+		.await?;
+	Ok(())
+}
+
+async fn whois(
+	ctx: &BridgeContext,
+	msg: &Message,
+	room: &Room,
+	query: &str,
+) -> anyhow::Result<String> {
+	let user_id = if query.is_empty() {
+		let Some(reply) = msg.reply_to_message() else {
+			return Ok("usage: /whois <matrix user>, or reply to a bridged message".to_string());
+		};
+		let Some(event_id) =
+			ctx.store.find_mx_event_id(room.room_id().as_str(), (reply.chat.id, reply.id))
+		else {
+			return Ok("that message isn't bridged".to_string());
+		};
+		let event = room.event(&event_id, None).await?;
+		event.kind.raw().get_field::<OwnedUserId>("sender")?.context("event has no sender")?
+	} else if let Ok(user_id) = UserId::parse(query) {
+		user_id
+	} else {
+		let members = room.members(RoomMemberships::JOIN).await?;
+		let member = members.iter().find(|member| {
+			member.display_name().is_some_and(|name| name.eq_ignore_ascii_case(query))
+		});
+		match member {
+			Some(member) => member.user_id().to_owned(),
+			None => return Ok(format!("no matrix member called {query}")),
+		}
+	};
+	let Some(member) = room.get_member(&user_id).await? else {
+		return Ok(format!("{user_id} isn't in the matrix room"));
+	};
+	Ok(format!(
+		"{}\n{}\nmembership: {}\npower level: {}",
+		member.name(),
+		member.user_id(),
+		member.membership().as_str(),
+		member.power_level(),
+	))
+}
+
+async fn members(room: &Room) -> anyhow::Result<String> {
+	let members = room.members(RoomMemberships::JOIN).await?;
+	let mut lines = members
+		.iter()
+		.map(|member| format!("{} ({})", member.name(), member.user_id()))
+		.collect::<Vec<String>>();
+	lines.sort_by_key(|line| line.to_lowercase());
+	let total = lines.len();
+	if total > MAX_LISTED_MEMBERS {
+		lines.truncate(MAX_LISTED_MEMBERS);
+		lines.push(format!("and {} more", total - MAX_LISTED_MEMBERS));
+	}
+	Ok(format!("{total} matrix members:\n{}", lines.join("\n")))
+}
+
+fn status(ctx: &BridgeContext, bridge: &Bridge, room: &Room) -> String {
+	let matrix = match ctx.client.user_id() {
+		Some(user_id) => format!("logged in as {user_id}"),
+		None => "not logged in".to_string(),
+	};
+	let direction = if bridge.read_only {
+		"telegram to matrix only"
+	} else {
+		"both ways"
+	};
+	let matrix_messages = if ctx.store.is_muted(ChatId(bridge.tg_id)) {
+		"muted"
+	} else {
+		"bridged"
+	};
+	format!(
+		"matrix: {matrix}\nroom: {} ({})\ndirection: {direction}\nmatrix messages: {matrix_messages}\nrecent bridged messages: {}",
+		room.name().unwrap_or_default(),
+		room.room_id(),
+		ctx.store.get_bms(room.room_id().as_str()).len(),
+	)
+}
+
+async fn mute(ctx: &BridgeContext, msg: &Message) -> anyhow::Result<String> {
+	let is_anonymous_admin = msg.sender_chat.as_ref().is_some_and(|chat| chat.id == msg.chat.id);
+	if !is_anonymous_admin {
+		let user = msg.from.as_ref().context("message has no sender")?;
+		let member = ctx.bot.get_chat_member(msg.chat.id, user.id).await?;
+		if !member.is_privileged() {
+			return Ok("only chat admins can mute the bridge".to_string());
+		}
+	}
+	let text = if ctx.store.toggle_muted(msg.chat.id)? {
+		"matrix messages won't be bridged here until /mute is sent again"
+	} else {
+		"matrix messages are bridged again"
+	};
+	Ok(text.to_string())
+}
+
+fn no_bridge(ctx: &BridgeContext, msg: &Message) -> anyhow::Result<String> {
+	let text = if ctx.store.toggle_opted_out(&tg_sender_id(msg))? {
+		"your messages won't be bridged to matrix until /nobridge is sent again"
+	} else {
+		"your messages are bridged to matrix again"
+	};
+	Ok(text.to_string())
+}
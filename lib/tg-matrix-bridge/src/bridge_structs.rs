@@ -1,47 +1,279 @@
-use std::sync::LazyLock;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::RwLock;
 
 use anyhow::bail;
 use anyhow::Context;
+use matrix_sdk::encryption::verification::SasVerification;
 use matrix_sdk::ruma::events::room::message::MessageType;
 use matrix_sdk::ruma::events::AnyMessageLikeEventContent;
 use matrix_sdk::ruma::events::OriginalMessageLikeEvent;
+use matrix_sdk::Client;
 use serde::Deserialize;
+use serde::Serialize;
 use teloxide::adaptors::Throttle;
 use teloxide::types::ChatId;
 use teloxide::Bot;
 
-#[derive(Clone)]
-pub enum TgMessageKind {
-	Text,
-	Photo,
-	Sticker,
-	Video,
-	Document,
+use crate::db::Store;
+use crate::discord::DiscordNetwork;
+use crate::irc::IrcNetwork;
+use crate::queue::ChatQueues;
+use crate::telegram::TelegramNetwork;
+use crate::templates::SenderTemplates;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum LinkPreviewPolicy {
+	#[default]
+	Always,
+	Never,
+	FirstLink,
+	LargeMedia,
 }
 
-#[derive(Deserialize)]
+#[derive(Serialize, Deserialize, Clone, Default)]
 pub struct Bridge {
 	pub mx_id: String,
 	pub tg_id: i64,
+	#[serde(default)]
+	pub read_only: bool,
+	#[serde(default)]
+	pub templates: SenderTemplates,
+	#[serde(default)]
+	pub link_previews: LinkPreviewPolicy,
+	// adds the previewed link of telegram messages as a quote block on matrix
+	#[serde(default)]
+	pub preview_blocks: bool,
+}
+
+// rooms created for telegram groups the bot is added to
+#[derive(Deserialize, Clone, Default)]
+pub struct PortalConfig {
+	// space the created rooms are added to
+	pub space_id: Option<String>,
+	// matrix users invited to every created room
+	#[serde(default)]
+	pub admins: Vec<String>,
+	// telegram users who can create a portal by adding the bot, nobody if empty
+	#[serde(default)]
+	pub allowed_tg_users: Vec<u64>,
+}
+
+#[derive(Deserialize, Clone, Default)]
+pub struct EncryptionConfig {
+	// unlocks the secret storage holding the cross-signing keys and the key backup
+	pub recovery_key: Option<String>,
+	// matrix users allowed to verify the bridge device
+	#[serde(default)]
+	pub admins: Vec<String>,
+}
+
+#[derive(Deserialize, Clone, Default)]
+pub struct DiscordBridge {
+	pub mx_id: String,
+	pub channel_id: String,
+	#[serde(default)]
+	pub read_only: bool,
+	#[serde(default)]
+	pub templates: SenderTemplates,
+}
+
+fn default_discord_api_url() -> String {
+	"https://discord.com/api/v10".to_string()
+}
+
+#[derive(Deserialize, Clone)]
+pub struct DiscordConfig {
+	pub token: String,
+	pub bridges: Vec<DiscordBridge>,
+	#[serde(default = "default_discord_api_url")]
+	pub api_url: String,
+}
+
+#[derive(Deserialize, Clone, Default)]
+pub struct IrcBridge {
+	pub mx_id: String,
+	pub channel: String,
+	#[serde(default)]
+	pub read_only: bool,
+	#[serde(default)]
+	pub templates: SenderTemplates,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct IrcSasl {
+	pub username: String,
+	pub password: String,
+}
+
+fn default_irc_port() -> u16 {
+	6697
+}
+
+fn default_irc_tls() -> bool {
+	true
+}
+
+#[derive(Deserialize, Clone)]
+pub struct IrcConfig {
+	pub server: String,
+	#[serde(default = "default_irc_port")]
+	pub port: u16,
+	#[serde(default = "default_irc_tls")]
+	pub tls: bool,
+	pub nick: String,
+	pub sasl: Option<IrcSasl>,
+	// public base url of a proxy serving matrix media without authentication, media is
+	// linked as {media_url}/{server_name}/{media_id}. homeservers no longer serve media
+	// to unauthenticated clients since matrix 1.11 (MSC3916), so irc users can't be sent
+	// to the homeserver itself
+	pub media_url: String,
+	pub bridges: Vec<IrcBridge>,
+}
+
+#[derive(Clone)]
+pub struct BridgeConfig {
+	pub bridges: Vec<Bridge>,
+	pub webhook_url: String,
+	pub portals: Option<PortalConfig>,
+	pub encryption: EncryptionConfig,
+	pub discord: Option<DiscordConfig>,
+	pub irc: Option<IrcConfig>,
 }
 
-#[derive(Default, Clone)]
-pub struct BmTgData {
-	pub bot: Option<Throttle<Bot>>,
-	pub chat_id: Option<ChatId>,
-	pub message: Vec<u8>,
-	pub tg_message_kind: Option<TgMessageKind>,
-	pub caption: Option<String>,
-	pub is_preview_disabled: bool,
+#[derive(Default)]
+struct Bridges {
+	by_mx: HashMap<String, Arc<Bridge>>,
+	by_tg: HashMap<i64, Arc<Bridge>>,
+	discord_by_mx: HashMap<String, Arc<DiscordBridge>>,
+	discord_by_channel: HashMap<String, Arc<DiscordBridge>>,
+	irc_by_mx: HashMap<String, Arc<IrcBridge>>,
+	// irc channel names are case insensitive, the keys are lowercase
+	irc_by_channel: HashMap<String, Arc<IrcBridge>>,
+}
+
+pub struct BridgeContext {
+	pub bot: Throttle<Bot>,
+	pub network: TelegramNetwork,
+	pub discord: Option<DiscordNetwork>,
+	pub irc: Option<IrcNetwork>,
+	pub client: Arc<Client>,
+	pub store: Store,
+	pub queues: ChatQueues,
+	pub config: BridgeConfig,
+	// emoji verification waiting for an admin's !verify
+	pub pending_sas: Mutex<Option<SasVerification>>,
+	bridges: RwLock<Bridges>,
+}
+
+impl BridgeContext {
+	#[must_use]
+	pub fn new(
+		bot: Throttle<Bot>,
+		client: Arc<Client>,
+		store: Store,
+		config: BridgeConfig,
+	) -> Self {
+		let portals = store.get_portals();
+		let ctx = Self {
+			network: TelegramNetwork::new(bot.clone()),
+			discord: config.discord.as_ref().map(DiscordNetwork::new),
+			irc: config.irc.as_ref().map(IrcNetwork::new),
+			bot,
+			client,
+			store,
+			queues: ChatQueues::default(),
+			config,
+			pending_sas: Mutex::default(),
+			bridges: RwLock::default(),
+		};
+		for bridge in ctx.config.bridges.iter().cloned().chain(portals) {
+			ctx.add_bridge(bridge);
+		}
+		let mut bridges = ctx.bridges.write().unwrap();
+		for bridge in ctx.config.discord.iter().flat_map(|discord| discord.bridges.iter()) {
+			let bridge = Arc::new(bridge.clone());
+			bridges.discord_by_mx.insert(bridge.mx_id.clone(), bridge.clone());
+			bridges.discord_by_channel.insert(bridge.channel_id.clone(), bridge);
+		}
+		for bridge in ctx.config.irc.iter().flat_map(|irc| irc.bridges.iter()) {
+			let bridge = Arc::new(bridge.clone());
+			bridges.irc_by_mx.insert(bridge.mx_id.clone(), bridge.clone());
+			bridges.irc_by_channel.insert(bridge.channel.to_lowercase(), bridge);
+		}
+		drop(bridges);
+		ctx
+	}
+
+	#[allow(clippy::missing_panics_doc)]
+	pub fn add_bridge(&self, bridge: Bridge) {
+		let bridge = Arc::new(bridge);
+		let mut bridges = self.bridges.write().unwrap();
+		bridges.by_mx.insert(bridge.mx_id.clone(), bridge.clone());
+		bridges.by_tg.insert(bridge.tg_id, bridge);
+	}
+
+	// telegram gives a group a new id when it's upgraded to a supergroup
+	#[allow(clippy::missing_panics_doc)]
+	pub fn move_bridge(&self, old_tg_id: ChatId, bridge: Bridge) {
+		self.bridges.write().unwrap().by_tg.remove(&old_tg_id.0);
+		self.add_bridge(bridge);
+	}
+
+	#[allow(clippy::missing_panics_doc)]
+	#[must_use]
+	pub fn bridges(&self) -> Vec<Arc<Bridge>> {
+		self.bridges.read().unwrap().by_mx.values().cloned().collect()
+	}
+
+	#[allow(clippy::missing_panics_doc)]
+	#[must_use]
+	pub fn bridge_by_mx(&self, mx_id: &str) -> Option<Arc<Bridge>> {
+		self.bridges.read().unwrap().by_mx.get(mx_id).cloned()
+	}
+
+	#[allow(clippy::missing_panics_doc)]
+	#[must_use]
+	pub fn bridge_by_tg(&self, tg_id: ChatId) -> Option<Arc<Bridge>> {
+		self.bridges.read().unwrap().by_tg.get(&tg_id.0).cloned()
+	}
+
+	#[allow(clippy::missing_panics_doc)]
+	#[must_use]
+	pub fn discord_bridge_by_mx(&self, mx_id: &str) -> Option<Arc<DiscordBridge>> {
+		self.bridges.read().unwrap().discord_by_mx.get(mx_id).cloned()
+	}
+
+	#[allow(clippy::missing_panics_doc)]
+	#[must_use]
+	pub fn discord_bridge_by_channel(&self, channel_id: &str) -> Option<Arc<DiscordBridge>> {
+		self.bridges.read().unwrap().discord_by_channel.get(channel_id).cloned()
+	}
+
+	#[allow(clippy::missing_panics_doc)]
+	#[must_use]
+	pub fn irc_bridge_by_mx(&self, mx_id: &str) -> Option<Arc<IrcBridge>> {
+		self.bridges.read().unwrap().irc_by_mx.get(mx_id).cloned()
+	}
+
+	#[allow(clippy::missing_panics_doc)]
+	#[must_use]
+	pub fn irc_bridge_by_channel(&self, channel: &str) -> Option<Arc<IrcBridge>> {
+		self.bridges.read().unwrap().irc_by_channel.get(&channel.to_lowercase()).cloned()
+	}
 }
 
 pub struct BmMxData<'a> {
 	pub mx_event: &'a OriginalMessageLikeEvent<AnyMessageLikeEventContent>,
-	pub mx_msg_type: &'a MessageType,
 	pub room: matrix_sdk::Room,
 }
 
-pub static BM_FILE_PATH: LazyLock<&str> = LazyLock::new(|| "bridged_messages/");
+pub const BM_FILE_PATH: &str = "bridged_messages/";
+
+pub const CONTENT_WARNING_KEY: &str = "town.robin.msc3725.content_warning";
+pub const CONTENT_WARNING_SPOILER: &str = "town.robin.msc3725.spoiler";
 
 pub type MatrixMedia = (String, Vec<u8>, MessageType);
 
@@ -1,43 +1,40 @@
-use std::fs::File;
-use std::path::Path;
+use std::io::Cursor;
+use std::ops::Range;
 
 use anyhow::bail;
+use anyhow::Context;
+use image::imageops::FilterType;
+use image::DynamicImage;
+use image::ImageFormat;
 
-use matrix_sdk::media::MediaEventContent;
-use matrix_sdk::ruma::events::room::message::FileMessageEventContent;
-use matrix_sdk::ruma::events::room::message::ImageMessageEventContent;
 use matrix_sdk::ruma::events::room::message::MessageType;
-use matrix_sdk::ruma::events::room::message::Relation;
-use matrix_sdk::ruma::events::room::message::RoomMessageEventContent;
-use matrix_sdk::ruma::events::room::message::VideoMessageEventContent;
-use matrix_sdk::ruma::events::AnyMessageLikeEventContent;
-use matrix_sdk::ruma::OwnedEventId;
+use matrix_sdk::ruma::events::room::power_levels::RoomPowerLevelsEventContent;
+use matrix_sdk::ruma::events::room::ImageInfo;
+use matrix_sdk::ruma::Int;
+use matrix_sdk::ruma::OwnedTransactionId;
+use matrix_sdk::ruma::UInt;
+use matrix_sdk::ruma::UserId;
 use matrix_sdk::Client;
 
 use teloxide::adaptors::throttle::Limits;
 use teloxide::adaptors::Throttle;
-use teloxide::payloads::SendDocumentSetters;
-use teloxide::payloads::SendMessageSetters;
-use teloxide::payloads::SendPhotoSetters;
-use teloxide::payloads::SendStickerSetters;
-use teloxide::payloads::SendVideoSetters;
+use teloxide::net::Download;
 use teloxide::prelude::Requester;
 use teloxide::prelude::RequesterExt;
-use teloxide::types::ChatId;
-use teloxide::types::InputFile;
-use teloxide::types::LinkPreviewOptions;
+use teloxide::types::FileMeta;
+use teloxide::types::Location;
+use teloxide::types::MediaKind;
 use teloxide::types::Message;
-use teloxide::types::MessageId;
-use teloxide::types::ReplyParameters;
+use teloxide::types::MessageEntityKind;
+use teloxide::types::MessageEntityRef;
+use teloxide::types::MessageKind;
+use teloxide::types::MessageOrigin;
+use teloxide::types::Poll;
+use teloxide::types::Sticker;
 use teloxide::Bot;
-use teloxide::RequestError;
 
-use crate::bridge_structs::BmMxData;
-use crate::bridge_structs::BmTgData;
-use crate::bridge_structs::Bridge;
 use crate::bridge_structs::GetMatrixMedia;
-use crate::bridge_structs::TgMessageKind;
-use crate::db::BridgedMessage;
+use crate::templates::Sender;
 
 pub async fn get_matrix_media(
 	client: Client,
@@ -60,7 +57,11 @@ pub async fn get_tg_bot() -> Throttle<teloxide::Bot> {
 pub fn get_user_name(msg: &Message) -> anyhow::Result<String> {
 	let name = if let Some(chat) = &msg.sender_chat {
 		if chat.is_channel() {
-			chat.title().unwrap_or_default().to_string()
+			let title = chat.title().unwrap_or_default();
+			match msg.author_signature() {
+				Some(signature) => format!("{title} ({signature})"),
+				None => title.to_string(),
+			}
 		} else {
 			bail!("chat isn't a channel, name not found")
 		}
@@ -72,180 +73,267 @@ pub fn get_user_name(msg: &Message) -> anyhow::Result<String> {
 	Ok(name)
 }
 
-pub fn update_bridged_messages(
-	matrix_event_id: OwnedEventId,
-	telegram_event_id: (ChatId, MessageId),
-	matrix_chat_id: &str,
-) -> anyhow::Result<()> {
-	let bm_file_path = format!("bridged_messages/{matrix_chat_id}.mpk");
-	if !Path::new(&bm_file_path).exists() {
-		File::create_new(&bm_file_path)?;
+pub fn get_tg_sender(msg: &Message) -> anyhow::Result<Sender> {
+	let name = get_user_name(msg)?;
+	let username = match &msg.sender_chat {
+		Some(chat) => chat.username(),
+		None => msg.from.as_ref().and_then(|user| user.username.as_deref()),
+	};
+	Ok(Sender {
+		username: username.map_or_else(|| name.clone(), |username| format!("@{username}")),
+		name,
+	})
+}
+
+// stable id used for per-user settings, anonymous admins and channels use the sender chat
+#[must_use]
+pub fn tg_sender_id(msg: &Message) -> String {
+	match (&msg.sender_chat, &msg.from) {
+		(Some(chat), _) => format!("tg:{}", chat.id),
+		(None, Some(user)) => format!("tg:{}", user.id),
+		(None, None) => format!("tg:{}", msg.chat.id),
 	}
-	let mut bridged_messages: Vec<BridgedMessage> =
-		match rmp_serde::decode::from_read(File::open(&bm_file_path)?) {
-			Ok(bm) => bm,
-			Err(e) => {
-				log::debug!("{}:{}:{}", file!(), line!(), e);
-				vec![]
-			}
-		};
-	bridged_messages.push(BridgedMessage {
-		matrix_id: matrix_event_id,
-		telegram_id: (telegram_event_id.0, telegram_event_id.1),
-	});
-	bridged_messages.reverse();
-	bridged_messages.truncate(1000);
-	bridged_messages.reverse();
-	rmp_serde::encode::write(&mut File::create(bm_file_path)?, &bridged_messages)?;
+}
+
+pub async fn get_mx_sender(room: &matrix_sdk::Room, user_id: &UserId) -> Sender {
+	let member = room.get_member_no_sync(user_id).await.ok().flatten();
+	Sender {
+		name: member
+			.as_ref()
+			.and_then(|member| member.display_name())
+			.unwrap_or(user_id.as_str())
+			.to_string(),
+		username: user_id.to_string(),
+	}
+}
+
+// only moderators can post, telegram channels are one way
+pub async fn make_room_read_only(room: &matrix_sdk::Room) -> anyhow::Result<()> {
+	let mut power_levels = room.power_levels().await?;
+	let moderator = Int::from(50);
+	if power_levels.events_default >= moderator {
+		return Ok(());
+	}
+	let own_user_id = room.own_user_id();
+	if power_levels.for_user(own_user_id) < moderator {
+		bail!("{own_user_id} can't post in {} once it's read-only", room.room_id());
+	}
+	power_levels.events_default = moderator;
+	room.send_state_event(RoomPowerLevelsEventContent::from(power_levels)).await?;
 	Ok(())
 }
 
 #[must_use]
-pub fn get_bms(mx_chat: &str) -> Option<Vec<BridgedMessage>> {
-	let bm_file_path = format!("bridged_messages/{mx_chat}.mpk");
-	let file = match File::open(bm_file_path) {
-		Ok(f) => f,
-		Err(e) => {
-			log::error!("{}:{}", line!(), e);
-			return None;
+pub fn get_forward_name(msg: &Message) -> Option<String> {
+	let name = match msg.forward_origin()? {
+		MessageOrigin::User {
+			sender_user,
+			..
+		} => sender_user.full_name(),
+		MessageOrigin::HiddenUser {
+			sender_user_name,
+			..
+		} => format!("{sender_user_name} (hidden user)"),
+		MessageOrigin::Chat {
+			sender_chat: chat,
+			author_signature,
+			..
 		}
-	};
-	match rmp_serde::from_read(file) {
-		Ok(bms) => Some(bms),
-		Err(e) => {
-			log::error!("{}:{}", line!(), e);
-			None
+		| MessageOrigin::Channel {
+			chat,
+			author_signature,
+			..
+		} => {
+			let title = chat.title().unwrap_or_default();
+			match author_signature {
+				Some(signature) => format!("{title} ({signature})"),
+				None => title.to_string(),
+			}
 		}
+	};
+	Some(name)
+}
+
+#[must_use]
+pub fn escape_html(text: &str) -> String {
+	text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+#[must_use]
+pub fn unescape_html(html: &str) -> String {
+	html.replace("&lt;", "<")
+		.replace("&gt;", ">")
+		.replace("&quot;", "\"")
+		.replace("&#39;", "'")
+		.replace("&amp;", "&")
+}
+
+fn html_to_plain(html: &str) -> String {
+	let html = html.replace("<br>", "\n").replace("<br/>", "\n").replace("<br />", "\n");
+	let mut text = String::new();
+	let mut rest = html.as_str();
+	while let Some(start) = rest.find('<') {
+		text.push_str(&rest[..start]);
+		let Some(end) = rest[start..].find('>') else {
+			break;
+		};
+		rest = &rest[start + end + 1..];
 	}
+	text.push_str(rest);
+	unescape_html(&text)
 }
 
-pub async fn get_to_tg_data<'a>(
-	from_mx_data: &BmMxData<'a>,
-	bot: Throttle<Bot>,
-	client: Client,
-	bridge: &Bridge,
-) -> anyhow::Result<BmTgData> {
-	let mut tg_data = BmTgData {
-		bot: Some(bot),
-		chat_id: Some(ChatId(bridge.tg_id)),
-		..Default::default()
-	};
-	let message_type = &from_mx_data.mx_msg_type;
-	let relates_to = match &from_mx_data.mx_event.content {
-		AnyMessageLikeEventContent::RoomMessage(RoomMessageEventContent {
-			relates_to,
-			..
-		}) => relates_to,
-		_ => &None,
-	};
-	let is_reply = { matches!(&relates_to, Some(Relation::Reply { .. })) };
-	match message_type {
-		MessageType::Text(t) => {
-			tg_data.message = {
-				if is_reply {
-					match t.body.split_once("\n\n") {
-						Some(split) => split.1.as_bytes().to_vec(),
-						None => bail!("couldn't find newline split"),
-					}
-				} else {
-					t.body.as_bytes().to_vec()
-				}
-			};
-			tg_data.tg_message_kind = Some(TgMessageKind::Text);
-			tg_data.is_preview_disabled = false;
-		}
-		MessageType::Image(i) => {
-			let ec = ImageMessageEventContent::new(i.body.clone(), i.source.clone());
-			tg_data.message = get_event_content_vec(ec, &client).await?;
-			tg_data.tg_message_kind = Some(TgMessageKind::Photo);
-			tg_data.caption = Some(i.body.clone());
-		}
-		MessageType::Video(v) => {
-			let ec = VideoMessageEventContent::new(v.body.clone(), v.source.clone());
-			let message = get_event_content_vec(ec, &client).await?;
-			tg_data.message = message;
-			tg_data.tg_message_kind = Some(TgMessageKind::Video);
-			tg_data.caption = Some(v.body.clone());
+// byte ranges of the data-mx-spoiler spans of html in its plain text
+#[must_use]
+pub fn html_spoilers(html: &str, text: &str) -> Vec<Range<usize>> {
+	let mut spoilers = vec![];
+	let mut rest = html;
+	let mut searched = 0;
+	while let Some(start) = rest.find("<span data-mx-spoiler") {
+		let Some(open_len) = rest[start..].find('>') else {
+			break;
+		};
+		let inner = &rest[start + open_len + 1..];
+		let Some(close) = inner.find("</span>") else {
+			break;
+		};
+		let spoiler = html_to_plain(&inner[..close]);
+		if let Some(found) = text[searched..].find(&spoiler).filter(|_| !spoiler.is_empty()) {
+			let spoiler_start = searched + found;
+			searched = spoiler_start + spoiler.len();
+			spoilers.push(spoiler_start..searched);
 		}
-		MessageType::File(f) => {
-			let ec = FileMessageEventContent::new(f.body.clone(), f.source.clone());
-			let message = get_event_content_vec(ec, &client).await?;
-			tg_data.message = message;
-			tg_data.tg_message_kind = Some(TgMessageKind::Document);
-			tg_data.caption = Some(f.body.clone());
+		rest = &inner[close + "</span>".len()..];
+	}
+	spoilers
+}
+
+#[must_use]
+pub fn spoiler_text(entities: &[MessageEntityRef]) -> Option<(String, String)> {
+	let mut spoilers = entities
+		.iter()
+		.filter(|entity| matches!(entity.kind(), MessageEntityKind::Spoiler))
+		.map(MessageEntityRef::range)
+		.collect::<Vec<_>>();
+	if spoilers.is_empty() {
+		return None;
+	}
+	spoilers.sort_by_key(|range| range.start);
+	let message = entities.first()?.message_text();
+	let mut text = String::new();
+	let mut html_text = String::new();
+	let mut end = 0;
+	for spoiler in spoilers {
+		if spoiler.start < end {
+			continue;
 		}
-		t => bail!("unsupported type: {:?}", t),
+		text.push_str(&message[end..spoiler.start]);
+		text.push_str("[spoiler]");
+		html_text.push_str(&escape_html(&message[end..spoiler.start]));
+		html_text.push_str(&format!(
+			"<span data-mx-spoiler>{}</span>",
+			escape_html(&message[spoiler.clone()])
+		));
+		end = spoiler.end;
+	}
+	text.push_str(&message[end..]);
+	html_text.push_str(&escape_html(&message[end..]));
+	Some((text, html_text.replace('\n', "<br>")))
+}
+
+#[must_use]
+pub fn geo_uri(location: &Location) -> String {
+	format!("geo:{},{}", location.latitude, location.longitude)
+}
+
+#[must_use]
+pub fn parse_geo_uri(geo_uri: &str) -> Option<(f64, f64)> {
+	let coordinates = geo_uri.strip_prefix("geo:")?.split(';').next()?;
+	let mut coordinates = coordinates.split(',');
+	let latitude = coordinates.next()?.parse::<f64>().ok()?;
+	let longitude = coordinates.next()?.parse::<f64>().ok()?;
+	Some((latitude, longitude))
+}
+
+pub fn convert_image(
+	data: &[u8],
+	format: ImageFormat,
+	fit_side: Option<u32>,
+) -> anyhow::Result<(Vec<u8>, u32, u32)> {
+	let mut image = DynamicImage::ImageRgba8(image::load_from_memory(data)?.to_rgba8());
+	if let Some(fit_side) = fit_side {
+		image = image.resize(fit_side, fit_side, FilterType::Lanczos3);
 	}
-	Ok(tg_data)
+	let mut converted = Cursor::new(Vec::new());
+	image.write_to(&mut converted, format)?;
+	Ok((converted.into_inner(), image.width(), image.height()))
 }
 
-async fn get_event_content_vec(
-	ec: impl MediaEventContent,
-	client: &Client,
-) -> anyhow::Result<Vec<u8>> {
-	let message = match client.media().get_file(&ec, false).await {
-		Ok(Some(message)) => message,
-		Ok(None) => bail!("{}:couldn't get content vec", line!()),
-		Err(e) => bail!("{}:couldn't get content vec: {}", line!(), e),
+pub async fn download_tg_file(bot: &Bot, file_id: &str) -> anyhow::Result<Vec<u8>> {
+	let file_path = bot.get_file(file_id).await?.path;
+	let mut file = Vec::new();
+	bot.download_file(&file_path, &mut file).await?;
+	Ok(file)
+}
+
+pub async fn get_sticker_image(
+	bot: &Bot,
+	sticker: &Sticker,
+) -> anyhow::Result<(Vec<u8>, ImageInfo)> {
+	let file_id = if sticker.is_static() {
+		&sticker.file.id
+	} else {
+		&sticker.thumbnail.as_ref().context("sticker has no thumbnail")?.file.id
 	};
-	Ok(message)
-}
-
-pub async fn bot_send_request(
-	bot: Throttle<Bot>,
-	to_tg_data: BmTgData,
-	chat_id: ChatId,
-	reply_params: ReplyParameters,
-	link_preview: LinkPreviewOptions,
-	from_user: String,
-) -> Result<Message, teloxide::RequestError> {
-	let caption = format!("(from: {from_user})\n{}", to_tg_data.caption.unwrap_or_default());
-	loop {
-		let res = match to_tg_data.tg_message_kind {
-			Some(TgMessageKind::Text) => {
-				let text = format!(
-					"{from_user}: {}",
-					String::from_utf8_lossy(&to_tg_data.message.clone())
-				);
-				bot.send_message(chat_id, text)
-					.reply_parameters(reply_params.clone())
-					.link_preview_options(link_preview.clone())
-					.await
-			}
-			Some(TgMessageKind::Photo) => {
-				let input_file = InputFile::memory(to_tg_data.message.clone());
-				bot.send_photo(chat_id, input_file)
-					.caption(&caption)
-					.reply_parameters(reply_params.clone())
-					.await
-			}
-			Some(TgMessageKind::Sticker) => {
-				let input_file = InputFile::memory(to_tg_data.message.clone());
-				bot.send_sticker(chat_id, input_file).reply_parameters(reply_params.clone()).await
-			}
-			Some(TgMessageKind::Video) => {
-				let input_file = InputFile::memory(to_tg_data.message.clone());
-				bot.send_video(chat_id, input_file)
-					.caption(&caption)
-					.reply_parameters(reply_params.clone())
-					.await
-			}
-			Some(TgMessageKind::Document) => {
-				let input_file = InputFile::memory(to_tg_data.message.clone());
-				bot.send_document(chat_id, input_file)
-					.caption(&caption)
-					.reply_parameters(reply_params.clone())
-					.await
-			}
-			None => unreachable!(""),
-		};
-		match res {
-			Err(RequestError::Network(e)) if e.is_timeout() => {
-				continue;
-			}
-			x => {
-				return x;
-			}
-		}
+	let file = download_tg_file(bot, file_id).await?;
+	let (png, width, height) = convert_image(&file, ImageFormat::Png, None)?;
+	let mut info = ImageInfo::new();
+	info.mimetype = Some(mime::IMAGE_PNG.to_string());
+	info.width = Some(UInt::from(width));
+	info.height = Some(UInt::from(height));
+	info.size = Some(UInt::try_from(png.len())?);
+	Ok((png, info))
+}
+
+#[must_use]
+pub fn get_sent_file(msg: &Message) -> Option<&FileMeta> {
+	let MessageKind::Common(msg_common) = &msg.kind else {
+		return None;
+	};
+	match &msg_common.media_kind {
+		MediaKind::Photo(m) => m.photo.last().map(|photo| &photo.file),
+		MediaKind::Animation(m) => Some(&m.animation.file),
+		MediaKind::Sticker(m) => Some(&m.sticker.file),
+		MediaKind::Video(m) => Some(&m.video.file),
+		MediaKind::Document(m) => Some(&m.document.file),
+		_ => None,
+	}
+}
+
+#[must_use]
+pub fn tg_transaction_id(msg: &Message) -> OwnedTransactionId {
+	format!("tg_{}_{}", msg.chat.id.0, msg.id.0).into()
+}
+
+#[must_use]
+pub fn poll_vote_text(answers: &[(String, String)], chosen: &[String]) -> String {
+	let options = answers
+		.iter()
+		.filter(|(id, _)| chosen.contains(id))
+		.map(|(_, text)| text.as_str())
+		.collect::<Vec<_>>();
+	if options.is_empty() {
+		"retracted their vote".to_string()
+	} else {
+		format!("voted for {}", options.join(", "))
+	}
+}
+
+#[must_use]
+pub fn poll_results_text(poll: &Poll) -> String {
+	let mut text = format!("poll closed: {}", poll.question);
+	for option in &poll.options {
+		text.push_str(&format!("\n{}: {}", option.text, option.voter_count));
 	}
+	text
 }
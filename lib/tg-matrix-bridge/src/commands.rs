@@ -0,0 +1,95 @@
+use anyhow::Context;
+use interactive::chat::ChatMessage;
+use interactive::chat::ChatReply;
+use interactive::commands::match_command;
+use interactive::commands::match_text;
+use matrix_sdk::ruma::events::relation::InReplyTo;
+use matrix_sdk::ruma::events::room::message::FileMessageEventContent;
+use matrix_sdk::ruma::events::room::message::MessageType;
+use matrix_sdk::ruma::events::room::message::Relation;
+use matrix_sdk::ruma::events::room::message::RoomMessageEventContent;
+use matrix_sdk::Room;
+use teloxide::payloads::SendDocumentSetters;
+use teloxide::payloads::SendMessageSetters;
+use teloxide::prelude::Requester;
+use teloxide::types::InputFile;
+use teloxide::types::Message;
+use teloxide::types::ReplyParameters;
+
+use crate::bridge_structs::BridgeContext;
+use crate::bridge_utils::tg_sender_id;
+
+#[must_use]
+pub fn tg_chat_message(msg: &Message) -> Option<ChatMessage> {
+	let reply_text = msg.reply_to_message().and_then(|reply| reply.text().or(reply.caption()));
+	Some(ChatMessage {
+		id: format!("{}_{}", msg.chat.id, msg.id),
+		sender: tg_sender_id(msg),
+		text: msg.text()?.to_string(),
+		reply_text: reply_text.map(str::to_string),
+	})
+}
+
+// answers on telegram and mirrors the answer into the bridged room
+pub async fn run_tg_commands(
+	ctx: &BridgeContext,
+	msg: &Message,
+	matrix_room: &Room,
+) -> anyhow::Result<()> {
+	let Some(message) = tg_chat_message(msg) else {
+		return Ok(());
+	};
+	let replies = tokio::task::spawn_blocking(move || {
+		[match_command(&message), match_text(&message)].into_iter().flatten().collect::<Vec<_>>()
+	})
+	.await
+	.context("command panicked")?;
+	for reply in replies {
+		send_command_reply(ctx, msg, matrix_room, reply).await?;
+	}
+	Ok(())
+}
+
+async fn send_command_reply(
+	ctx: &BridgeContext,
+	msg: &Message,
+	matrix_room: &Room,
+	reply: ChatReply,
+) -> anyhow::Result<()> {
+	let reply_params = ReplyParameters::new(msg.id).allow_sending_without_reply();
+	let (t_msg, mut content) = match reply {
+		ChatReply::Text(text) => {
+			let t_msg =
+				ctx.bot.send_message(msg.chat.id, &text).reply_parameters(reply_params).await?;
+			(t_msg, RoomMessageEventContent::notice_plain(text))
+		}
+		ChatReply::File {
+			name,
+			mime,
+			data,
+		} => {
+			let input_file = InputFile::memory(data.clone()).file_name(name.clone());
+			let t_msg = ctx
+				.bot
+				.send_document(msg.chat.id, input_file)
+				.reply_parameters(reply_params)
+				.await?;
+			let mxc_uri = ctx.client.media().upload(&mime, data, None).await?.content_uri;
+			let file_content = FileMessageEventContent::plain(name, mxc_uri);
+			(t_msg, RoomMessageEventContent::new(MessageType::File(file_content)))
+		}
+	};
+	let matrix_chat_id = matrix_room.room_id().as_str();
+	if let Some(event_id) = ctx.store.find_mx_event_id(matrix_chat_id, (msg.chat.id, msg.id)) {
+		content.relates_to = Some(Relation::Reply {
+			in_reply_to: InReplyTo::new(event_id),
+		});
+	}
+	let sent_mx_msg = matrix_room.send(content).await?;
+	ctx.store.update_bridged_messages(
+		sent_mx_msg.event_id,
+		(t_msg.chat.id, t_msg.id),
+		matrix_chat_id,
+	)?;
+	Ok(())
+}
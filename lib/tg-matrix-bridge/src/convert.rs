@@ -0,0 +1,490 @@
+use anyhow::bail;
+use anyhow::Context;
+use matrix_sdk::ruma::events::poll::unstable_start::NewUnstablePollStartEventContent;
+use matrix_sdk::ruma::events::poll::unstable_start::UnstablePollAnswer;
+use matrix_sdk::ruma::events::poll::unstable_start::UnstablePollAnswers;
+use matrix_sdk::ruma::events::poll::unstable_start::UnstablePollStartContentBlock;
+use matrix_sdk::ruma::events::relation::InReplyTo;
+use matrix_sdk::ruma::events::room::message::sanitize::remove_plain_reply_fallback;
+use matrix_sdk::ruma::events::room::message::AddMentions;
+use matrix_sdk::ruma::events::room::message::FileMessageEventContent;
+use matrix_sdk::ruma::events::room::message::FormattedBody;
+use matrix_sdk::ruma::events::room::message::ForwardThread;
+use matrix_sdk::ruma::events::room::message::ImageMessageEventContent;
+use matrix_sdk::ruma::events::room::message::LocationMessageEventContent;
+use matrix_sdk::ruma::events::room::message::MessageFormat;
+use matrix_sdk::ruma::events::room::message::MessageType;
+use matrix_sdk::ruma::events::room::message::OriginalRoomMessageEvent;
+use matrix_sdk::ruma::events::room::message::Relation;
+use matrix_sdk::ruma::events::room::message::RoomMessageEventContent;
+use matrix_sdk::ruma::events::room::message::VideoMessageEventContent;
+use matrix_sdk::ruma::events::room::ImageInfo;
+use matrix_sdk::ruma::events::room::MediaSource;
+use matrix_sdk::ruma::events::sticker::StickerEventContent;
+use matrix_sdk::ruma::events::AnyMessageLikeEventContent;
+use matrix_sdk::ruma::EventId;
+use matrix_sdk::ruma::OwnedEventId;
+use matrix_sdk::ruma::OwnedMxcUri;
+use matrix_sdk::ruma::UInt;
+use serde_json::Value;
+use teloxide::types::FileMeta;
+use teloxide::types::LinkPreviewOptions;
+use teloxide::types::MediaKind;
+use teloxide::types::Message;
+use teloxide::types::MessageEntityKind;
+use teloxide::types::MessageKind;
+use teloxide::types::Poll;
+use teloxide::types::Sticker;
+
+use crate::bridge_structs::LinkPreviewPolicy;
+use crate::bridge_structs::CONTENT_WARNING_KEY;
+use crate::bridge_utils::escape_html;
+use crate::bridge_utils::geo_uri;
+use crate::bridge_utils::get_forward_name;
+use crate::bridge_utils::get_user_name;
+use crate::bridge_utils::html_spoilers;
+use crate::bridge_utils::parse_geo_uri;
+use crate::bridge_utils::spoiler_text;
+use crate::network::ContentKind;
+use crate::network::OutgoingMessage;
+use crate::network::ReplyQuote;
+use crate::templates::render;
+use crate::templates::render_html;
+use crate::templates::Sender;
+use crate::templates::SenderTemplates;
+
+pub enum TgUpload<'a> {
+	File(&'a FileMeta, mime::Mime, ContentKind),
+	Vcard(String),
+}
+
+pub fn get_tg_upload(msg: &Message) -> anyhow::Result<Option<TgUpload<'_>>> {
+	let MessageKind::Common(msg_common) = &msg.kind else {
+		return Ok(None);
+	};
+	let tg_upload = match &msg_common.media_kind {
+		MediaKind::Photo(m) => {
+			let Some(photo) = &m.photo.last() else {
+				bail!("photo has no sizes")
+			};
+			TgUpload::File(&photo.file, mime::IMAGE_JPEG, ContentKind::Photo)
+		}
+		MediaKind::Animation(m) => {
+			TgUpload::File(&m.animation.file, "video/mp4".parse()?, ContentKind::Video)
+		}
+		MediaKind::Sticker(m) => {
+			let mime = if m.sticker.is_video() {
+				"video/webm".parse()?
+			} else {
+				"image/webp".parse()?
+			};
+			TgUpload::File(&m.sticker.file, mime, ContentKind::Sticker)
+		}
+		MediaKind::Video(m) => {
+			TgUpload::File(&m.video.file, "video/mp4".parse()?, ContentKind::Video)
+		}
+		MediaKind::Document(m) => {
+			TgUpload::File(&m.document.file, mime::APPLICATION_OCTET_STREAM, ContentKind::Document)
+		}
+		MediaKind::Contact(m) => match &m.contact.vcard {
+			Some(vcard) => TgUpload::Vcard(vcard.clone()),
+			None => return Ok(None),
+		},
+		_ => return Ok(None),
+	};
+	Ok(Some(tg_upload))
+}
+
+#[must_use]
+pub fn tg_has_spoiler(msg: &Message) -> bool {
+	msg.has_media_spoiler()
+		|| msg.parse_caption_entities().as_deref().and_then(spoiler_text).is_some()
+}
+
+pub fn tg_to_mx_content(
+	msg: &Message,
+	sender: &Sender,
+	templates: &SenderTemplates,
+	mxc_uri: Option<OwnedMxcUri>,
+	reply_to: Option<&OriginalRoomMessageEvent>,
+) -> anyhow::Result<RoomMessageEventContent> {
+	let MessageKind::Common(msg_common) = &msg.kind else {
+		bail!("unsupported message kind");
+	};
+	let forward_name = get_forward_name(msg);
+	let caption_text = match msg.parse_caption_entities().as_deref().and_then(spoiler_text) {
+		Some((caption_text, _)) => caption_text,
+		None => msg.caption().unwrap_or("").to_string(),
+	};
+	let caption_sender = match &forward_name {
+		Some(forward_name) => Sender {
+			name: format!("{}, forwarded from {forward_name}", sender.name),
+			..sender.clone()
+		},
+		None => sender.clone(),
+	};
+	let caption = render(templates.for_caption(&caption_text), &caption_sender, &caption_text);
+	let message = match &msg_common.media_kind {
+		MediaKind::Text(t) => {
+			let (text, html_text) = match msg.parse_entities().as_deref().and_then(spoiler_text) {
+				Some((text, html_text)) => (text, Some(html_text)),
+				None => (t.text.clone(), None),
+			};
+			if let Some(forward_name) = &forward_name {
+				let html_text =
+					html_text.unwrap_or_else(|| escape_html(&text).replace('\n', "<br>"));
+				let text = format!("(forwarded from {forward_name})\n{text}");
+				let html_text = format!(
+					"<i>forwarded from <b>{}</b></i><blockquote>{html_text}</blockquote>",
+					escape_html(forward_name),
+				);
+				text_content(sender, templates, &text, Some(html_text))
+			} else {
+				text_content(sender, templates, &text, html_text)
+			}
+		}
+		MediaKind::Sticker(m) if m.sticker.is_video() => {
+			let mxc_uri = mxc_uri.context("media wasn't uploaded")?;
+			let event_content = VideoMessageEventContent::new(caption, MediaSource::Plain(mxc_uri));
+			RoomMessageEventContent::new(MessageType::Video(event_content))
+		}
+		MediaKind::Photo(_) | MediaKind::Sticker(_) => {
+			let mxc_uri = mxc_uri.context("media wasn't uploaded")?;
+			let event_content = ImageMessageEventContent::new(caption, MediaSource::Plain(mxc_uri));
+			RoomMessageEventContent::new(MessageType::Image(event_content))
+		}
+		MediaKind::Animation(_) | MediaKind::Video(_) => {
+			let mxc_uri = mxc_uri.context("media wasn't uploaded")?;
+			let event_content = VideoMessageEventContent::new(caption, MediaSource::Plain(mxc_uri));
+			RoomMessageEventContent::new(MessageType::Video(event_content))
+		}
+		MediaKind::Document(_) => {
+			let mxc_uri = mxc_uri.context("media wasn't uploaded")?;
+			let event_content = FileMessageEventContent::new(caption, MediaSource::Plain(mxc_uri));
+			RoomMessageEventContent::new(MessageType::File(event_content))
+		}
+		MediaKind::Location(m) => {
+			let body = render(&templates.text, sender, "location");
+			let event_content = LocationMessageEventContent::new(body, geo_uri(&m.location));
+			RoomMessageEventContent::new(MessageType::Location(event_content))
+		}
+		MediaKind::Venue(m) => {
+			let venue = format!("{}\n{}", m.venue.title, m.venue.address);
+			let body = render(&templates.text, sender, &venue);
+			let event_content = LocationMessageEventContent::new(body, geo_uri(&m.venue.location));
+			RoomMessageEventContent::new(MessageType::Location(event_content))
+		}
+		MediaKind::Contact(m) => {
+			let contact = &m.contact;
+			let name = match &contact.last_name {
+				Some(last_name) => format!("{} {last_name}", contact.first_name),
+				None => contact.first_name.clone(),
+			};
+			let body = render(&templates.text, sender, &format!("{name} {}", contact.phone_number));
+			if let Some(mxc_uri) = mxc_uri {
+				let mut event_content =
+					FileMessageEventContent::new(body, MediaSource::Plain(mxc_uri));
+				event_content.filename = Some(format!("{name}.vcf"));
+				RoomMessageEventContent::new(MessageType::File(event_content))
+			} else {
+				RoomMessageEventContent::text_plain(body)
+			}
+		}
+		_ => bail!("unsupported media_kind"),
+	};
+	let message = match (reply_to, tg_reply_quote(msg)) {
+		(Some(reply_to), _) => message.make_reply_to(reply_to, ForwardThread::No, AddMentions::No),
+		(None, Some(quote)) => add_reply_quote(message, &quote),
+		(None, None) => message,
+	};
+	Ok(message)
+}
+
+#[must_use]
+pub fn text_content(
+	sender: &Sender,
+	templates: &SenderTemplates,
+	text: &str,
+	html_text: Option<String>,
+) -> RoomMessageEventContent {
+	let body = render(&templates.text, sender, text);
+	if html_text.is_none() && !templates.bold_name {
+		return RoomMessageEventContent::text_plain(body);
+	}
+	let html_text = html_text.unwrap_or_else(|| escape_html(text).replace('\n', "<br>"));
+	let html_text = render_html(&templates.text, sender, &html_text, templates.bold_name);
+	RoomMessageEventContent::text_html(body, html_text)
+}
+
+const REPLY_SNIPPET_LEN: usize = 80;
+
+#[must_use]
+pub fn reply_snippet(text: &str) -> String {
+	let text = text.split_whitespace().collect::<Vec<&str>>().join(" ");
+	match text.char_indices().nth(REPLY_SNIPPET_LEN) {
+		Some((i, _)) => format!("{}…", &text[..i]),
+		None => text,
+	}
+}
+
+// quote of the replied-to telegram message, preferring the part the user selected
+#[must_use]
+pub fn tg_reply_quote(msg: &Message) -> Option<ReplyQuote> {
+	let reply = msg.reply_to_message()?;
+	if !matches!(reply.kind, MessageKind::Common(_)) {
+		return None;
+	}
+	let text = match msg.quote() {
+		Some(quote) => quote.text.as_str(),
+		None => reply.text().or(reply.caption()).unwrap_or("media"),
+	};
+	Some(ReplyQuote {
+		sender: get_user_name(reply).unwrap_or_else(|_| "unknown".to_string()),
+		text: reply_snippet(text),
+	})
+}
+
+#[must_use]
+pub fn mx_reply_quote(event: &OriginalRoomMessageEvent) -> ReplyQuote {
+	ReplyQuote {
+		sender: event.sender.to_string(),
+		text: reply_snippet(remove_plain_reply_fallback(event.content.body())),
+	}
+}
+
+// media carry the quote in their caption
+fn add_reply_quote(
+	mut message: RoomMessageEventContent,
+	quote: &ReplyQuote,
+) -> RoomMessageEventContent {
+	let (body, formatted) = match &mut message.msgtype {
+		MessageType::Text(content) => (&mut content.body, &mut content.formatted),
+		MessageType::Image(content) => (&mut content.body, &mut content.formatted),
+		MessageType::Video(content) => (&mut content.body, &mut content.formatted),
+		MessageType::Audio(content) => (&mut content.body, &mut content.formatted),
+		MessageType::File(content) => (&mut content.body, &mut content.formatted),
+		_ => return message,
+	};
+	let html_text = match formatted.as_ref() {
+		Some(formatted) if formatted.format == MessageFormat::Html => formatted.body.clone(),
+		_ => escape_html(body).replace('\n', "<br>"),
+	};
+	*body = format!("> {}: {}\n\n{body}", quote.sender, quote.text);
+	*formatted = Some(FormattedBody::html(format!(
+		"<blockquote><b>{}</b>: {}</blockquote>{html_text}",
+		escape_html(&quote.sender),
+		escape_html(&quote.text),
+	)));
+	message
+}
+
+pub fn tg_poll_content(
+	poll: &Poll,
+	sender: &Sender,
+	templates: &SenderTemplates,
+) -> anyhow::Result<NewUnstablePollStartEventContent> {
+	let answers = poll
+		.options
+		.iter()
+		.enumerate()
+		.map(|(i, option)| UnstablePollAnswer::new(i.to_string(), &option.text))
+		.collect::<Vec<UnstablePollAnswer>>();
+	let mut poll_start =
+		UnstablePollStartContentBlock::new(&poll.question, UnstablePollAnswers::try_from(answers)?);
+	if poll.allows_multiple_answers {
+		poll_start.max_selections = UInt::try_from(poll.options.len())?;
+	}
+	let mut fallback = render(&templates.text, sender, &poll.question);
+	for (i, option) in poll.options.iter().enumerate() {
+		fallback.push_str(&format!("\n{}. {}", i + 1, option.text));
+	}
+	Ok(NewUnstablePollStartEventContent::plain_text(fallback, poll_start))
+}
+
+#[must_use]
+pub fn tg_sticker_content(
+	sticker: &Sticker,
+	sender: &Sender,
+	templates: &SenderTemplates,
+	mxc_uri: OwnedMxcUri,
+	info: ImageInfo,
+	reply_to: Option<OwnedEventId>,
+) -> StickerEventContent {
+	let body = render(&templates.text, sender, sticker.emoji.as_deref().unwrap_or("sticker"));
+	let mut content = StickerEventContent::new(body, info, mxc_uri);
+	if let Some(event_id) = reply_to {
+		content.relates_to = Some(Relation::Reply {
+			in_reply_to: InReplyTo::new(event_id),
+		});
+	}
+	content
+}
+
+// MSC4095: an empty list means the sender turned previews off
+#[must_use]
+pub fn get_preview_disabled(raw_event: &Value) -> bool {
+	raw_event["content"]["com.beeper.linkpreviews"].as_array().is_some_and(Vec::is_empty)
+}
+
+#[must_use]
+pub fn first_link(text: &str) -> Option<&str> {
+	text.split_whitespace().find(|word| word.starts_with("https://") || word.starts_with("http://"))
+}
+
+#[must_use]
+pub fn tg_link_preview(
+	policy: LinkPreviewPolicy,
+	is_preview_disabled: bool,
+	text: &str,
+) -> LinkPreviewOptions {
+	let mut options = LinkPreviewOptions {
+		is_disabled: is_preview_disabled,
+		url: None,
+		prefer_small_media: false,
+		prefer_large_media: false,
+		show_above_text: false,
+	};
+	match policy {
+		LinkPreviewPolicy::Always => (),
+		LinkPreviewPolicy::Never => options.is_disabled = true,
+		LinkPreviewPolicy::FirstLink => options.url = first_link(text).map(str::to_string),
+		LinkPreviewPolicy::LargeMedia => options.prefer_large_media = true,
+	}
+	options
+}
+
+#[must_use]
+pub fn tg_preview_url(msg: &Message) -> Option<String> {
+	let options = msg.link_preview_options();
+	if options.is_some_and(|options| options.is_disabled) {
+		return None;
+	}
+	if let Some(url) = options.and_then(|options| options.url.clone()) {
+		return Some(url);
+	}
+	msg.parse_entities()?.iter().find_map(|entity| match entity.kind() {
+		MessageEntityKind::Url => Some(entity.text().to_string()),
+		MessageEntityKind::TextLink {
+			url,
+		} => Some(url.to_string()),
+		_ => None,
+	})
+}
+
+#[must_use]
+pub fn add_preview_block(
+	mut message: RoomMessageEventContent,
+	url: &str,
+) -> RoomMessageEventContent {
+	let MessageType::Text(text) = &mut message.msgtype else {
+		return message;
+	};
+	let html_text = match &text.formatted {
+		Some(formatted) if formatted.format == MessageFormat::Html => formatted.body.clone(),
+		_ => escape_html(&text.body).replace('\n', "<br>"),
+	};
+	let url = escape_html(url);
+	let html_text = format!("{html_text}<blockquote><a href=\"{url}\">{url}</a></blockquote>");
+	text.formatted = Some(FormattedBody::html(html_text));
+	message
+}
+
+#[must_use]
+pub fn get_has_spoiler(raw_event: &Value) -> bool {
+	let content = &raw_event["content"];
+	content.get(CONTENT_WARNING_KEY).is_some()
+		|| content["format"] == "org.matrix.custom.html"
+			&& content["formatted_body"]
+				.as_str()
+				.is_some_and(|body| remove_html_reply_fallback(body).contains("data-mx-spoiler"))
+}
+
+#[must_use]
+pub fn remove_html_reply_fallback(html: &str) -> &str {
+	match html.find("</mx-reply>") {
+		Some(end) if html.trim_start().starts_with("<mx-reply>") => {
+			&html[end + "</mx-reply>".len()..]
+		}
+		_ => html,
+	}
+}
+
+#[must_use]
+pub fn get_reply_to(
+	content: &AnyMessageLikeEventContent,
+	raw_event: &Value,
+) -> Option<OwnedEventId> {
+	if let AnyMessageLikeEventContent::RoomMessage(room_message) = content {
+		return match &room_message.relates_to {
+			Some(Relation::Reply {
+				in_reply_to,
+			}) => Some(in_reply_to.event_id.clone()),
+			Some(Relation::Thread(thread)) if !thread.is_falling_back => {
+				thread.in_reply_to.as_ref().map(|in_reply_to| in_reply_to.event_id.clone())
+			}
+			_ => None,
+		};
+	}
+	// stickers and other content don't expose their relation through ruma
+	let event_id = raw_event["content"]["m.relates_to"]["m.in_reply_to"]["event_id"].as_str()?;
+	EventId::parse(event_id).ok()
+}
+
+pub fn mx_to_outgoing(
+	content: &AnyMessageLikeEventContent,
+	raw_event: &Value,
+) -> anyhow::Result<OutgoingMessage> {
+	let mut message = OutgoingMessage {
+		has_spoiler: get_has_spoiler(raw_event),
+		reply_to: get_reply_to(content, raw_event),
+		is_preview_disabled: get_preview_disabled(raw_event),
+		..Default::default()
+	};
+	let room_message = match content {
+		AnyMessageLikeEventContent::Sticker(sticker) => {
+			set_media(&mut message, sticker.source.clone().into(), ContentKind::Sticker);
+			message.caption = Some(sticker.body.clone());
+			return Ok(message);
+		}
+		AnyMessageLikeEventContent::RoomMessage(room_message) => room_message,
+		_ => bail!("unsupported content"),
+	};
+	match &room_message.msgtype {
+		MessageType::Text(t) => {
+			let text = remove_plain_reply_fallback(&t.body);
+			if let Some(formatted) =
+				t.formatted.as_ref().filter(|f| f.format == MessageFormat::Html)
+			{
+				message.spoilers = html_spoilers(remove_html_reply_fallback(&formatted.body), text);
+			}
+			message.message = text.as_bytes().to_vec();
+			message.kind = Some(ContentKind::Text);
+		}
+		MessageType::Image(i) => {
+			set_media(&mut message, i.source.clone(), ContentKind::Photo);
+			message.caption = Some(i.body.clone());
+		}
+		MessageType::Video(v) => {
+			set_media(&mut message, v.source.clone(), ContentKind::Video);
+			message.caption = Some(v.body.clone());
+		}
+		MessageType::File(f) => {
+			set_media(&mut message, f.source.clone(), ContentKind::Document);
+			message.caption = Some(f.body.clone());
+		}
+		MessageType::Location(l) => {
+			message.location = Some(parse_geo_uri(&l.geo_uri).context("invalid geo uri")?);
+			message.kind = Some(ContentKind::Location);
+		}
+		t => bail!("unsupported type: {:?}", t),
+	}
+	Ok(message)
+}
+
+fn set_media(message: &mut OutgoingMessage, source: MediaSource, kind: ContentKind) {
+	if let MediaSource::Plain(mxc_uri) = &source {
+		message.mxc_uri = Some(mxc_uri.clone());
+	}
+	message.source = Some(source);
+	message.kind = Some(kind);
+}
@@ -1,11 +1,361 @@
+use std::fs::File;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use matrix_sdk::ruma::EventId;
+use matrix_sdk::ruma::MxcUri;
 use matrix_sdk::ruma::OwnedEventId;
+use matrix_sdk::ruma::OwnedMxcUri;
+use serde::de::DeserializeOwned;
 use serde::Deserialize;
 use serde::Serialize;
 use teloxide::types::ChatId;
 use teloxide::types::MessageId;
 
+use crate::bridge_structs::Bridge;
+use crate::network::ContentKind;
+use crate::network::RemoteNetwork;
+use crate::telegram::TelegramNetwork;
+
+const MAX_ENTRIES: usize = 1000;
+const BRIDGED_POLLS_FILE: &str = "polls.mpk";
+const MEDIA_CACHE_FILE: &str = "media_cache.mpk";
+const MUTED_CHATS_FILE: &str = "muted_chats.mpk";
+const OPTED_OUT_USERS_FILE: &str = "opted_out_users.mpk";
+const PORTALS_FILE: &str = "portals.mpk";
+const STICKER_PACKS_FILE: &str = "sticker_packs.mpk";
+
 #[derive(Serialize, Deserialize, Debug)]
-pub struct BridgedMessage {
+pub struct BridgedMessage<R = (ChatId, MessageId)> {
 	pub matrix_id: OwnedEventId,
+	pub remote_id: R,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BridgedPoll {
+	pub telegram_poll_id: String,
+	pub matrix_id: OwnedEventId,
+	pub matrix_chat_id: String,
 	pub telegram_id: (ChatId, MessageId),
+	// matrix answer ids and texts in telegram's option order
+	#[serde(default)]
+	pub answers: Vec<(String, String)>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PackSticker {
+	pub file_unique_id: String,
+	pub shortcode: String,
+	pub emoji: Option<String>,
+	pub mxc_uri: OwnedMxcUri,
+	pub width: u32,
+	pub height: u32,
+	pub size: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct StickerPack {
+	pub set_name: String,
+	pub matrix_chat_id: String,
+	pub stickers: Vec<PackSticker>,
+}
+
+// oldest first, so keep_last drops the least recently cached media
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct MediaCache {
+	pub mxc_uris: Vec<(String, OwnedMxcUri)>,
+	pub file_ids: Vec<(String, String)>,
+}
+
+pub struct Store {
+	path: PathBuf,
+	lock: Mutex<()>,
+}
+
+fn keep_last<T>(entries: &mut Vec<T>) {
+	if entries.len() > MAX_ENTRIES {
+		entries.drain(..entries.len() - MAX_ENTRIES);
+	}
+}
+
+fn cache_insert<T>(entries: &mut Vec<(String, T)>, key: String, value: T) {
+	entries.retain(|(existing, _)| *existing != key);
+	entries.push((key, value));
+	keep_last(entries);
+}
+
+fn cache_get<T>(entries: Vec<(String, T)>, key: &str) -> Option<T> {
+	entries.into_iter().find(|(existing, _)| existing == key).map(|(_, value)| value)
+}
+
+// telegram keeps the names it had before other networks were added
+fn network_key(network: &str, key: &str) -> String {
+	if network == TelegramNetwork::NAME {
+		key.to_string()
+	} else {
+		format!("{network}_{key}")
+	}
+}
+
+fn file_key(network: &str, kind: &ContentKind, mxc_uri: &MxcUri) -> String {
+	network_key(network, &format!("{kind:?}:{mxc_uri}"))
+}
+
+impl Store {
+	pub fn new(path: impl Into<PathBuf>) -> anyhow::Result<Self> {
+		let path = path.into();
+		std::fs::create_dir_all(&path)?;
+		Ok(Self {
+			path,
+			lock: Mutex::new(()),
+		})
+	}
+
+	fn read<T: DeserializeOwned + Default>(&self, file_name: &str) -> T {
+		let file = match File::open(self.path.join(file_name)) {
+			Ok(f) => f,
+			Err(e) => {
+				log::debug!("{}:{}:{}", line!(), file_name, e);
+				return T::default();
+			}
+		};
+		match rmp_serde::from_read(file) {
+			Ok(value) => value,
+			Err(e) => {
+				log::error!("{}:{}:{}", line!(), file_name, e);
+				T::default()
+			}
+		}
+	}
+
+	fn write<T: Serialize + ?Sized>(&self, file_name: &str, value: &T) -> anyhow::Result<()> {
+		rmp_serde::encode::write(&mut File::create(self.path.join(file_name))?, value)?;
+		Ok(())
+	}
+
+	#[must_use]
+	pub fn get_remote_bms<N: RemoteNetwork>(
+		&self,
+		mx_chat: &str,
+	) -> Vec<BridgedMessage<N::MessageId>> {
+		self.read(&network_key(N::NAME, &format!("{mx_chat}.mpk")))
+	}
+
+	#[allow(clippy::missing_panics_doc)]
+	pub fn update_remote_messages<N: RemoteNetwork>(
+		&self,
+		matrix_event_id: OwnedEventId,
+		remote_id: N::MessageId,
+		matrix_chat_id: &str,
+	) -> anyhow::Result<()> {
+		let _lock = self.lock.lock().unwrap();
+		let mut bridged_messages = self.get_remote_bms::<N>(matrix_chat_id);
+		bridged_messages.push(BridgedMessage {
+			matrix_id: matrix_event_id,
+			remote_id,
+		});
+		keep_last(&mut bridged_messages);
+		self.write(&network_key(N::NAME, &format!("{matrix_chat_id}.mpk")), &bridged_messages)
+	}
+
+	#[must_use]
+	pub fn find_mx_event_for<N: RemoteNetwork>(
+		&self,
+		mx_chat: &str,
+		remote_id: &N::MessageId,
+	) -> Option<OwnedEventId> {
+		let bms = self.get_remote_bms::<N>(mx_chat);
+		let bm = bms.into_iter().find(|bm| bm.remote_id == *remote_id)?;
+		Some(bm.matrix_id)
+	}
+
+	#[must_use]
+	pub fn find_remote_id<N: RemoteNetwork>(
+		&self,
+		mx_chat: &str,
+		matrix_id: &EventId,
+	) -> Option<N::MessageId> {
+		let bms = self.get_remote_bms::<N>(mx_chat);
+		let bm = bms.into_iter().find(|bm| bm.matrix_id == matrix_id)?;
+		Some(bm.remote_id)
+	}
+
+	#[must_use]
+	pub fn get_bms(&self, mx_chat: &str) -> Vec<BridgedMessage> {
+		self.get_remote_bms::<TelegramNetwork>(mx_chat)
+	}
+
+	pub fn update_bridged_messages(
+		&self,
+		matrix_event_id: OwnedEventId,
+		telegram_event_id: (ChatId, MessageId),
+		matrix_chat_id: &str,
+	) -> anyhow::Result<()> {
+		self.update_remote_messages::<TelegramNetwork>(
+			matrix_event_id,
+			telegram_event_id,
+			matrix_chat_id,
+		)
+	}
+
+	#[must_use]
+	pub fn find_mx_event_id(
+		&self,
+		mx_chat: &str,
+		telegram_id: (ChatId, MessageId),
+	) -> Option<OwnedEventId> {
+		self.find_mx_event_for::<TelegramNetwork>(mx_chat, &telegram_id)
+	}
+
+	#[must_use]
+	pub fn find_tg_msg_id(&self, mx_chat: &str, matrix_id: &EventId) -> Option<MessageId> {
+		self.find_remote_id::<TelegramNetwork>(mx_chat, matrix_id).map(|(_, message_id)| message_id)
+	}
+
+	#[allow(clippy::missing_panics_doc)]
+	pub fn update_bridged_polls(&self, bridged_poll: BridgedPoll) -> anyhow::Result<()> {
+		let _lock = self.lock.lock().unwrap();
+		let mut bridged_polls: Vec<BridgedPoll> = self.read(BRIDGED_POLLS_FILE);
+		bridged_polls.push(bridged_poll);
+		keep_last(&mut bridged_polls);
+		self.write(BRIDGED_POLLS_FILE, &bridged_polls)
+	}
+
+	#[must_use]
+	pub fn find_bridged_poll(
+		&self,
+		predicate: impl Fn(&BridgedPoll) -> bool,
+	) -> Option<BridgedPoll> {
+		self.read::<Vec<BridgedPoll>>(BRIDGED_POLLS_FILE).into_iter().find(predicate)
+	}
+
+	#[allow(clippy::missing_panics_doc)]
+	pub fn take_bridged_poll(
+		&self,
+		predicate: impl Fn(&BridgedPoll) -> bool,
+	) -> anyhow::Result<Option<BridgedPoll>> {
+		let _lock = self.lock.lock().unwrap();
+		let mut bridged_polls: Vec<BridgedPoll> = self.read(BRIDGED_POLLS_FILE);
+		let Some(index) = bridged_polls.iter().position(predicate) else {
+			return Ok(None);
+		};
+		let bridged_poll = bridged_polls.remove(index);
+		self.write(BRIDGED_POLLS_FILE, &bridged_polls)?;
+		Ok(Some(bridged_poll))
+	}
+
+	#[must_use]
+	pub fn get_cached_mxc_uri(&self, network: &str, file_unique_id: &str) -> Option<OwnedMxcUri> {
+		let media_cache: MediaCache = self.read(MEDIA_CACHE_FILE);
+		cache_get(media_cache.mxc_uris, &network_key(network, file_unique_id))
+	}
+
+	#[must_use]
+	pub fn get_cached_file_id(
+		&self,
+		network: &str,
+		kind: &ContentKind,
+		mxc_uri: &MxcUri,
+	) -> Option<String> {
+		let media_cache: MediaCache = self.read(MEDIA_CACHE_FILE);
+		cache_get(media_cache.file_ids, &file_key(network, kind, mxc_uri))
+	}
+
+	#[allow(clippy::missing_panics_doc)]
+	pub fn cache_media(
+		&self,
+		network: &str,
+		mxc_uri: &MxcUri,
+		file_unique_id: Option<&str>,
+		remote_file: Option<(&ContentKind, &str)>,
+	) -> anyhow::Result<()> {
+		let _lock = self.lock.lock().unwrap();
+		let mut media_cache: MediaCache = self.read(MEDIA_CACHE_FILE);
+		if let Some(file_unique_id) = file_unique_id {
+			let key = network_key(network, file_unique_id);
+			cache_insert(&mut media_cache.mxc_uris, key, mxc_uri.to_owned());
+		}
+		if let Some((kind, file_id)) = remote_file {
+			let key = file_key(network, kind, mxc_uri);
+			cache_insert(&mut media_cache.file_ids, key, file_id.to_string());
+		}
+		self.write(MEDIA_CACHE_FILE, &media_cache)
+	}
+
+	#[must_use]
+	pub fn is_muted(&self, chat_id: ChatId) -> bool {
+		self.read::<Vec<ChatId>>(MUTED_CHATS_FILE).contains(&chat_id)
+	}
+
+	// returns whether the chat is muted afterwards
+	pub fn toggle_muted(&self, chat_id: ChatId) -> anyhow::Result<bool> {
+		self.toggle(MUTED_CHATS_FILE, chat_id)
+	}
+
+	#[must_use]
+	pub fn is_opted_out(&self, sender_id: &str) -> bool {
+		self.read::<Vec<String>>(OPTED_OUT_USERS_FILE).iter().any(|user| user == sender_id)
+	}
+
+	// returns whether the user is opted out afterwards
+	pub fn toggle_opted_out(&self, sender_id: &str) -> anyhow::Result<bool> {
+		self.toggle(OPTED_OUT_USERS_FILE, sender_id.to_string())
+	}
+
+	// adds the item to the list in file_name, or removes it if it's already there
+	#[allow(clippy::missing_panics_doc)]
+	fn toggle<T>(&self, file_name: &str, item: T) -> anyhow::Result<bool>
+	where
+		T: Serialize + DeserializeOwned + PartialEq,
+	{
+		let _lock = self.lock.lock().unwrap();
+		let mut items: Vec<T> = self.read(file_name);
+		let added = !items.contains(&item);
+		if added {
+			items.push(item);
+		} else {
+			items.retain(|i| *i != item);
+		}
+		self.write(file_name, &items)?;
+		Ok(added)
+	}
+
+	#[must_use]
+	pub fn get_portals(&self) -> Vec<Bridge> {
+		self.read(PORTALS_FILE)
+	}
+
+	#[allow(clippy::missing_panics_doc)]
+	pub fn add_portal(&self, bridge: Bridge) -> anyhow::Result<()> {
+		let _lock = self.lock.lock().unwrap();
+		let mut portals = self.get_portals();
+		portals.retain(|p| p.tg_id != bridge.tg_id);
+		portals.push(bridge);
+		self.write(PORTALS_FILE, &portals)
+	}
+
+	#[allow(clippy::missing_panics_doc)]
+	pub fn move_portal(&self, old_tg_id: i64, bridge: Bridge) -> anyhow::Result<()> {
+		let _lock = self.lock.lock().unwrap();
+		let mut portals = self.get_portals();
+		portals.retain(|p| p.tg_id != old_tg_id && p.tg_id != bridge.tg_id);
+		portals.push(bridge);
+		self.write(PORTALS_FILE, &portals)
+	}
+
+	#[must_use]
+	pub fn get_sticker_packs(&self) -> Vec<StickerPack> {
+		self.read(STICKER_PACKS_FILE)
+	}
+
+	#[allow(clippy::missing_panics_doc)]
+	pub fn update_sticker_pack(&self, sticker_pack: StickerPack) -> anyhow::Result<()> {
+		let _lock = self.lock.lock().unwrap();
+		let mut sticker_packs = self.get_sticker_packs();
+		sticker_packs.retain(|p| {
+			p.set_name != sticker_pack.set_name || p.matrix_chat_id != sticker_pack.matrix_chat_id
+		});
+		sticker_packs.push(sticker_pack);
+		self.write(STICKER_PACKS_FILE, &sticker_packs)
+	}
 }
@@ -0,0 +1,635 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use anyhow::bail;
+use anyhow::Context;
+use futures_util::SinkExt;
+use futures_util::Stream;
+use futures_util::StreamExt;
+use matrix_sdk::ruma::RoomId;
+use reqwest::header::AUTHORIZATION;
+use reqwest::multipart;
+use reqwest::Method;
+use reqwest::RequestBuilder;
+use reqwest::StatusCode;
+use serde::Deserialize;
+use serde_json::json;
+use serde_json::Value;
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio_tungstenite::tungstenite;
+use tokio_tungstenite::MaybeTlsStream;
+use tokio_tungstenite::WebSocketStream;
+
+use crate::bridge_structs::BridgeContext;
+use crate::bridge_structs::DiscordConfig;
+use crate::bridge_structs::LinkPreviewPolicy;
+use crate::network::ContentKind;
+use crate::network::IncomingMedia;
+use crate::network::IncomingMessage;
+use crate::network::OutgoingMessage;
+use crate::network::RemoteEvent;
+use crate::network::RemoteNetwork;
+use crate::network::SendOptions;
+use crate::network::SentMessage;
+use crate::relay::remote_to_mx;
+use crate::templates::Sender;
+use crate::templates::SenderTemplates;
+
+const WEBHOOK_NAME: &str = "matrix bridge";
+const MAX_CONTENT_LEN: usize = 2000;
+const MAX_USERNAME_LEN: usize = 80;
+// guild messages, guild message reactions and message content
+const INTENTS: u64 = (1 << 9) | (1 << 10) | (1 << 15);
+const SUPPRESS_EMBEDS: u64 = 1 << 2;
+
+pub type DiscordEvent = RemoteEvent<String, (String, String)>;
+
+type Socket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+#[derive(Deserialize, Clone)]
+struct Webhook {
+	id: String,
+	token: Option<String>,
+	guild_id: Option<String>,
+	name: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct User {
+	id: String,
+	username: String,
+	global_name: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Member {
+	nick: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Attachment {
+	id: String,
+	filename: String,
+	content_type: Option<String>,
+	url: String,
+}
+
+#[derive(Deserialize)]
+struct MessageReference {
+	message_id: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Message {
+	id: String,
+	channel_id: String,
+	author: Option<User>,
+	member: Option<Member>,
+	content: Option<String>,
+	#[serde(default)]
+	attachments: Vec<Attachment>,
+	message_reference: Option<MessageReference>,
+	application_id: Option<String>,
+	edited_timestamp: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct MessageDelete {
+	id: String,
+	channel_id: String,
+}
+
+#[derive(Deserialize)]
+struct Emoji {
+	id: Option<String>,
+	name: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ReactionAdd {
+	user_id: String,
+	channel_id: String,
+	message_id: String,
+	emoji: Emoji,
+}
+
+#[derive(Deserialize)]
+struct Application {
+	id: String,
+}
+
+#[derive(Deserialize)]
+struct Ready {
+	user: User,
+	application: Application,
+}
+
+#[derive(Deserialize)]
+struct GatewayPayload {
+	op: u8,
+	#[serde(default)]
+	d: Value,
+	s: Option<u64>,
+	t: Option<String>,
+}
+
+#[derive(Clone)]
+struct Rest {
+	http: reqwest::Client,
+	api_url: String,
+	token: String,
+}
+
+impl Rest {
+	fn builder(&self, method: Method, path: &str) -> RequestBuilder {
+		self.http
+			.request(method, format!("{}{path}", self.api_url))
+			.header(AUTHORIZATION, format!("Bot {}", self.token))
+	}
+
+	async fn request(
+		&self,
+		method: Method,
+		path: &str,
+		body: Option<&Value>,
+	) -> anyhow::Result<Value> {
+		self.send(|| {
+			let request = self.builder(method.clone(), path);
+			Ok(match body {
+				Some(body) => request.json(body),
+				None => request,
+			})
+		})
+		.await
+	}
+
+	// the request is built again when it's rate limited
+	async fn send(
+		&self,
+		request: impl Fn() -> anyhow::Result<RequestBuilder> + Send + Sync,
+	) -> anyhow::Result<Value> {
+		loop {
+			let response = request()?.send().await?;
+			let status = response.status();
+			if status == StatusCode::TOO_MANY_REQUESTS {
+				let body = response.json::<Value>().await?;
+				let retry_after = body["retry_after"].as_f64().unwrap_or(1.0);
+				tokio::time::sleep(Duration::from_secs_f64(retry_after)).await;
+				continue;
+			}
+			if !status.is_success() {
+				bail!("discord returned {status}: {}", response.text().await?);
+			}
+			if status == StatusCode::NO_CONTENT {
+				return Ok(Value::Null);
+			}
+			return Ok(response.json().await?);
+		}
+	}
+}
+
+pub struct DiscordNetwork {
+	rest: Rest,
+	// messages are sent through a webhook to show the matrix sender's name
+	webhooks: Mutex<HashMap<String, Webhook>>,
+}
+
+impl DiscordNetwork {
+	#[must_use]
+	pub fn new(config: &DiscordConfig) -> Self {
+		Self {
+			rest: Rest {
+				http: reqwest::Client::new(),
+				api_url: config.api_url.trim_end_matches('/').to_string(),
+				token: config.token.clone(),
+			},
+			webhooks: Mutex::default(),
+		}
+	}
+
+	async fn webhook(&self, channel_id: &str) -> anyhow::Result<Webhook> {
+		if let Some(webhook) = self.webhooks.lock().unwrap().get(channel_id) {
+			return Ok(webhook.clone());
+		}
+		let path = format!("/channels/{channel_id}/webhooks");
+		let webhooks: Vec<Webhook> =
+			serde_json::from_value(self.rest.request(Method::GET, &path, None).await?)?;
+		let webhook = webhooks.into_iter().find(|webhook| {
+			webhook.name.as_deref() == Some(WEBHOOK_NAME) && webhook.token.is_some()
+		});
+		let webhook = match webhook {
+			Some(webhook) => webhook,
+			None => {
+				let body = json!({ "name": WEBHOOK_NAME });
+				serde_json::from_value(self.rest.request(Method::POST, &path, Some(&body)).await?)?
+			}
+		};
+		self.webhooks.lock().unwrap().insert(channel_id.to_string(), webhook.clone());
+		Ok(webhook)
+	}
+
+	async fn webhook_message_path(
+		&self,
+		(channel_id, message_id): &(String, String),
+	) -> anyhow::Result<String> {
+		let webhook = self.webhook(channel_id).await?;
+		let token = webhook.token.context("webhook has no token")?;
+		Ok(format!("/webhooks/{}/{token}/messages/{message_id}", webhook.id))
+	}
+}
+
+fn truncate(text: &str, max_len: usize) -> String {
+	match text.char_indices().nth(max_len) {
+		Some((i, _)) => {
+			format!("{}…", &text[..i - text[..i].chars().last().map_or(0, char::len_utf8)])
+		}
+		None => text.to_string(),
+	}
+}
+
+// discord rejects webhook names with these, matrix ids always have them
+fn webhook_username(name: &str) -> String {
+	let name = name.replace("```", "").replace(['@', '#', ':'], " ");
+	let name = truncate(name.trim(), MAX_USERNAME_LEN);
+	if name.is_empty() {
+		"matrix user".to_string()
+	} else {
+		name
+	}
+}
+
+fn is_file_name(caption: &str) -> bool {
+	!caption.contains(char::is_whitespace) && caption.contains('.')
+}
+
+fn file_name(message: &OutgoingMessage) -> String {
+	match (&message.caption, &message.kind) {
+		(Some(caption), _) if is_file_name(caption) => caption.clone(),
+		(_, Some(ContentKind::Photo)) => "image.jpg".to_string(),
+		(_, Some(ContentKind::Sticker)) => "sticker.png".to_string(),
+		(_, Some(ContentKind::Video)) => "video.mp4".to_string(),
+		_ => "file".to_string(),
+	}
+}
+
+fn message_content(message: &OutgoingMessage, reply_link: Option<String>) -> String {
+	let mut content = String::new();
+	if let Some(reply_link) = reply_link {
+		content.push_str(&format!("> ↪ {reply_link}\n"));
+	} else if let Some(quote) = &message.reply_quote {
+		content.push_str(&format!("> {}: {}\n", quote.sender, quote.text));
+	}
+	match (&message.kind, message.location) {
+		(Some(ContentKind::Location), Some((latitude, longitude))) => content
+			.push_str(&format!("https://www.openstreetmap.org/?mlat={latitude}&mlon={longitude}")),
+		(Some(ContentKind::Text), _) => content.push_str(&message.text()),
+		_ => {
+			let caption = message.caption.as_deref().filter(|caption| !is_file_name(caption));
+			content.push_str(caption.unwrap_or_default());
+		}
+	}
+	truncate(&content, MAX_CONTENT_LEN)
+}
+
+impl RemoteNetwork for DiscordNetwork {
+	type ChatId = String;
+	type MessageId = (String, String);
+
+	const NAME: &'static str = "discord";
+
+	async fn send(
+		&self,
+		chat_id: &String,
+		message: &OutgoingMessage,
+		options: SendOptions<'_, Self::MessageId>,
+	) -> anyhow::Result<SentMessage<Self::MessageId>> {
+		let webhook = self.webhook(chat_id).await?;
+		let token = webhook.token.as_deref().context("webhook has no token")?;
+		let path = format!("/webhooks/{}/{token}?wait=true", webhook.id);
+		// webhooks can't reply, so link the replied-to message instead
+		let reply_link = options.reply_to.map(|(channel_id, message_id)| {
+			let guild_id = webhook.guild_id.as_deref().unwrap_or("@me");
+			format!("https://discord.com/channels/{guild_id}/{channel_id}/{message_id}")
+		});
+		let mut payload = json!({
+			"content": message_content(message, reply_link),
+			"username": webhook_username(&options.sender.name),
+			"allowed_mentions": { "parse": [] },
+		});
+		if options.link_previews == LinkPreviewPolicy::Never || message.is_preview_disabled {
+			payload["flags"] = json!(SUPPRESS_EMBEDS);
+		}
+		let sent = match message.kind {
+			Some(ContentKind::Text | ContentKind::Location) | None => {
+				self.rest.request(Method::POST, &path, Some(&payload)).await?
+			}
+			Some(_) => {
+				let file_name = file_name(message);
+				let request = || {
+					let file = multipart::Part::bytes(message.message.clone())
+						.file_name(file_name.clone());
+					let form = multipart::Form::new()
+						.text("payload_json", payload.to_string())
+						.part("files[0]", file);
+					Ok(self.rest.builder(Method::POST, &path).multipart(form))
+				};
+				self.rest.send(request).await?
+			}
+		};
+		let sent: Message = serde_json::from_value(sent)?;
+		Ok(SentMessage {
+			id: (sent.channel_id, sent.id),
+			// attachment urls expire, so there's nothing to send again
+			file: None,
+		})
+	}
+
+	async fn edit(
+		&self,
+		id: &Self::MessageId,
+		text: &str,
+		_sender: &Sender,
+		_templates: &SenderTemplates,
+	) -> anyhow::Result<()> {
+		let path = self.webhook_message_path(id).await?;
+		let body = json!({ "content": truncate(text, MAX_CONTENT_LEN) });
+		self.rest.request(Method::PATCH, &path, Some(&body)).await?;
+		Ok(())
+	}
+
+	async fn delete(&self, id: &Self::MessageId) -> anyhow::Result<()> {
+		let path = self.webhook_message_path(id).await?;
+		self.rest.request(Method::DELETE, &path, None).await?;
+		Ok(())
+	}
+
+	async fn react(
+		&self,
+		(channel_id, message_id): &Self::MessageId,
+		emoji: &str,
+	) -> anyhow::Result<()> {
+		let emoji = url::form_urlencoded::byte_serialize(emoji.as_bytes()).collect::<String>();
+		let path = format!("/channels/{channel_id}/messages/{message_id}/reactions/{emoji}/@me");
+		self.rest.request(Method::PUT, &path, None).await?;
+		Ok(())
+	}
+
+	async fn fetch_media(&self, file_id: &str) -> anyhow::Result<Vec<u8>> {
+		let response = self.rest.http.get(file_id).send().await?.error_for_status()?;
+		Ok(response.bytes().await?.to_vec())
+	}
+
+	fn incoming(&self) -> impl Stream<Item = DiscordEvent> + Send + 'static {
+		let (events, receiver) = mpsc::unbounded_channel();
+		tokio::spawn(run_gateway(self.rest.clone(), events));
+		futures_util::stream::unfold(receiver, |mut receiver| async move {
+			let event = receiver.recv().await?;
+			Some((event, receiver))
+		})
+	}
+}
+
+async fn run_gateway(rest: Rest, events: mpsc::UnboundedSender<DiscordEvent>) {
+	while !events.is_closed() {
+		if let Err(e) = gateway_session(&rest, &events).await {
+			log::error!("discord gateway: {e}");
+		}
+		tokio::time::sleep(Duration::from_secs(5)).await;
+	}
+}
+
+async fn next_payload(socket: &mut Socket) -> anyhow::Result<Option<GatewayPayload>> {
+	while let Some(message) = socket.next().await {
+		match message? {
+			tungstenite::Message::Text(text) => return Ok(Some(serde_json::from_str(&text)?)),
+			tungstenite::Message::Close(_) => return Ok(None),
+			_ => (),
+		}
+	}
+	Ok(None)
+}
+
+async fn gateway_session(
+	rest: &Rest,
+	events: &mpsc::UnboundedSender<DiscordEvent>,
+) -> anyhow::Result<()> {
+	let gateway = rest.request(Method::GET, "/gateway/bot", None).await?;
+	let url = gateway["url"].as_str().context("no gateway url")?;
+	let (mut socket, _) =
+		tokio_tungstenite::connect_async(format!("{url}/?v=10&encoding=json")).await?;
+	let hello = next_payload(&mut socket).await?.context("gateway closed before hello")?;
+	let interval = hello.d["heartbeat_interval"].as_u64().context("no heartbeat interval")?;
+	let identify = json!({
+		"op": 2,
+		"d": {
+			"token": rest.token,
+			"intents": INTENTS,
+			"properties": {
+				"os": std::env::consts::OS,
+				"browser": "tg-matrix-bridge",
+				"device": "tg-matrix-bridge",
+			},
+		},
+	});
+	socket.send(tungstenite::Message::text(identify.to_string())).await?;
+	let mut heartbeat = tokio::time::interval(Duration::from_millis(interval));
+	let mut sequence = None;
+	// the bot's user and application, to skip what the bridge sent itself
+	let mut own_ids = vec![];
+	loop {
+		tokio::select! {
+			_ = heartbeat.tick() => {
+				let payload = json!({ "op": 1, "d": sequence });
+				socket.send(tungstenite::Message::text(payload.to_string())).await?;
+			}
+			payload = next_payload(&mut socket) => {
+				let payload = payload?.context("gateway closed")?;
+				sequence = payload.s.or(sequence);
+				match payload.op {
+					0 => {
+						let kind = payload.t.unwrap_or_default();
+						match gateway_event(&kind, payload.d, &mut own_ids) {
+							Ok(Some(event)) => events.send(event)?,
+							Ok(None) => (),
+							Err(e) => log::error!("{kind}: {e}"),
+						}
+					}
+					1 => heartbeat.reset_immediately(),
+					7 => return Ok(()),
+					9 => bail!("invalid session"),
+					_ => (),
+				}
+			}
+		}
+	}
+}
+
+fn discord_sender(author: &User, member: Option<&Member>) -> Sender {
+	let name = member
+		.and_then(|member| member.nick.clone())
+		.or_else(|| author.global_name.clone())
+		.unwrap_or_else(|| author.username.clone());
+	Sender {
+		name,
+		username: format!("@{}", author.username),
+	}
+}
+
+fn content_kind(mime: &str) -> ContentKind {
+	match mime.split('/').next() {
+		Some("image") => ContentKind::Photo,
+		Some("video") => ContentKind::Video,
+		_ => ContentKind::Document,
+	}
+}
+
+fn incoming_message(message: Message) -> Option<IncomingMessage<String, (String, String)>> {
+	let author = message.author?;
+	let mut text = message.content.unwrap_or_default();
+	let mut attachments = message.attachments.into_iter();
+	let media = attachments.next().map(|attachment| {
+		let mime =
+			attachment.content_type.unwrap_or_else(|| mime::APPLICATION_OCTET_STREAM.to_string());
+		IncomingMedia {
+			file_id: attachment.url,
+			unique_id: attachment.id,
+			name: attachment.filename,
+			kind: content_kind(&mime),
+			mime,
+		}
+	});
+	// only the first attachment is uploaded, the others are linked
+	for attachment in attachments {
+		if !text.is_empty() {
+			text.push('\n');
+		}
+		text.push_str(&attachment.url);
+	}
+	let channel_id = message.channel_id;
+	let reply_to = message.message_reference.and_then(|reference| reference.message_id);
+	Some(IncomingMessage {
+		id: (channel_id.clone(), message.id),
+		sender: discord_sender(&author, message.member.as_ref()),
+		sender_id: format!("discord:{}", author.id),
+		text,
+		html: None,
+		reply_to: reply_to.map(|message_id| (channel_id.clone(), message_id)),
+		media,
+		chat_id: channel_id,
+	})
+}
+
+fn is_own(message: &Message, own_ids: &[String]) -> bool {
+	let author_id = message.author.as_ref().map(|author| &author.id);
+	[author_id, message.application_id.as_ref()]
+		.into_iter()
+		.flatten()
+		.any(|id| own_ids.contains(id))
+}
+
+fn gateway_event(
+	kind: &str,
+	data: Value,
+	own_ids: &mut Vec<String>,
+) -> anyhow::Result<Option<DiscordEvent>> {
+	let event = match kind {
+		"READY" => {
+			let ready: Ready = serde_json::from_value(data)?;
+			*own_ids = vec![ready.user.id, ready.application.id];
+			None
+		}
+		"MESSAGE_CREATE" => {
+			let message: Message = serde_json::from_value(data)?;
+			if is_own(&message, own_ids) {
+				return Ok(None);
+			}
+			incoming_message(message).map(RemoteEvent::Message)
+		}
+		"MESSAGE_UPDATE" => {
+			let message: Message = serde_json::from_value(data)?;
+			if is_own(&message, own_ids) {
+				return Ok(None);
+			}
+			// updates without content only add embeds
+			let (Some(author), Some(text)) = (&message.author, message.content) else {
+				return Ok(None);
+			};
+			Some(RemoteEvent::Edit {
+				chat_id: message.channel_id.clone(),
+				id: (message.channel_id, message.id),
+				edit_id: message.edited_timestamp,
+				sender: discord_sender(author, message.member.as_ref()),
+				text,
+			})
+		}
+		"MESSAGE_DELETE" => {
+			let delete: MessageDelete = serde_json::from_value(data)?;
+			Some(RemoteEvent::Delete {
+				chat_id: delete.channel_id.clone(),
+				id: (delete.channel_id, delete.id),
+			})
+		}
+		"MESSAGE_REACTION_ADD" => {
+			let reaction: ReactionAdd = serde_json::from_value(data)?;
+			if own_ids.contains(&reaction.user_id) {
+				return Ok(None);
+			}
+			let name = reaction.emoji.name.context("reaction has no emoji")?;
+			let emoji = match reaction.emoji.id {
+				Some(_) => format!(":{name}:"),
+				None => name,
+			};
+			Some(RemoteEvent::Reaction {
+				chat_id: reaction.channel_id.clone(),
+				id: (reaction.channel_id, reaction.message_id),
+				emoji,
+			})
+		}
+		_ => None,
+	};
+	Ok(event)
+}
+
+#[must_use]
+pub fn discord_queue_key(channel_id: &str) -> String {
+	format!("discord:{channel_id}")
+}
+
+// bridges discord events of bridged channels to matrix
+pub async fn discord_incoming(ctx: Arc<BridgeContext>) {
+	let Some(discord) = &ctx.discord else {
+		return;
+	};
+	let mut events = std::pin::pin!(discord.incoming());
+	while let Some(event) = events.next().await {
+		if ctx.discord_bridge_by_channel(event.chat_id()).is_none() {
+			continue;
+		}
+		let queue_ctx = ctx.clone();
+		ctx.queues
+			.enqueue(discord_queue_key(event.chat_id()), async move {
+				let ctx = &queue_ctx;
+				let discord = ctx.discord.as_ref().context("discord isn't configured")?;
+				let bridge = ctx
+					.discord_bridge_by_channel(event.chat_id())
+					.context("channel isn't bridged")?;
+				let matrix_room = ctx
+					.client
+					.get_room(&RoomId::parse(&bridge.mx_id)?)
+					.context("can't get matrix room")?;
+				remote_to_mx(
+					discord,
+					&ctx.client,
+					&ctx.store,
+					&matrix_room,
+					&bridge.templates,
+					event,
+				)
+				.await
+			})
+			.await;
+	}
+}
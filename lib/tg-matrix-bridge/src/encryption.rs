@@ -0,0 +1,205 @@
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::sync::Arc;
+
+use anyhow::bail;
+use anyhow::Context;
+use futures_util::StreamExt;
+use matrix_sdk::encryption::recovery::RecoveryState;
+use matrix_sdk::encryption::verification::SasState;
+use matrix_sdk::encryption::verification::SasVerification;
+use matrix_sdk::encryption::verification::VerificationRequestState;
+use matrix_sdk::encryption::BackupDownloadStrategy;
+use matrix_sdk::encryption::EncryptionSettings;
+use matrix_sdk::ruma::events::key::verification::request::ToDeviceKeyVerificationRequestEvent;
+use matrix_sdk::ruma::events::room::message::MessageType;
+use matrix_sdk::ruma::events::room::message::OriginalSyncRoomMessageEvent;
+use matrix_sdk::ruma::events::room::message::RoomMessageEventContent;
+use matrix_sdk::ruma::events::AnyMessageLikeEventContent;
+use matrix_sdk::ruma::events::AnySyncMessageLikeEvent;
+use matrix_sdk::ruma::OwnedUserId;
+use matrix_sdk::ruma::UserId;
+use matrix_sdk::Room;
+
+use crate::bridge_structs::BridgeContext;
+
+const RECOVERY_KEY_FILE: &str = "recovery_key";
+
+// cross-signing is bootstrapped on login, room keys missing locally are fetched from the backup
+#[must_use]
+pub fn encryption_settings() -> EncryptionSettings {
+	EncryptionSettings {
+		auto_enable_cross_signing: true,
+		backup_download_strategy: BackupDownloadStrategy::AfterDecryptionFailure,
+		auto_enable_backups: false,
+	}
+}
+
+pub async fn setup_encryption(ctx: Arc<BridgeContext>) {
+	if let Err(e) = setup_recovery(&ctx).await {
+		log::error!("{e}");
+	}
+}
+
+async fn setup_recovery(ctx: &BridgeContext) -> anyhow::Result<()> {
+	let encryption = ctx.client.encryption();
+	encryption.wait_for_e2ee_initialization_tasks().await;
+	let recovery = encryption.recovery();
+	match (recovery.state(), &ctx.config.encryption.recovery_key) {
+		(RecoveryState::Enabled, _) => (),
+		(RecoveryState::Incomplete, Some(recovery_key)) => recovery.recover(recovery_key).await?,
+		(RecoveryState::Incomplete, None) => {
+			bail!("secret storage exists but no recovery_key is configured")
+		}
+		(RecoveryState::Disabled, _) => {
+			let recovery_key = recovery.enable().wait_for_backups_to_upload().await?;
+			write_recovery_key(&recovery_key)?;
+			log::warn!(
+				"key backup enabled, set recovery_key in the config to the key in {RECOVERY_KEY_FILE}"
+			);
+		}
+		(RecoveryState::Unknown, _) => bail!("couldn't get the recovery state"),
+	}
+	let device = encryption.get_own_device().await?.context("no own device")?;
+	if !device.is_cross_signed_by_owner() {
+		device.verify().await?;
+	}
+	Ok(())
+}
+
+// only readable by the bridge user, the key unlocks every backed up room key
+fn write_recovery_key(recovery_key: &str) -> anyhow::Result<()> {
+	match std::fs::remove_file(RECOVERY_KEY_FILE) {
+		Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+		_ => (),
+	}
+	let mut file = OpenOptions::new()
+		.write(true)
+		.create_new(true)
+		.mode(0o600)
+		.open(RECOVERY_KEY_FILE)
+		.context("can't write the recovery key")?;
+	writeln!(file, "{recovery_key}")?;
+	Ok(())
+}
+
+fn is_admin(ctx: &BridgeContext, user_id: &UserId) -> bool {
+	ctx.config.encryption.admins.iter().any(|admin| admin == user_id.as_str())
+}
+
+pub async fn to_device_verification_handler(
+	ev: ToDeviceKeyVerificationRequestEvent,
+	ctx: Arc<BridgeContext>,
+) {
+	// the request only progresses with later syncs, so don't block this one
+	tokio::spawn(verification_request(ctx, ev.sender, ev.content.transaction_id.to_string()));
+}
+
+pub async fn room_verification_handler(ev: OriginalSyncRoomMessageEvent, ctx: Arc<BridgeContext>) {
+	if let MessageType::VerificationRequest(_) = ev.content.msgtype {
+		tokio::spawn(verification_request(ctx, ev.sender, ev.event_id.to_string()));
+	}
+}
+
+async fn verification_request(ctx: Arc<BridgeContext>, sender: OwnedUserId, flow_id: String) {
+	if !is_admin(&ctx, &sender) {
+		log::info!("ignoring verification request from {sender}");
+		return;
+	}
+	let Some(request) = ctx.client.encryption().get_verification_request(&sender, flow_id).await
+	else {
+		return;
+	};
+	if let Err(e) = request.accept().await {
+		log::error!("{e}");
+		return;
+	}
+	let mut changes = request.changes();
+	while let Some(state) = changes.next().await {
+		match state {
+			VerificationRequestState::Transitioned {
+				verification,
+			} => {
+				if let Some(sas) = verification.sas() {
+					track_sas(&ctx, sas).await;
+				}
+				return;
+			}
+			VerificationRequestState::Done | VerificationRequestState::Cancelled(_) => return,
+			_ => (),
+		}
+	}
+}
+
+async fn track_sas(ctx: &BridgeContext, sas: SasVerification) {
+	if let Err(e) = sas.accept().await {
+		log::error!("{e}");
+		return;
+	}
+	let mut changes = sas.changes();
+	while let Some(state) = changes.next().await {
+		match state {
+			SasState::KeysExchanged {
+				..
+			} => {
+				log::info!("verification with {} is waiting for !verify", sas.other_user_id());
+				*ctx.pending_sas.lock().unwrap() = Some(sas.clone());
+			}
+			SasState::Done {
+				..
+			}
+			| SasState::Cancelled(_) => {
+				ctx.pending_sas.lock().unwrap().take();
+				return;
+			}
+			_ => (),
+		}
+	}
+}
+
+// arguments of an admin's !verify command
+#[must_use]
+pub fn verify_command_args(ctx: &BridgeContext, ev: &AnySyncMessageLikeEvent) -> Option<String> {
+	let Some(AnyMessageLikeEventContent::RoomMessage(content)) = ev.original_content() else {
+		return None;
+	};
+	let MessageType::Text(text) = content.msgtype else {
+		return None;
+	};
+	let args = text.body.strip_prefix("!verify")?;
+	if !args.is_empty() && !args.starts_with(char::is_whitespace) {
+		return None;
+	}
+	is_admin(ctx, ev.sender()).then(|| args.to_string())
+}
+
+pub async fn verify_command(ctx: &BridgeContext, room: &Room, args: &str) -> anyhow::Result<()> {
+	let sas = ctx.pending_sas.lock().unwrap().clone();
+	let text = match (sas, args.trim()) {
+		(None, _) => "no verification is waiting, start one from your client first".to_string(),
+		(Some(sas), "confirm") => {
+			sas.confirm().await?;
+			"confirmed, the bridge device is verified once your client confirms too".to_string()
+		}
+		(Some(sas), "cancel") => {
+			sas.mismatch().await?;
+			"verification cancelled".to_string()
+		}
+		(Some(sas), _) => {
+			let emojis = sas.emoji().context("verification doesn't support emoji")?;
+			let emojis = emojis
+				.iter()
+				.map(|emoji| format!("{} {}", emoji.symbol, emoji.description))
+				.collect::<Vec<String>>()
+				.join(", ");
+			format!(
+				"verifying with {} {}: {emojis}\nsend !verify confirm if they match, !verify cancel otherwise",
+				sas.other_user_id(),
+				sas.other_device().device_id(),
+			)
+		}
+	};
+	utils::matrix::send(room.clone().into(), RoomMessageEventContent::notice_plain(text)).await?;
+	Ok(())
+}
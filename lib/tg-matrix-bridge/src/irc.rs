@@ -0,0 +1,492 @@
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::SystemTime;
+
+use anyhow::bail;
+use anyhow::Context;
+use base64::Engine;
+use futures_util::Stream;
+use futures_util::StreamExt;
+use matrix_sdk::ruma::RoomId;
+use tokio::io::AsyncBufReadExt;
+use tokio::io::AsyncRead;
+use tokio::io::AsyncWrite;
+use tokio::io::AsyncWriteExt;
+use tokio::io::BufReader;
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio_rustls::rustls;
+use tokio_rustls::TlsConnector;
+
+use crate::bridge_structs::BridgeContext;
+use crate::bridge_structs::IrcConfig;
+use crate::network::ContentKind;
+use crate::network::IncomingMessage;
+use crate::network::OutgoingMessage;
+use crate::network::RemoteEvent;
+use crate::network::RemoteNetwork;
+use crate::network::SendOptions;
+use crate::network::SentMessage;
+use crate::relay::remote_to_mx;
+use crate::templates::render;
+use crate::templates::Sender;
+use crate::templates::SenderTemplates;
+
+// a command line is 512 bytes, minus the line ending and room for the prefix the server adds
+const MAX_LINE_LEN: usize = 512 - 2 - 100;
+const MAX_LINES: usize = 10;
+// long display names would leave no room for the text
+const MAX_SENDER_LEN: usize = 64;
+// readable on both light and dark backgrounds
+const NICK_COLOURS: [u8; 7] = [3, 4, 6, 7, 10, 12, 13];
+
+pub type IrcEvent = RemoteEvent<String, (String, String)>;
+
+trait IrcStream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> IrcStream for T {}
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+// irc messages have no ids, these only need to be unique across restarts
+fn next_id() -> String {
+	let millis =
+		SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default().as_millis();
+	format!("{millis}-{}", NEXT_ID.fetch_add(1, Ordering::Relaxed))
+}
+
+pub struct IrcNetwork {
+	config: IrcConfig,
+	media_url: String,
+	// lines for the connection, kept across reconnects
+	outgoing: mpsc::UnboundedSender<String>,
+	outgoing_receiver: Mutex<Option<mpsc::UnboundedReceiver<String>>>,
+}
+
+impl IrcNetwork {
+	#[must_use]
+	pub fn new(config: &IrcConfig) -> Self {
+		let (outgoing, outgoing_receiver) = mpsc::unbounded_channel();
+		Self {
+			config: config.clone(),
+			media_url: config.media_url.trim_end_matches('/').to_string(),
+			outgoing,
+			outgoing_receiver: Mutex::new(Some(outgoing_receiver)),
+		}
+	}
+
+	fn send_lines(&self, channel: &str, lines: Vec<String>) -> anyhow::Result<()> {
+		for line in lines {
+			self.outgoing.send(privmsg(channel, &line)).context("irc isn't running")?;
+		}
+		Ok(())
+	}
+
+	fn media_link(&self, message: &OutgoingMessage) -> String {
+		let Some(mxc_uri) = &message.mxc_uri else {
+			return "(encrypted media)".to_string();
+		};
+		match mxc_uri.parts() {
+			Ok((server_name, media_id)) => {
+				format!("{}/{server_name}/{media_id}", self.media_url)
+			}
+			Err(_) => mxc_uri.to_string(),
+		}
+	}
+}
+
+fn privmsg(channel: &str, text: &str) -> String {
+	format!("PRIVMSG {channel} :{text}")
+}
+
+fn nick_colour(name: &str) -> u8 {
+	let hash =
+		name.bytes().fold(0usize, |hash, byte| hash.wrapping_mul(31).wrapping_add(byte.into()));
+	NICK_COLOURS[hash % NICK_COLOURS.len()]
+}
+
+fn truncate_name(name: &str) -> String {
+	if name.len() <= MAX_SENDER_LEN {
+		return name.to_string();
+	}
+	let mut end = MAX_SENDER_LEN - '…'.len_utf8();
+	while !name.is_char_boundary(end) {
+		end -= 1;
+	}
+	format!("{}…", &name[..end])
+}
+
+// the bold name of the telegram path, coloured by nick like irc clients do
+fn irc_sender(sender: &Sender, bold_name: bool) -> Sender {
+	let format = |name: &str| {
+		let name = truncate_name(name);
+		if bold_name {
+			format!("\x02\x03{:02}{name}\x03\x02", nick_colour(&name))
+		} else {
+			name
+		}
+	};
+	Sender {
+		name: format(&sender.name),
+		username: format(&sender.username),
+	}
+}
+
+fn split_line(line: &str, max_len: usize) -> Vec<&str> {
+	let mut chunks = vec![];
+	let mut rest = line;
+	while rest.len() > max_len {
+		let mut end = max_len;
+		while !rest.is_char_boundary(end) {
+			end -= 1;
+		}
+		// a char longer than the budget still makes up a chunk
+		if end == 0 {
+			end = rest.chars().next().map_or(0, char::len_utf8);
+		}
+		// break at the last space when there is one
+		let split = rest[..end].rfind(' ').filter(|&i| i > 0).unwrap_or(end);
+		chunks.push(&rest[..split]);
+		rest = rest[split..].trim_start_matches(' ');
+	}
+	chunks.push(rest);
+	chunks
+}
+
+// every line carries the sender, long lines are split at words
+fn irc_lines(
+	channel: &str,
+	text: &str,
+	sender: &Sender,
+	templates: &SenderTemplates,
+) -> Vec<String> {
+	let sender = irc_sender(sender, templates.bold_name);
+	let overhead = privmsg(channel, "").len() + render(&templates.text, &sender, "").len();
+	let max_len = MAX_LINE_LEN.saturating_sub(overhead).max(1);
+	let mut lines = text
+		.lines()
+		.flat_map(|line| split_line(line, max_len))
+		.filter(|line| !line.trim().is_empty())
+		.map(|line| render(&templates.text, &sender, line).replace(['\r', '\n'], " "))
+		.collect::<Vec<_>>();
+	if lines.len() > MAX_LINES {
+		lines.truncate(MAX_LINES - 1);
+		lines.push(render(&templates.text, &sender, "(message truncated)"));
+	}
+	lines
+}
+
+impl RemoteNetwork for IrcNetwork {
+	type ChatId = String;
+	type MessageId = (String, String);
+
+	const NAME: &'static str = "irc";
+	const LINKS_MEDIA: bool = true;
+
+	async fn send(
+		&self,
+		chat_id: &String,
+		message: &OutgoingMessage,
+		options: SendOptions<'_, Self::MessageId>,
+	) -> anyhow::Result<SentMessage<Self::MessageId>> {
+		let mut text = String::new();
+		if let Some(quote) = &message.reply_quote {
+			text.push_str(&format!("> {}: {}\n", quote.sender, quote.text));
+		}
+		match (&message.kind, message.location) {
+			(Some(ContentKind::Text) | None, _) => text.push_str(&message.text()),
+			(Some(ContentKind::Location), Some((latitude, longitude))) => text.push_str(&format!(
+				"https://www.openstreetmap.org/?mlat={latitude}&mlon={longitude}"
+			)),
+			_ => {
+				if let Some(caption) = &message.caption {
+					text.push_str(&format!("{caption} "));
+				}
+				text.push_str(&self.media_link(message));
+			}
+		}
+		self.send_lines(chat_id, irc_lines(chat_id, &text, &options.sender, options.templates))?;
+		Ok(SentMessage {
+			id: (chat_id.clone(), next_id()),
+			file: None,
+		})
+	}
+
+	// irc can't edit, the correction is sent again like matrix's fallback
+	async fn edit(
+		&self,
+		(channel, _): &Self::MessageId,
+		text: &str,
+		sender: &Sender,
+		templates: &SenderTemplates,
+	) -> anyhow::Result<()> {
+		self.send_lines(channel, irc_lines(channel, &format!("* {text}"), sender, templates))
+	}
+
+	// irc has no deletions or reactions
+	async fn delete(&self, _id: &Self::MessageId) -> anyhow::Result<()> {
+		Ok(())
+	}
+
+	async fn react(&self, _id: &Self::MessageId, _emoji: &str) -> anyhow::Result<()> {
+		Ok(())
+	}
+
+	async fn fetch_media(&self, _file_id: &str) -> anyhow::Result<Vec<u8>> {
+		bail!("irc has no media")
+	}
+
+	fn incoming(&self) -> impl Stream<Item = IrcEvent> + Send + 'static {
+		let (events, receiver) = mpsc::unbounded_channel();
+		if let Some(outgoing) = self.outgoing_receiver.lock().unwrap().take() {
+			tokio::spawn(run_connection(self.config.clone(), outgoing, events));
+		}
+		futures_util::stream::unfold(receiver, |mut receiver| async move {
+			let event = receiver.recv().await?;
+			Some((event, receiver))
+		})
+	}
+}
+
+struct IrcMessage<'a> {
+	source: Option<&'a str>,
+	command: &'a str,
+	params: Vec<&'a str>,
+}
+
+impl<'a> IrcMessage<'a> {
+	fn parse(line: &'a str) -> Option<Self> {
+		let mut rest = line.trim_end_matches(['\r', '\n']);
+		if rest.starts_with('@') {
+			rest = rest.split_once(' ')?.1;
+		}
+		let mut source = None;
+		if let Some(prefixed) = rest.strip_prefix(':') {
+			let (prefix, after) = prefixed.split_once(' ')?;
+			source = Some(prefix);
+			rest = after;
+		}
+		let (command, mut rest) = rest.split_once(' ').unwrap_or((rest, ""));
+		let mut params = vec![];
+		while !rest.is_empty() {
+			if let Some(trailing) = rest.strip_prefix(':') {
+				params.push(trailing);
+				break;
+			}
+			let (param, after) = rest.split_once(' ').unwrap_or((rest, ""));
+			if !param.is_empty() {
+				params.push(param);
+			}
+			rest = after;
+		}
+		Some(Self {
+			source,
+			command,
+			params,
+		})
+	}
+
+	fn nick(&self) -> Option<&'a str> {
+		self.source?.split('!').next()
+	}
+
+	fn param(&self, i: usize) -> &'a str {
+		self.params.get(i).copied().unwrap_or_default()
+	}
+}
+
+fn skip_digits(chars: &mut std::iter::Peekable<std::str::Chars<'_>>) {
+	for _ in 0..2 {
+		if chars.next_if(char::is_ascii_digit).is_none() {
+			return;
+		}
+	}
+}
+
+// drops bold, colour, italics and the other formatting codes
+fn strip_formatting(text: &str) -> String {
+	let mut stripped = String::new();
+	let mut chars = text.chars().peekable();
+	while let Some(c) = chars.next() {
+		match c {
+			'\x02' | '\x0f' | '\x11' | '\x16' | '\x1d' | '\x1e' | '\x1f' => (),
+			'\x03' => {
+				skip_digits(&mut chars);
+				let mut ahead = chars.clone();
+				if ahead.next() == Some(',') && ahead.peek().is_some_and(char::is_ascii_digit) {
+					chars.next();
+					skip_digits(&mut chars);
+				}
+			}
+			_ => stripped.push(c),
+		}
+	}
+	stripped
+}
+
+fn privmsg_event(message: &IrcMessage<'_>, own_nick: &str) -> Option<IrcEvent> {
+	let nick = message.nick()?;
+	let channel = message.param(0);
+	if nick.eq_ignore_ascii_case(own_nick) || !channel.starts_with(['#', '&']) {
+		return None;
+	}
+	let text = message.param(1);
+	let text = match text.strip_prefix('\x01') {
+		Some(ctcp) => format!("* {}", ctcp.strip_prefix("ACTION ")?.trim_end_matches('\x01')),
+		None => text.to_string(),
+	};
+	Some(RemoteEvent::Message(IncomingMessage {
+		chat_id: channel.to_string(),
+		id: (channel.to_string(), next_id()),
+		sender: Sender {
+			name: nick.to_string(),
+			username: nick.to_string(),
+		},
+		sender_id: format!("irc:{nick}"),
+		text: strip_formatting(&text),
+		html: None,
+		reply_to: None,
+		media: None,
+	}))
+}
+
+async fn connect(config: &IrcConfig) -> anyhow::Result<Box<dyn IrcStream>> {
+	let stream = TcpStream::connect((config.server.as_str(), config.port)).await?;
+	if !config.tls {
+		return Ok(Box::new(stream));
+	}
+	let roots = rustls::RootCertStore {
+		roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+	};
+	let provider = Arc::new(rustls::crypto::ring::default_provider());
+	let tls_config = rustls::ClientConfig::builder_with_provider(provider)
+		.with_safe_default_protocol_versions()?
+		.with_root_certificates(roots)
+		.with_no_client_auth();
+	let server_name = rustls::pki_types::ServerName::try_from(config.server.clone())?;
+	let stream = TlsConnector::from(Arc::new(tls_config)).connect(server_name, stream).await?;
+	Ok(Box::new(stream))
+}
+
+async fn run_connection(
+	config: IrcConfig,
+	mut outgoing: mpsc::UnboundedReceiver<String>,
+	events: mpsc::UnboundedSender<IrcEvent>,
+) {
+	while !events.is_closed() {
+		if let Err(e) = irc_session(&config, &mut outgoing, &events).await {
+			log::error!("irc: {e}");
+		}
+		tokio::time::sleep(Duration::from_secs(10)).await;
+	}
+}
+
+async fn send_line(writer: &mut (impl AsyncWrite + Unpin), line: &str) -> anyhow::Result<()> {
+	writer.write_all(format!("{line}\r\n").as_bytes()).await?;
+	Ok(())
+}
+
+async fn irc_session(
+	config: &IrcConfig,
+	outgoing: &mut mpsc::UnboundedReceiver<String>,
+	events: &mpsc::UnboundedSender<IrcEvent>,
+) -> anyhow::Result<()> {
+	let (reader, mut writer) = tokio::io::split(connect(config).await?);
+	let mut lines = BufReader::new(reader).lines();
+	let mut nick = config.nick.clone();
+	let mut registered = false;
+	if config.sasl.is_some() {
+		send_line(&mut writer, "CAP REQ :sasl").await?;
+	}
+	send_line(&mut writer, &format!("NICK {nick}")).await?;
+	send_line(&mut writer, &format!("USER {nick} 0 * :{nick}")).await?;
+	loop {
+		let reply = tokio::select! {
+			line = lines.next_line() => {
+				let line = line?.context("connection closed")?;
+				let Some(message) = IrcMessage::parse(&line) else {
+					continue;
+				};
+				match message.command {
+					"PING" => Some(format!("PONG :{}", message.param(0))),
+					"CAP" if message.param(1) == "ACK" => Some("AUTHENTICATE PLAIN".to_string()),
+					"CAP" if message.param(1) == "NAK" => bail!("the server doesn't support sasl"),
+					"AUTHENTICATE" if message.param(0) == "+" => {
+						let sasl = config.sasl.as_ref().context("sasl isn't configured")?;
+						let credentials = format!("\0{}\0{}", sasl.username, sasl.password);
+						Some(format!("AUTHENTICATE {}", base64::prelude::BASE64_STANDARD.encode(credentials)))
+					}
+					"903" => Some("CAP END".to_string()),
+					"902" | "904" | "905" => bail!("sasl authentication failed"),
+					"433" if !registered => {
+						nick.push('_');
+						Some(format!("NICK {nick}"))
+					}
+					"001" => {
+						registered = true;
+						message.param(0).clone_into(&mut nick);
+						let channels = config.bridges.iter().map(|bridge| bridge.channel.as_str());
+						Some(format!("JOIN {}", channels.collect::<Vec<_>>().join(",")))
+					}
+					"NICK" if message.nick() == Some(nick.as_str()) => {
+						message.param(0).clone_into(&mut nick);
+						None
+					}
+					"PRIVMSG" => {
+						if let Some(event) = privmsg_event(&message, &nick) {
+							events.send(event)?;
+						}
+						None
+					}
+					"ERROR" => bail!("{}", message.param(0)),
+					_ => None,
+				}
+			}
+			line = outgoing.recv(), if registered => {
+				let Some(line) = line else {
+					return Ok(());
+				};
+				Some(line)
+			}
+		};
+		if let Some(reply) = reply {
+			send_line(&mut writer, &reply).await?;
+		}
+	}
+}
+
+#[must_use]
+pub fn irc_queue_key(channel: &str) -> String {
+	format!("irc:{}", channel.to_lowercase())
+}
+
+// bridges irc messages of bridged channels to matrix
+pub async fn irc_incoming(ctx: Arc<BridgeContext>) {
+	let Some(irc) = &ctx.irc else {
+		return;
+	};
+	let mut events = std::pin::pin!(irc.incoming());
+	while let Some(event) = events.next().await {
+		if ctx.irc_bridge_by_channel(event.chat_id()).is_none() {
+			continue;
+		}
+		let queue_ctx = ctx.clone();
+		ctx.queues
+			.enqueue(irc_queue_key(event.chat_id()), async move {
+				let ctx = &queue_ctx;
+				let irc = ctx.irc.as_ref().context("irc isn't configured")?;
+				let bridge =
+					ctx.irc_bridge_by_channel(event.chat_id()).context("channel isn't bridged")?;
+				let matrix_room = ctx
+					.client
+					.get_room(&RoomId::parse(&bridge.mx_id)?)
+					.context("can't get matrix room")?;
+				remote_to_mx(irc, &ctx.client, &ctx.store, &matrix_room, &bridge.templates, event)
+					.await
+			})
+			.await;
+	}
+}
@@ -2,26 +2,79 @@
 use crate::bridge_utils::get_tg_bot;
 use std::sync::Arc;
 
-use crate::bridge_structs::Bridge;
+use crate::bot_commands::tg_command;
+use crate::bot_commands::BridgeCommand;
+use crate::bridge_structs::BridgeConfig;
+use crate::bridge_structs::BridgeContext;
+use crate::bridge_structs::BM_FILE_PATH;
+use crate::db::Store;
+use crate::matrix_handlers::client_event_handler;
+use crate::portals::tg_member_update;
+use crate::tg_handlers::tg_edit_to_mx;
+use crate::tg_handlers::tg_poll_answer;
+use crate::tg_handlers::tg_poll_update;
 use crate::tg_handlers::tg_to_mx;
 use matrix_sdk::Client;
 
 use teloxide::dispatching::Dispatcher;
+use teloxide::dispatching::HandlerExt;
 use teloxide::dispatching::UpdateFilterExt;
+use teloxide::prelude::Requester;
 use teloxide::update_listeners::webhooks;
+use teloxide::utils::command::BotCommands;
 
+pub mod bot_commands;
 pub mod bridge_structs;
 pub mod bridge_utils;
+pub mod commands;
+pub mod convert;
 pub mod db;
+pub mod discord;
+pub mod encryption;
+pub mod irc;
 pub mod matrix_handlers;
+pub mod network;
+pub mod portals;
+pub mod queue;
+pub mod relay;
+pub mod sticker_packs;
+pub mod telegram;
+pub mod templates;
 pub mod tg_handlers;
 mod timer;
 
 #[allow(clippy::missing_panics_doc)]
-pub async fn dispatch(client: Arc<Client>, bridges: Arc<Vec<Bridge>>, webhook_url: Arc<String>) {
+pub async fn dispatch(client: Arc<Client>, config: BridgeConfig) {
 	let bot = get_tg_bot().await;
+	let store = match Store::new(BM_FILE_PATH) {
+		Ok(store) => store,
+		Err(e) => {
+			log::error!("{e}");
+			return;
+		}
+	};
+	let ctx = Arc::new(BridgeContext::new(bot.clone(), client.clone(), store, config));
+
+	let handler_ctx = ctx.clone();
+	client.add_event_handler(move |ev, raw_event, room| {
+		client_event_handler(ev, raw_event, room, handler_ctx.clone())
+	});
+	let handler_ctx = ctx.clone();
+	client.add_event_handler(move |ev| {
+		encryption::to_device_verification_handler(ev, handler_ctx.clone())
+	});
+	let handler_ctx = ctx.clone();
+	client.add_event_handler(move |ev| {
+		encryption::room_verification_handler(ev, handler_ctx.clone())
+	});
+	tokio::spawn(encryption::setup_encryption(ctx.clone()));
+	tokio::spawn(tg_handlers::tg_incoming(ctx.clone()));
+	tokio::spawn(tg_handlers::setup_read_only_bridges(ctx.clone()));
+	tokio::spawn(discord::discord_incoming(ctx.clone()));
+	tokio::spawn(irc::irc_incoming(ctx.clone()));
+
 	let url =
-		url::Url::parse(&format!("{webhook_url}{}", bot.clone().into_inner().token())).unwrap();
+		url::Url::parse(&format!("{}{}", ctx.config.webhook_url, bot.inner().token())).unwrap();
 	let addr = ([0, 0, 0, 0], 8443).into();
 	let listener = loop {
 		match webhooks::axum(bot.clone(), webhooks::Options::new(addr, url.clone())).await {
@@ -38,12 +91,46 @@ pub async fn dispatch(client: Arc<Client>, bridges: Arc<Vec<Bridge>>, webhook_ur
 		}
 	};
 
-	let tg_update_handler =
-		teloxide::types::Update::filter_message().branch(teloxide::dptree::endpoint(tg_to_mx));
+	tokio::spawn(sticker_packs::sync_sticker_packs_loop(ctx.clone()));
+	if let Err(e) = bot.set_my_commands(BridgeCommand::bot_commands()).await {
+		log::error!("{e}");
+	}
+
+	// redelivered messages are caught by their stored mapping and transaction id
+	let tg_update_handler = teloxide::dptree::entry()
+		.branch(
+			teloxide::types::Update::filter_message()
+				.branch(
+					teloxide::dptree::entry()
+						.filter_command::<BridgeCommand>()
+						.endpoint(tg_command),
+				)
+				.branch(teloxide::dptree::endpoint(tg_to_mx)),
+		)
+		.branch(
+			teloxide::types::Update::filter_channel_post()
+				.branch(teloxide::dptree::endpoint(tg_to_mx)),
+		)
+		.branch(
+			teloxide::types::Update::filter_edited_channel_post()
+				.branch(teloxide::dptree::endpoint(tg_edit_to_mx)),
+		)
+		.branch(
+			teloxide::types::Update::filter_my_chat_member()
+				.branch(teloxide::dptree::endpoint(tg_member_update)),
+		)
+		.branch(
+			teloxide::types::Update::filter_poll()
+				.branch(teloxide::dptree::endpoint(tg_poll_update)),
+		)
+		.branch(
+			teloxide::types::Update::filter_poll_answer()
+				.branch(teloxide::dptree::endpoint(tg_poll_answer)),
+		);
 	let err_handler = teloxide::error_handlers::LoggingErrorHandler::new();
 	Box::pin(
 		Dispatcher::builder(bot, tg_update_handler)
-			.dependencies(teloxide::dptree::deps![client, bridges])
+			.dependencies(teloxide::dptree::deps![ctx])
 			.build()
 			.dispatch_with_listener(listener, err_handler),
 	)
@@ -1,217 +1,443 @@
 use std::sync::Arc;
 
 use crate::bridge_structs::BmMxData;
-use crate::bridge_structs::BmTgData;
 use crate::bridge_structs::Bridge;
-use crate::bridge_utils::bot_send_request;
-use crate::bridge_utils::get_bms;
-use crate::bridge_utils::get_tg_bot;
-use crate::bridge_utils::get_to_tg_data;
-use crate::bridge_utils::update_bridged_messages;
+use crate::bridge_structs::BridgeContext;
+use crate::bridge_structs::LinkPreviewPolicy;
+use crate::bridge_utils::get_mx_sender;
+use crate::bridge_utils::poll_results_text;
+use crate::bridge_utils::poll_vote_text;
+use crate::convert::mx_to_outgoing;
+use crate::db::BridgedPoll;
+use crate::discord::discord_queue_key;
+use crate::encryption::verify_command;
+use crate::encryption::verify_command_args;
+use crate::irc::irc_queue_key;
+use crate::network::RemoteNetwork;
+use crate::relay::fetch_mx_media;
+use crate::relay::mx_edit_to_remote;
+use crate::relay::mx_reaction_to_remote;
+use crate::relay::mx_redaction_to_remote;
+use crate::relay::mx_to_remote;
+use crate::relay::RemoteTarget;
+use crate::sticker_packs::sync_sticker_pack;
+use crate::sticker_packs::ROOM_EMOTES_EVENT_TYPE;
+use crate::templates::render;
 use anyhow::bail;
 use anyhow::Context;
 use matrix_sdk::event_handler::RawEvent;
-use matrix_sdk::media::MediaEventContent;
-use matrix_sdk::ruma::events::room::message::AddMentions;
-use matrix_sdk::ruma::events::room::message::ForwardThread;
-use matrix_sdk::ruma::events::room::message::ImageMessageEventContent;
+use matrix_sdk::ruma::events::poll::unstable_end::UnstablePollEndEventContent;
+use matrix_sdk::ruma::events::poll::unstable_response::UnstablePollResponseEventContent;
+use matrix_sdk::ruma::events::poll::unstable_start::UnstablePollStartEventContent;
+use matrix_sdk::ruma::events::relation::InReplyTo;
 use matrix_sdk::ruma::events::room::message::MessageType;
+use matrix_sdk::ruma::events::room::message::Relation;
 use matrix_sdk::ruma::events::room::message::RoomMessageEventContent;
-use matrix_sdk::ruma::events::AnyMessageLikeEvent;
+use matrix_sdk::ruma::events::room::redaction::SyncRoomRedactionEvent;
 use matrix_sdk::ruma::events::AnyMessageLikeEventContent;
 use matrix_sdk::ruma::events::AnySyncMessageLikeEvent;
-use matrix_sdk::ruma::events::AnyTimelineEvent;
 use matrix_sdk::ruma::events::MessageLikeUnsigned;
 use matrix_sdk::ruma::events::OriginalMessageLikeEvent;
-use matrix_sdk::ruma::EventId;
-use matrix_sdk::ruma::OwnedEventId;
-use matrix_sdk::Client;
+use matrix_sdk::ruma::events::StateEventType;
+use matrix_sdk::ruma::UInt;
+use matrix_sdk::ruma::UserId;
 use serde_json::Value;
-use teloxide::types::LinkPreviewOptions;
-use teloxide::types::MessageId;
+use teloxide::payloads::SendMessageSetters;
+use teloxide::payloads::SendPollSetters;
+use teloxide::prelude::Requester;
+use teloxide::types::ChatId;
 use teloxide::types::ReplyParameters;
-use teloxide::ApiError;
 
-fn find_tg_msg_id(reply: &OwnedEventId, mx_chat: &str) -> Option<MessageId> {
-	let bms = get_bms(mx_chat)?;
-	let bm = bms.iter().find(|bm| bm.matrix_id == *reply)?;
-	Some(bm.telegram_id.1)
+// the pack ends up in the room state, so only those who could set it themselves may import
+async fn can_import_sticker_pack(sender: &UserId, room: &matrix_sdk::Room) -> bool {
+	room.power_levels().await.is_ok_and(|power_levels| {
+		power_levels.user_can_send_state(sender, StateEventType::from(ROOM_EMOTES_EVENT_TYPE))
+	})
 }
 
-async fn get_reply(
-	matrix_event: &OriginalMessageLikeEvent<AnyMessageLikeEventContent>,
+async fn import_sticker_pack(
+	ctx: &BridgeContext,
+	args: &str,
+	sender: &UserId,
 	room: &matrix_sdk::Room,
-) -> Option<OwnedEventId> {
-	let timeline_event = match room.event(&matrix_event.event_id, None).await {
-		Ok(timeline_event) => timeline_event,
-		Err(e) => {
-			log::debug!("{:?}", e);
-			return None;
+) -> anyhow::Result<()> {
+	let set_name = args.trim();
+	let text = if !can_import_sticker_pack(sender, room).await {
+		"only room moderators can import sticker packs".to_string()
+	} else {
+		match sync_sticker_pack(ctx, room, set_name).await {
+			Ok(count) => format!("imported {count} stickers from {set_name}"),
+			Err(e) => format!("couldn't import {set_name}: {e}"),
 		}
 	};
-	let raw_json = match serde_json::from_str::<Value>(timeline_event.kind.raw().json().get()) {
-		Ok(raw_json) => raw_json,
-		Err(e) => {
-			log::debug!("{:?}", e);
-			return None;
-		}
+	utils::matrix::send(room.clone().into(), RoomMessageEventContent::notice_plain(text)).await?;
+	Ok(())
+}
+
+async fn mx_poll_to_tg(
+	ctx: &BridgeContext,
+	poll: &UnstablePollStartEventContent,
+	mx_event: &OriginalMessageLikeEvent<AnyMessageLikeEventContent>,
+	room: &matrix_sdk::Room,
+	bridge: &Bridge,
+) -> anyhow::Result<()> {
+	let UnstablePollStartEventContent::New(poll) = poll else {
+		bail!("poll edits aren't supported");
 	};
-	let reply_event_id = raw_json
-		.get("content")?
-		.get("m.relates_to")?
-		.get("m.in_reply_to")?
-		.get("event_id")?
-		.as_str()?;
-	let Ok(owned_event_id) = EventId::parse(reply_event_id) else {
-		return None;
+	let sender = get_mx_sender(room, &mx_event.sender).await;
+	let question = render(&bridge.templates.text, &sender, &poll.poll_start.question.text);
+	let options = poll.poll_start.answers.iter().map(|answer| answer.text.clone());
+	// telegram only reports votes to the bot for non-anonymous polls
+	let t_msg = ctx
+		.bot
+		.send_poll(ChatId(bridge.tg_id), question, options)
+		.allows_multiple_answers(poll.poll_start.max_selections > UInt::from(1u32))
+		.is_anonymous(false)
+		.await?;
+	let t_poll = t_msg.poll().context("sent message isn't a poll")?;
+	let matrix_chat_id = room.room_id().as_str();
+	ctx.store.update_bridged_messages(
+		mx_event.event_id.clone(),
+		(t_msg.chat.id, t_msg.id),
+		matrix_chat_id,
+	)?;
+	ctx.store.update_bridged_polls(BridgedPoll {
+		telegram_poll_id: t_poll.id.clone(),
+		matrix_id: mx_event.event_id.clone(),
+		matrix_chat_id: matrix_chat_id.to_string(),
+		telegram_id: (t_msg.chat.id, t_msg.id),
+		answers: poll
+			.poll_start
+			.answers
+			.iter()
+			.map(|answer| (answer.id.clone(), answer.text.clone()))
+			.collect(),
+	})?;
+	Ok(())
+}
+
+async fn mx_poll_response_to_tg(
+	ctx: &BridgeContext,
+	response: &UnstablePollResponseEventContent,
+	sender: &UserId,
+	room: &matrix_sdk::Room,
+	bridge: &Bridge,
+) -> anyhow::Result<()> {
+	let Some(bridged_poll) =
+		ctx.store.find_bridged_poll(|p| p.matrix_id == response.relates_to.event_id)
+	else {
+		return Ok(());
 	};
-	Some(owned_event_id)
+	let sender = get_mx_sender(room, sender).await;
+	let text = poll_vote_text(&bridged_poll.answers, &response.poll_response.answers);
+	let (chat_id, message_id) = bridged_poll.telegram_id;
+	ctx.bot
+		.send_message(chat_id, render(&bridge.templates.text, &sender, &text))
+		.reply_parameters(ReplyParameters::new(message_id).allow_sending_without_reply())
+		.await?;
+	Ok(())
 }
 
-pub async fn mx_to_tg(to_tg_data: BmTgData, from_mx_data: BmMxData<'_>) -> anyhow::Result<()> {
-	//crate::timer::timer!();
-	let bot = to_tg_data.bot.clone().context("bot not found")?;
-	let chat_id = to_tg_data.chat_id.context("chat not found")?;
-	let null_id = -1i32;
-	let matrix_chat_id = from_mx_data.room.room_id().as_str();
-	let matrix_event = from_mx_data.mx_event;
-	let reply_to_id = {
-		if let Some(matrix_reply) = get_reply(matrix_event, &from_mx_data.room).await {
-			find_tg_msg_id(&matrix_reply, matrix_chat_id).unwrap_or(MessageId(null_id))
-		} else {
-			MessageId(null_id)
-		}
+async fn mx_poll_end_to_tg(
+	ctx: &BridgeContext,
+	poll_end: &UnstablePollEndEventContent,
+	room: &matrix_sdk::Room,
+) -> anyhow::Result<()> {
+	let Some(bridged_poll) =
+		ctx.store.take_bridged_poll(|p| p.matrix_id == poll_end.relates_to.event_id)?
+	else {
+		return Ok(());
 	};
-	let from_user = from_mx_data.mx_event.sender.as_str();
-	let link_preview = LinkPreviewOptions {
-		is_disabled: to_tg_data.is_preview_disabled,
-		url: None,
-		prefer_large_media: true,
-		prefer_small_media: false,
-		show_above_text: false,
-	};
-	let reply_params = ReplyParameters::new(reply_to_id).allow_sending_without_reply();
-	let res = bot_send_request(
-		bot.clone(),
-		to_tg_data.clone(),
-		chat_id,
-		reply_params.clone(),
-		link_preview.clone(),
-		from_user.to_string(),
+	let bot = &ctx.bot;
+	let (chat_id, message_id) = bridged_poll.telegram_id;
+	let t_poll = bot.stop_poll(chat_id, message_id).await?;
+	bot.send_message(chat_id, &poll_end.text)
+		.reply_parameters(ReplyParameters::new(message_id).allow_sending_without_reply())
+		.await?;
+	let mut content = RoomMessageEventContent::notice_plain(poll_results_text(&t_poll));
+	content.relates_to = Some(Relation::Reply {
+		in_reply_to: InReplyTo::new(bridged_poll.matrix_id),
+	});
+	utils::matrix::send(room.clone().into(), content).await?;
+	Ok(())
+}
+
+fn sticker_pack_command_args(ev: &AnySyncMessageLikeEvent) -> Option<String> {
+	let Some(AnyMessageLikeEventContent::RoomMessage(content)) = ev.original_content() else {
+		return None;
+	};
+	let MessageType::Text(text) = content.msgtype else {
+		return None;
+	};
+	text.body.strip_prefix("!stickerpack ").map(str::to_string)
+}
+
+fn is_no_bridge_command(ev: &AnySyncMessageLikeEvent) -> bool {
+	matches!(
+		ev.original_content(),
+		Some(AnyMessageLikeEventContent::RoomMessage(RoomMessageEventContent {
+			msgtype: MessageType::Text(text),
+			..
+		})) if text.body.trim() == "!nobridge"
 	)
-	.await;
-	let t_msg = match res {
-		Ok(msg) => msg,
-		Err(teloxide::RequestError::Api(ApiError::RequestEntityTooLarge)) => {
-			let mut to_tg_data = to_tg_data;
-			to_tg_data.message =
-				"telegram sucks and cannot display this message".to_string().into_bytes();
-			bot_send_request(
-				bot,
-				to_tg_data,
-				chat_id,
-				reply_params,
-				link_preview,
-				from_user.to_string(),
-			)
-			.await?
-		}
-		_ => bail!(""),
+}
+
+async fn toggle_no_bridge(
+	ctx: &BridgeContext,
+	sender: &UserId,
+	room: &matrix_sdk::Room,
+) -> anyhow::Result<()> {
+	let text = if ctx.store.toggle_opted_out(sender.as_str())? {
+		format!("{sender}'s messages won't be bridged until !nobridge is sent again")
+	} else {
+		format!("{sender}'s messages are bridged again")
 	};
-	update_bridged_messages(
-		from_mx_data.mx_event.event_id.clone(),
-		(t_msg.chat.id, t_msg.id),
-		matrix_chat_id,
-	)?;
+	utils::matrix::send(room.clone().into(), RoomMessageEventContent::notice_plain(text)).await?;
 	Ok(())
 }
 
+fn is_bridged_room(ctx: &BridgeContext, mx_id: &str) -> bool {
+	ctx.bridge_by_mx(mx_id).is_some()
+		|| ctx.discord_bridge_by_mx(mx_id).is_some()
+		|| ctx.irc_bridge_by_mx(mx_id).is_some()
+}
+
+fn original_event(
+	ev: &AnySyncMessageLikeEvent,
+	content: AnyMessageLikeEventContent,
+	room: &matrix_sdk::Room,
+) -> OriginalMessageLikeEvent<AnyMessageLikeEventContent> {
+	OriginalMessageLikeEvent {
+		content,
+		event_id: ev.event_id().into(),
+		origin_server_ts: ev.origin_server_ts(),
+		room_id: room.room_id().into(),
+		sender: ev.sender().into(),
+		unsigned: MessageLikeUnsigned::new(),
+	}
+}
+
 pub async fn client_event_handler(
 	ev: AnySyncMessageLikeEvent,
 	raw: RawEvent,
 	room: matrix_sdk::Room,
-	client: Client,
-	bridges: Arc<Vec<Bridge>>,
+	ctx: Arc<BridgeContext>,
 ) {
-	let Some(client_id) = client.user_id() else {
+	let Some(client_id) = ctx.client.user_id() else {
 		return;
 	};
 	if ev.sender().as_str() == client_id.as_str() {
 		return;
 	}
-	let Some(bridge) = bridges.iter().find(|b| b.mx_id == room.room_id().as_str()) else {
+	if let Some(args) = verify_command_args(&ctx, &ev) {
+		if let Err(e) = verify_command(&ctx, &room, &args).await {
+			log::error!("{e}");
+		}
+		return;
+	}
+	if is_no_bridge_command(&ev) && is_bridged_room(&ctx, room.room_id().as_str()) {
+		if let Err(e) = toggle_no_bridge(&ctx, ev.sender(), &room).await {
+			log::error!("{e}");
+		}
+		return;
+	}
+	if let Some(bridge) = ctx.discord_bridge_by_mx(room.room_id().as_str()) {
+		if bridge.read_only || ctx.store.is_opted_out(ev.sender().as_str()) {
+			return;
+		}
+		let queue_ctx = ctx.clone();
+		ctx.queues
+			.enqueue(discord_queue_key(&bridge.channel_id), async move {
+				bridge_mx_event_to_discord(ev, raw, room, queue_ctx).await;
+				Ok(())
+			})
+			.await;
+		return;
+	}
+	if let Some(bridge) = ctx.irc_bridge_by_mx(room.room_id().as_str()) {
+		if bridge.read_only || ctx.store.is_opted_out(ev.sender().as_str()) {
+			return;
+		}
+		let queue_ctx = ctx.clone();
+		ctx.queues
+			.enqueue(irc_queue_key(&bridge.channel), async move {
+				bridge_mx_event_to_irc(ev, raw, room, queue_ctx).await;
+				Ok(())
+			})
+			.await;
+		return;
+	}
+	let Some(bridge) = ctx.bridge_by_mx(room.room_id().as_str()) else {
+		return;
+	};
+	if bridge.read_only || ctx.store.is_muted(ChatId(bridge.tg_id)) {
+		return;
+	}
+	if ctx.store.is_opted_out(ev.sender().as_str()) {
+		return;
+	}
+	// imports take a while, bridging carries on meanwhile
+	if let Some(args) = sticker_pack_command_args(&ev) {
+		let sender = ev.sender().to_owned();
+		tokio::spawn(async move {
+			if let Err(e) = import_sticker_pack(&ctx, &args, &sender, &room).await {
+				log::error!("{e}");
+			}
+		});
+		return;
+	}
+	let tg_id = bridge.tg_id;
+	let queue_ctx = ctx.clone();
+	ctx.queues
+		.enqueue(tg_id, async move {
+			bridge_mx_event(ev, raw, room, queue_ctx).await;
+			Ok(())
+		})
+		.await;
+}
+
+async fn bridge_mx_event(
+	ev: AnySyncMessageLikeEvent,
+	raw: RawEvent,
+	room: matrix_sdk::Room,
+	ctx: Arc<BridgeContext>,
+) {
+	let Some(bridge) = ctx.bridge_by_mx(room.room_id().as_str()) else {
 		return;
 	};
 	let Some(oc) = ev.original_content() else {
 		return;
 	};
-	let original_ev = OriginalMessageLikeEvent {
-		content: oc.clone(),
-		event_id: ev.event_id().into(),
-		origin_server_ts: ev.origin_server_ts(),
-		room_id: room.room_id().into(),
-		sender: ev.sender().into(),
-		unsigned: MessageLikeUnsigned::new(),
+	let res = match &oc {
+		AnyMessageLikeEventContent::UnstablePollStart(poll) => {
+			let original_ev = original_event(&ev, oc.clone(), &room);
+			Some(mx_poll_to_tg(&ctx, poll, &original_ev, &room, &bridge).await)
+		}
+		AnyMessageLikeEventContent::UnstablePollResponse(response) => {
+			Some(mx_poll_response_to_tg(&ctx, response, ev.sender(), &room, &bridge).await)
+		}
+		AnyMessageLikeEventContent::UnstablePollEnd(poll_end) => {
+			Some(mx_poll_end_to_tg(&ctx, poll_end, &room).await)
+		}
+		_ => None,
 	};
-	let room_message = if let AnyMessageLikeEventContent::Sticker(sticker) = oc {
-		let body = sticker.body.clone();
-		let Some(source) = sticker.source() else {
-			log::error!("sticker source not found");
-			return;
-		};
-		let event_content = ImageMessageEventContent::new(body, source);
-		let image_info = Some(Box::new(sticker.info));
-		let message_type = MessageType::Image(event_content.info(image_info));
-		let room_message = RoomMessageEventContent::new(message_type);
-		let Ok(raw_json_value) = serde_json::from_str::<serde_json::Value>(raw.get()) else {
-			return;
-		};
-		let reply_event_id = match raw_json_value["content"].get("m.relates_to") {
-			Some(relates_to) => {
-				if let Some(in_reply_to) = relates_to.get("m.in_reply_to") {
-					in_reply_to.get("event_id")
-				} else {
-					None
-				}
-			}
-			None => None,
-		};
-		if let Some(reply_event_id) = reply_event_id {
-			let Ok(event_id) = EventId::parse(reply_event_id.as_str().unwrap_or_default()) else {
-				return;
+	let res = match res {
+		Some(res) => res,
+		None => {
+			let target = RemoteTarget {
+				network: &ctx.network,
+				chat_id: ChatId(bridge.tg_id),
+				templates: &bridge.templates,
+				link_previews: bridge.link_previews,
 			};
-			let Ok(event) = room.event(&event_id, None).await else {
-				return;
+			relay_mx_event(&ctx, &target, &ev, &raw, room).await
+		}
+	};
+	if let Err(e) = res {
+		log::error!("{e}");
+	}
+}
+
+async fn bridge_mx_event_to_discord(
+	ev: AnySyncMessageLikeEvent,
+	raw: RawEvent,
+	room: matrix_sdk::Room,
+	ctx: Arc<BridgeContext>,
+) {
+	let (Some(discord), Some(bridge)) =
+		(&ctx.discord, ctx.discord_bridge_by_mx(room.room_id().as_str()))
+	else {
+		return;
+	};
+	let target = RemoteTarget {
+		network: discord,
+		chat_id: bridge.channel_id.clone(),
+		templates: &bridge.templates,
+		link_previews: LinkPreviewPolicy::default(),
+	};
+	if let Err(e) = relay_mx_event(&ctx, &target, &ev, &raw, room).await {
+		log::error!("{e}");
+	}
+}
+
+async fn bridge_mx_event_to_irc(
+	ev: AnySyncMessageLikeEvent,
+	raw: RawEvent,
+	room: matrix_sdk::Room,
+	ctx: Arc<BridgeContext>,
+) {
+	let (Some(irc), Some(bridge)) = (&ctx.irc, ctx.irc_bridge_by_mx(room.room_id().as_str()))
+	else {
+		return;
+	};
+	let target = RemoteTarget {
+		network: irc,
+		chat_id: bridge.channel.clone(),
+		templates: &bridge.templates,
+		link_previews: LinkPreviewPolicy::default(),
+	};
+	if let Err(e) = relay_mx_event(&ctx, &target, &ev, &raw, room).await {
+		log::error!("{e}");
+	}
+}
+
+// messages, edits, redactions and reactions are bridged the same way to every network
+async fn relay_mx_event<N: RemoteNetwork>(
+	ctx: &BridgeContext,
+	target: &RemoteTarget<'_, N>,
+	ev: &AnySyncMessageLikeEvent,
+	raw: &RawEvent,
+	room: matrix_sdk::Room,
+) -> anyhow::Result<()> {
+	let Some(oc) = ev.original_content() else {
+		return Ok(());
+	};
+	match &oc {
+		AnyMessageLikeEventContent::RoomMessage(RoomMessageEventContent {
+			relates_to: Some(Relation::Replacement(replacement)),
+			..
+		}) => {
+			let MessageType::Text(text) = &replacement.new_content.msgtype else {
+				bail!("only text edits are supported");
 			};
-			let Ok(AnyTimelineEvent::MessageLike(ev)) =
-				event.kind.raw().deserialize_as::<AnyTimelineEvent>()
+			let edited = &replacement.event_id;
+			return mx_edit_to_remote(&ctx.store, target, &room, ev.sender(), edited, &text.body)
+				.await;
+		}
+		AnyMessageLikeEventContent::RoomRedaction(_) => {
+			let AnySyncMessageLikeEvent::RoomRedaction(SyncRoomRedactionEvent::Original(redaction)) =
+				ev
 			else {
-				return;
+				return Ok(());
 			};
-			let AnyMessageLikeEvent::RoomMessage(msg_like_event) = ev else {
-				return;
-			};
-			let Some(oc) = msg_like_event.as_original() else {
-				return;
+			// the redacted event is in the content only since room version 11
+			let Some(redacts) = redaction.content.redacts.as_ref().or(redaction.redacts.as_ref())
+			else {
+				return Ok(());
 			};
-			room_message.clone().make_reply_to(oc, ForwardThread::No, AddMentions::No);
-		};
-		room_message
-	} else if let AnyMessageLikeEventContent::RoomMessage(room_message) = oc {
-		room_message
-	} else {
-		return;
+			return mx_redaction_to_remote(&ctx.store, target, &room, redacts).await;
+		}
+		AnyMessageLikeEventContent::Reaction(reaction) => {
+			let annotation = &reaction.relates_to;
+			let reacted = &annotation.event_id;
+			return mx_reaction_to_remote(&ctx.store, target, &room, reacted, &annotation.key)
+				.await;
+		}
+		_ => (),
+	}
+	let raw_event = serde_json::from_str::<Value>(raw.get())?;
+	let mut message = match mx_to_outgoing(&oc, &raw_event) {
+		Ok(message) => message,
+		Err(e) => {
+			log::debug!("{e}");
+			return Ok(());
+		}
 	};
+	fetch_mx_media::<N>(&ctx.client, &ctx.store, &mut message).await?;
+	let original_ev = original_event(ev, oc, &room);
 	let from_mx_data = BmMxData {
 		mx_event: &original_ev,
 		room,
-		mx_msg_type: &room_message.msgtype,
-	};
-	let Ok(to_tg_data) = get_to_tg_data(&from_mx_data, get_tg_bot().await, client, bridge).await
-	else {
-		return;
 	};
-	if let Err(e) = mx_to_tg(to_tg_data, from_mx_data).await {
-		log::error!("{e}");
-	}
+	mx_to_remote(&ctx.store, target, message, from_mx_data).await
 }
@@ -0,0 +1,192 @@
+use std::fmt::Debug;
+use std::fmt::Display;
+use std::future::Future;
+use std::ops::Range;
+
+use futures_util::Stream;
+use matrix_sdk::ruma::events::room::MediaSource;
+use matrix_sdk::ruma::OwnedEventId;
+use matrix_sdk::ruma::OwnedMxcUri;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::bridge_structs::LinkPreviewPolicy;
+use crate::templates::Sender;
+use crate::templates::SenderTemplates;
+
+#[derive(Clone, Debug)]
+pub enum ContentKind {
+	Text,
+	Photo,
+	Sticker,
+	Video,
+	Document,
+	Location,
+}
+
+#[derive(Clone, Debug)]
+pub struct ReplyQuote {
+	pub sender: String,
+	pub text: String,
+}
+
+// a matrix message on its way to another network
+#[derive(Default, Clone)]
+pub struct OutgoingMessage {
+	pub message: Vec<u8>,
+	pub source: Option<MediaSource>,
+	pub mxc_uri: Option<OwnedMxcUri>,
+	// the same media already uploaded to the network
+	pub file_id: Option<String>,
+	pub kind: Option<ContentKind>,
+	pub caption: Option<String>,
+	pub location: Option<(f64, f64)>,
+	pub has_spoiler: bool,
+	// byte ranges of the text, everything is hidden if empty and has_spoiler is set
+	pub spoilers: Vec<Range<usize>>,
+	pub is_preview_disabled: bool,
+	pub reply_to: Option<OwnedEventId>,
+	pub reply_quote: Option<ReplyQuote>,
+}
+
+impl OutgoingMessage {
+	#[must_use]
+	pub fn text(&self) -> String {
+		match self.kind {
+			Some(ContentKind::Text) => String::from_utf8_lossy(&self.message).to_string(),
+			_ => String::new(),
+		}
+	}
+}
+
+pub struct SendOptions<'a, M> {
+	// the reply target when it's bridged, otherwise the message carries a reply quote
+	pub reply_to: Option<M>,
+	pub sender: Sender,
+	pub templates: &'a SenderTemplates,
+	pub link_previews: LinkPreviewPolicy,
+}
+
+pub struct RemoteFile {
+	pub id: String,
+	pub unique_id: String,
+}
+
+pub struct SentMessage<M> {
+	pub id: M,
+	// uploaded media that can be sent again by id
+	pub file: Option<RemoteFile>,
+}
+
+#[derive(Clone, Debug)]
+pub struct IncomingMedia {
+	pub file_id: String,
+	// stable across messages, used to upload the same media to matrix once
+	pub unique_id: String,
+	pub name: String,
+	pub mime: String,
+	pub kind: ContentKind,
+}
+
+#[derive(Clone, Debug)]
+pub struct IncomingMessage<C, M> {
+	pub chat_id: C,
+	pub id: M,
+	pub sender: Sender,
+	pub sender_id: String,
+	pub text: String,
+	pub html: Option<String>,
+	pub reply_to: Option<M>,
+	pub media: Option<IncomingMedia>,
+}
+
+#[derive(Clone, Debug)]
+pub enum RemoteEvent<C, M> {
+	Message(IncomingMessage<C, M>),
+	Edit {
+		chat_id: C,
+		id: M,
+		// tells edits of one message apart, when the network reports when it was edited
+		edit_id: Option<String>,
+		sender: Sender,
+		text: String,
+	},
+	Delete {
+		chat_id: C,
+		id: M,
+	},
+	Reaction {
+		chat_id: C,
+		id: M,
+		emoji: String,
+	},
+}
+
+impl<C, M> RemoteEvent<C, M> {
+	#[must_use]
+	pub fn chat_id(&self) -> &C {
+		match self {
+			Self::Message(message) => &message.chat_id,
+			Self::Edit {
+				chat_id,
+				..
+			}
+			| Self::Delete {
+				chat_id,
+				..
+			}
+			| Self::Reaction {
+				chat_id,
+				..
+			} => chat_id,
+		}
+	}
+}
+
+// a chat network bridged to matrix, the matrix side, mapping store and queues are shared
+//
+// telegram sends everything from matrix through this trait, but only its edits come back
+// as RemoteEvents. new telegram messages are converted by convert::tg_to_mx_content
+// instead: IncomingMessage has no room for their spoilers and content warnings, preview
+// blocks, forward origins, polls, venues, contacts or animated stickers, and their
+// transaction ids predate the shared ones, so redeliveries across an upgrade still dedupe
+pub trait RemoteNetwork: Send + Sync {
+	type ChatId: Clone + Debug + Display + Send + Sync + 'static;
+	// identifies a message across chats, stored next to the matrix event id
+	type MessageId: Serialize + DeserializeOwned + Clone + Debug + PartialEq + Send + Sync + 'static;
+
+	// also names the network's files in the store
+	const NAME: &'static str;
+	// matrix media is sent as a link instead of being uploaded
+	const LINKS_MEDIA: bool = false;
+
+	fn send(
+		&self,
+		chat_id: &Self::ChatId,
+		message: &OutgoingMessage,
+		options: SendOptions<'_, Self::MessageId>,
+	) -> impl Future<Output = anyhow::Result<SentMessage<Self::MessageId>>> + Send;
+
+	fn edit(
+		&self,
+		id: &Self::MessageId,
+		text: &str,
+		sender: &Sender,
+		templates: &SenderTemplates,
+	) -> impl Future<Output = anyhow::Result<()>> + Send;
+
+	fn delete(&self, id: &Self::MessageId) -> impl Future<Output = anyhow::Result<()>> + Send;
+
+	fn react(
+		&self,
+		id: &Self::MessageId,
+		emoji: &str,
+	) -> impl Future<Output = anyhow::Result<()>> + Send;
+
+	fn fetch_media(&self, file_id: &str) -> impl Future<Output = anyhow::Result<Vec<u8>>> + Send;
+
+	// can only be taken once
+	fn incoming(
+		&self,
+	) -> impl Stream<Item = RemoteEvent<Self::ChatId, Self::MessageId>> + Send + 'static;
+}
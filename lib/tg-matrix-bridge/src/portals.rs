@@ -0,0 +1,134 @@
+use std::sync::Arc;
+
+use anyhow::Context;
+use matrix_sdk::ruma::api::client::room::create_room;
+use matrix_sdk::ruma::api::client::room::create_room::v3::RoomPreset;
+use matrix_sdk::ruma::events::space::child::SpaceChildEventContent;
+use matrix_sdk::ruma::events::space::parent::SpaceParentEventContent;
+use matrix_sdk::ruma::serde::Raw;
+use matrix_sdk::ruma::OwnedRoomId;
+use matrix_sdk::ruma::RoomId;
+use matrix_sdk::ruma::UserId;
+use matrix_sdk::Room;
+use serde_json::json;
+use teloxide::prelude::Requester;
+use teloxide::types::Chat;
+use teloxide::types::ChatId;
+use teloxide::types::ChatMemberUpdated;
+
+use crate::bridge_structs::Bridge;
+use crate::bridge_structs::BridgeContext;
+use crate::bridge_structs::PortalConfig;
+use crate::bridge_utils::download_tg_file;
+
+// creates and bridges a matrix room when the bot is added to a telegram group
+pub async fn tg_member_update(
+	update: ChatMemberUpdated,
+	ctx: Arc<BridgeContext>,
+) -> anyhow::Result<()> {
+	let Some(portals) = &ctx.config.portals else {
+		return Ok(());
+	};
+	let chat = &update.chat;
+	let added = !update.old_chat_member.is_present() && update.new_chat_member.is_present();
+	if !added || !(chat.is_group() || chat.is_supergroup()) || ctx.bridge_by_tg(chat.id).is_some() {
+		return Ok(());
+	}
+	if !portals.allowed_tg_users.contains(&update.from.id.0) {
+		log::info!("{} isn't allowed to create a portal for {}", update.from.id, chat.id);
+		return Ok(());
+	}
+	let room_id = create_portal(&ctx, portals, chat).await?;
+	ctx.bot.send_message(chat.id, format!("bridged to matrix room {room_id}")).await?;
+	Ok(())
+}
+
+// moves the bridge of a group that was upgraded to a supergroup
+pub fn migrate_bridge(
+	ctx: &BridgeContext,
+	old_tg_id: ChatId,
+	new_tg_id: ChatId,
+) -> anyhow::Result<()> {
+	let Some(bridge) = ctx.bridge_by_tg(old_tg_id) else {
+		return Ok(());
+	};
+	let bridge = Bridge {
+		tg_id: new_tg_id.0,
+		..(*bridge).clone()
+	};
+	if ctx.store.get_portals().iter().any(|portal| portal.tg_id == old_tg_id.0) {
+		ctx.store.move_portal(old_tg_id.0, bridge.clone())?;
+	} else {
+		log::warn!("{old_tg_id} moved to {new_tg_id}, update tg_id in the config");
+	}
+	ctx.move_bridge(old_tg_id, bridge);
+	Ok(())
+}
+
+async fn create_portal(
+	ctx: &BridgeContext,
+	portals: &PortalConfig,
+	chat: &Chat,
+) -> anyhow::Result<OwnedRoomId> {
+	let bot_user_id = ctx.client.user_id().context("matrix client isn't logged in")?;
+	let admins = portals
+		.admins
+		.iter()
+		.map(|admin| UserId::parse(admin.as_str()))
+		.collect::<Result<Vec<_>, _>>()?;
+	let mut users = serde_json::Map::new();
+	for user_id in admins.iter().map(AsRef::as_ref).chain([bot_user_id]) {
+		users.insert(user_id.to_string(), json!(100));
+	}
+	let mut request = create_room::v3::Request::new();
+	request.name = chat.title().map(str::to_string);
+	request.invite = admins;
+	request.preset = Some(RoomPreset::PrivateChat);
+	request.power_level_content_override = Some(Raw::new(&json!({ "users": users }))?.cast());
+	let room = ctx.client.create_room(request).await?;
+
+	let bridge = Bridge {
+		mx_id: room.room_id().to_string(),
+		tg_id: chat.id.0,
+		..Bridge::default()
+	};
+	ctx.store.add_portal(bridge.clone())?;
+	ctx.add_bridge(bridge);
+
+	if let Some(space_id) = &portals.space_id {
+		if let Err(e) = add_to_space(ctx, &room, space_id).await {
+			log::error!("{e}");
+		}
+	}
+	if let Err(e) = copy_photo(ctx, &room, chat).await {
+		log::error!("{e}");
+	}
+	Ok(room.room_id().to_owned())
+}
+
+async fn add_to_space(ctx: &BridgeContext, room: &Room, space_id: &str) -> anyhow::Result<()> {
+	let space_id = RoomId::parse(space_id)?;
+	let space = ctx.client.get_room(&space_id).context("bot isn't in the portal space")?;
+	let via = vec![ctx
+		.client
+		.user_id()
+		.context("matrix client isn't logged in")?
+		.server_name()
+		.to_owned()];
+	space
+		.send_state_event_for_key(room.room_id(), SpaceChildEventContent::new(via.clone()))
+		.await?;
+	room.send_state_event_for_key(&space_id, SpaceParentEventContent::new(via)).await?;
+	Ok(())
+}
+
+async fn copy_photo(ctx: &BridgeContext, room: &Room, chat: &Chat) -> anyhow::Result<()> {
+	// the chat from updates doesn't carry the photo
+	let full_chat = ctx.bot.get_chat(chat.id).await?;
+	let Some(photo) = full_chat.photo else {
+		return Ok(());
+	};
+	let data = download_tg_file(ctx.bot.inner(), &photo.big_file_id).await?;
+	room.upload_avatar(&mime::IMAGE_JPEG, data, None).await?;
+	Ok(())
+}
@@ -0,0 +1,85 @@
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::future::Future;
+use std::panic::AssertUnwindSafe;
+use std::pin::Pin;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use futures_util::FutureExt;
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::SendError;
+
+type Job = Pin<Box<dyn Future<Output = anyhow::Result<()>> + Send>>;
+
+// enqueueing waits beyond this, a stuck chat shouldn't grow without bound
+pub const QUEUE_CAPACITY: usize = 256;
+// workers of quiet chats exit and are spawned again with the next job
+const IDLE_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+#[derive(Default)]
+pub struct ChatQueues {
+	// keyed by the remote chat, messages of one chat are bridged in order
+	queues: Mutex<HashMap<String, mpsc::Sender<Job>>>,
+}
+
+async fn run_job(chat_id: &str, job: Job) {
+	match AssertUnwindSafe(job).catch_unwind().await {
+		Ok(Ok(())) => (),
+		Ok(Err(e)) => log::error!("{chat_id}: {e}"),
+		Err(_) => log::error!("{chat_id}: job panicked"),
+	}
+}
+
+fn spawn_worker(chat_id: String) -> mpsc::Sender<Job> {
+	let (sender, mut receiver) = mpsc::channel::<Job>(QUEUE_CAPACITY);
+	tokio::spawn(async move {
+		loop {
+			match tokio::time::timeout(IDLE_TIMEOUT, receiver.recv()).await {
+				Ok(Some(job)) => run_job(&chat_id, job).await,
+				Ok(None) => break,
+				// refuse new jobs but still run the ones that raced the timeout
+				Err(_) => receiver.close(),
+			}
+			log::debug!("{chat_id}: queue depth {}", receiver.len());
+		}
+	});
+	sender
+}
+
+impl ChatQueues {
+	// waits while the chat's queue is full, so a stuck chat holds back its updates
+	#[allow(clippy::missing_panics_doc)]
+	pub async fn enqueue(
+		&self,
+		chat_id: impl Display,
+		job: impl Future<Output = anyhow::Result<()>> + Send + 'static,
+	) {
+		let chat_id = chat_id.to_string();
+		let mut job: Job = Box::pin(job);
+		loop {
+			let sender = {
+				let mut chat_queues = self.queues.lock().unwrap();
+				chat_queues.retain(|_, sender| !sender.is_closed());
+				chat_queues
+					.entry(chat_id.clone())
+					.or_insert_with(|| spawn_worker(chat_id.clone()))
+					.clone()
+			};
+			if sender.capacity() == 0 {
+				log::warn!("{chat_id}: queue is full, waiting");
+			}
+			match sender.send(job).await {
+				Ok(()) => {
+					let depth = sender.max_capacity() - sender.capacity();
+					if depth > 1 {
+						log::info!("{chat_id}: queue depth {depth}");
+					}
+					return;
+				}
+				// the worker went idle, the next loop spawns a new one
+				Err(SendError(returned)) => job = returned,
+			}
+		}
+	}
+}
@@ -0,0 +1,301 @@
+use matrix_sdk::media::MediaFormat;
+use matrix_sdk::media::MediaRequestParameters;
+use matrix_sdk::ruma::events::reaction::ReactionEventContent;
+use matrix_sdk::ruma::events::relation::Annotation;
+use matrix_sdk::ruma::events::relation::InReplyTo;
+use matrix_sdk::ruma::events::room::message::FileMessageEventContent;
+use matrix_sdk::ruma::events::room::message::ImageMessageEventContent;
+use matrix_sdk::ruma::events::room::message::MessageType;
+use matrix_sdk::ruma::events::room::message::OriginalRoomMessageEvent;
+use matrix_sdk::ruma::events::room::message::Relation;
+use matrix_sdk::ruma::events::room::message::ReplacementMetadata;
+use matrix_sdk::ruma::events::room::message::RoomMessageEventContent;
+use matrix_sdk::ruma::events::room::message::VideoMessageEventContent;
+use matrix_sdk::ruma::EventId;
+use matrix_sdk::ruma::OwnedMxcUri;
+use matrix_sdk::ruma::OwnedTransactionId;
+use matrix_sdk::ruma::UserId;
+use matrix_sdk::Client;
+use matrix_sdk::Room;
+
+use crate::bridge_structs::BmMxData;
+use crate::bridge_structs::LinkPreviewPolicy;
+use crate::bridge_utils::get_mx_sender;
+use crate::convert::mx_reply_quote;
+use crate::convert::text_content;
+use crate::db::Store;
+use crate::network::ContentKind;
+use crate::network::IncomingMedia;
+use crate::network::IncomingMessage;
+use crate::network::OutgoingMessage;
+use crate::network::RemoteEvent;
+use crate::network::RemoteNetwork;
+use crate::network::ReplyQuote;
+use crate::network::SendOptions;
+use crate::templates::render;
+use crate::templates::SenderTemplates;
+
+async fn get_reply_quote(room: &Room, event_id: &EventId) -> Option<ReplyQuote> {
+	let timeline_event = room.event(event_id, None).await.ok()?;
+	let event = timeline_event.kind.raw().deserialize_as::<OriginalRoomMessageEvent>().ok()?;
+	let sender = get_mx_sender(room, &event.sender).await;
+	Some(ReplyQuote {
+		sender: sender.name,
+		..mx_reply_quote(&event)
+	})
+}
+
+// downloads the matrix media unless the network already has it or only links it
+pub async fn fetch_mx_media<N: RemoteNetwork>(
+	client: &Client,
+	store: &Store,
+	message: &mut OutgoingMessage,
+) -> anyhow::Result<()> {
+	let (Some(source), Some(kind)) = (&message.source, &message.kind) else {
+		return Ok(());
+	};
+	if N::LINKS_MEDIA {
+		return Ok(());
+	}
+	if let Some(mxc_uri) = &message.mxc_uri {
+		message.file_id = store.get_cached_file_id(N::NAME, kind, mxc_uri);
+	}
+	if message.file_id.is_none() {
+		let request = MediaRequestParameters {
+			source: source.clone(),
+			format: MediaFormat::File,
+		};
+		message.message = client.media().get_media_content(&request, false).await?;
+	}
+	Ok(())
+}
+
+// where a matrix room is bridged to
+pub struct RemoteTarget<'a, N: RemoteNetwork> {
+	pub network: &'a N,
+	pub chat_id: N::ChatId,
+	pub templates: &'a SenderTemplates,
+	pub link_previews: LinkPreviewPolicy,
+}
+
+pub async fn mx_to_remote<N: RemoteNetwork>(
+	store: &Store,
+	target: &RemoteTarget<'_, N>,
+	mut message: OutgoingMessage,
+	from_mx_data: BmMxData<'_>,
+) -> anyhow::Result<()> {
+	let matrix_chat_id = from_mx_data.room.room_id().as_str();
+	let reply_to = match &message.reply_to {
+		Some(matrix_reply) => {
+			let reply_to = store.find_remote_id::<N>(matrix_chat_id, matrix_reply);
+			if reply_to.is_none() {
+				message.reply_quote = get_reply_quote(&from_mx_data.room, matrix_reply).await;
+			}
+			reply_to
+		}
+		None => None,
+	};
+	let options = SendOptions {
+		reply_to,
+		sender: get_mx_sender(&from_mx_data.room, &from_mx_data.mx_event.sender).await,
+		templates: target.templates,
+		link_previews: target.link_previews,
+	};
+	let sent = target.network.send(&target.chat_id, &message, options).await?;
+	if let (Some(mxc_uri), None, Some(file), Some(kind)) =
+		(&message.mxc_uri, &message.file_id, &sent.file, &message.kind)
+	{
+		store.cache_media(N::NAME, mxc_uri, Some(&file.unique_id), Some((kind, &file.id)))?;
+	}
+	store.update_remote_messages::<N>(
+		from_mx_data.mx_event.event_id.clone(),
+		sent.id,
+		matrix_chat_id,
+	)?;
+	Ok(())
+}
+
+pub async fn mx_edit_to_remote<N: RemoteNetwork>(
+	store: &Store,
+	target: &RemoteTarget<'_, N>,
+	room: &Room,
+	sender: &UserId,
+	edited: &EventId,
+	text: &str,
+) -> anyhow::Result<()> {
+	let Some(id) = store.find_remote_id::<N>(room.room_id().as_str(), edited) else {
+		return Ok(());
+	};
+	let sender = get_mx_sender(room, sender).await;
+	target.network.edit(&id, text, &sender, target.templates).await
+}
+
+pub async fn mx_redaction_to_remote<N: RemoteNetwork>(
+	store: &Store,
+	target: &RemoteTarget<'_, N>,
+	room: &Room,
+	redacted: &EventId,
+) -> anyhow::Result<()> {
+	let Some(id) = store.find_remote_id::<N>(room.room_id().as_str(), redacted) else {
+		return Ok(());
+	};
+	target.network.delete(&id).await
+}
+
+pub async fn mx_reaction_to_remote<N: RemoteNetwork>(
+	store: &Store,
+	target: &RemoteTarget<'_, N>,
+	room: &Room,
+	reacted: &EventId,
+	emoji: &str,
+) -> anyhow::Result<()> {
+	let Some(id) = store.find_remote_id::<N>(room.room_id().as_str(), reacted) else {
+		return Ok(());
+	};
+	target.network.react(&id, emoji).await
+}
+
+async fn upload_media<N: RemoteNetwork>(
+	network: &N,
+	client: &Client,
+	store: &Store,
+	media: &IncomingMedia,
+) -> anyhow::Result<OwnedMxcUri> {
+	if let Some(mxc_uri) = store.get_cached_mxc_uri(N::NAME, &media.unique_id) {
+		return Ok(mxc_uri);
+	}
+	let data = network.fetch_media(&media.file_id).await?;
+	let mime = media.mime.parse::<mime::Mime>().unwrap_or(mime::APPLICATION_OCTET_STREAM);
+	let mxc_uri = client.media().upload(&mime, data, None).await?.content_uri;
+	store.cache_media(
+		N::NAME,
+		&mxc_uri,
+		Some(&media.unique_id),
+		Some((&media.kind, &media.file_id)),
+	)?;
+	Ok(mxc_uri)
+}
+
+async fn remote_message_content<N: RemoteNetwork>(
+	network: &N,
+	client: &Client,
+	store: &Store,
+	templates: &SenderTemplates,
+	message: &IncomingMessage<N::ChatId, N::MessageId>,
+) -> anyhow::Result<RoomMessageEventContent> {
+	let Some(media) = &message.media else {
+		return Ok(text_content(&message.sender, templates, &message.text, message.html.clone()));
+	};
+	let mxc_uri = upload_media(network, client, store, media).await?;
+	let caption = render(templates.for_caption(&message.text), &message.sender, &message.text);
+	let msgtype = match media.kind {
+		ContentKind::Photo | ContentKind::Sticker => {
+			let mut content = ImageMessageEventContent::plain(caption, mxc_uri);
+			content.filename = Some(media.name.clone());
+			MessageType::Image(content)
+		}
+		ContentKind::Video => {
+			let mut content = VideoMessageEventContent::plain(caption, mxc_uri);
+			content.filename = Some(media.name.clone());
+			MessageType::Video(content)
+		}
+		_ => {
+			let mut content = FileMessageEventContent::plain(caption, mxc_uri);
+			content.filename = Some(media.name.clone());
+			MessageType::File(content)
+		}
+	};
+	Ok(RoomMessageEventContent::new(msgtype))
+}
+
+// stable across redeliveries of the same remote message or edit
+fn remote_transaction_id<N: RemoteNetwork>(
+	id: &N::MessageId,
+	edit_id: Option<&str>,
+) -> anyhow::Result<OwnedTransactionId> {
+	let mut id = serde_json::to_string(id)?;
+	if let Some(edit_id) = edit_id {
+		id.push_str(&format!("_edit_{edit_id}"));
+	}
+	let id = id.replace(|c: char| !c.is_ascii_alphanumeric() && c != '-', "_");
+	Ok(format!("{}_{id}", N::NAME).into())
+}
+
+// bridges an event of any network into its matrix room
+pub async fn remote_to_mx<N: RemoteNetwork>(
+	network: &N,
+	client: &Client,
+	store: &Store,
+	room: &Room,
+	templates: &SenderTemplates,
+	event: RemoteEvent<N::ChatId, N::MessageId>,
+) -> anyhow::Result<()> {
+	let mx_chat = room.room_id().as_str();
+	match event {
+		RemoteEvent::Message(message) => {
+			if store.is_opted_out(&message.sender_id) {
+				return Ok(());
+			}
+			if store.find_mx_event_for::<N>(mx_chat, &message.id).is_some() {
+				log::debug!("{}:{:?} already bridged", N::NAME, message.id);
+				return Ok(());
+			}
+			let mut content =
+				remote_message_content(network, client, store, templates, &message).await?;
+			let reply_to =
+				message.reply_to.and_then(|id| store.find_mx_event_for::<N>(mx_chat, &id));
+			if let Some(event_id) = reply_to {
+				content.relates_to = Some(Relation::Reply {
+					in_reply_to: InReplyTo::new(event_id),
+				});
+			}
+			let txn_id = remote_transaction_id::<N>(&message.id, None)?;
+			let sent_mx_msg =
+				utils::matrix::send_with_transaction_id(room.clone().into(), content, txn_id)
+					.await?;
+			store.update_remote_messages::<N>(sent_mx_msg.event_id, message.id, mx_chat)?;
+		}
+		RemoteEvent::Edit {
+			id,
+			edit_id,
+			sender,
+			text,
+			..
+		} => {
+			let Some(event_id) = store.find_mx_event_for::<N>(mx_chat, &id) else {
+				log::debug!("{}:{id:?} edited message isn't bridged", N::NAME);
+				return Ok(());
+			};
+			let content = text_content(&sender, templates, &text, None)
+				.make_replacement(ReplacementMetadata::new(event_id, None), None);
+			match edit_id {
+				Some(edit_id) => {
+					let txn_id = remote_transaction_id::<N>(&id, Some(&edit_id))?;
+					utils::matrix::send_with_transaction_id(room.clone().into(), content, txn_id)
+						.await?;
+				}
+				None => {
+					room.send(content).await?;
+				}
+			}
+		}
+		RemoteEvent::Delete {
+			id,
+			..
+		} => {
+			if let Some(event_id) = store.find_mx_event_for::<N>(mx_chat, &id) {
+				room.redact(&event_id, None, None).await?;
+			}
+		}
+		RemoteEvent::Reaction {
+			id,
+			emoji,
+			..
+		} => {
+			if let Some(event_id) = store.find_mx_event_for::<N>(mx_chat, &id) {
+				room.send(ReactionEventContent::new(Annotation::new(event_id, emoji))).await?;
+			}
+		}
+	}
+	Ok(())
+}
@@ -0,0 +1,143 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Context;
+use matrix_sdk::ruma::RoomId;
+use matrix_sdk::Room;
+use serde_json::json;
+use serde_json::Value;
+use teloxide::prelude::Requester;
+use teloxide::types::Sticker;
+
+use crate::bridge_structs::BridgeContext;
+use crate::bridge_utils::get_sticker_image;
+use crate::db::PackSticker;
+use crate::db::StickerPack;
+
+pub const ROOM_EMOTES_EVENT_TYPE: &str = "im.ponies.room_emotes";
+const SYNC_INTERVAL: Duration = Duration::from_secs(6 * 60 * 60);
+
+fn room_emotes_content(title: &str, stickers: &[PackSticker]) -> Value {
+	let images = stickers
+		.iter()
+		.map(|sticker| {
+			let image = json!({
+				"url": sticker.mxc_uri,
+				"body": sticker.emoji.as_deref().unwrap_or(&sticker.shortcode),
+				"info": {
+					"mimetype": mime::IMAGE_PNG.to_string(),
+					"w": sticker.width,
+					"h": sticker.height,
+					"size": sticker.size,
+				},
+				"usage": ["sticker"],
+			});
+			(sticker.shortcode.clone(), image)
+		})
+		.collect::<serde_json::Map<String, Value>>();
+	json!({
+		"pack": {
+			"display_name": title,
+			"usage": ["sticker"],
+		},
+		"images": images,
+	})
+}
+
+async fn upload_sticker(
+	ctx: &BridgeContext,
+	room: &Room,
+	sticker: &Sticker,
+	shortcode: String,
+	emoji: Option<String>,
+) -> anyhow::Result<PackSticker> {
+	let (png, info) = get_sticker_image(ctx.bot.inner(), sticker).await?;
+	let size = png.len() as u64;
+	let mxc_uri = room.client().media().upload(&mime::IMAGE_PNG, png, None).await?.content_uri;
+	Ok(PackSticker {
+		file_unique_id: sticker.file.unique_id.clone(),
+		shortcode,
+		emoji,
+		mxc_uri,
+		width: info.width.map_or(0, |w| u32::try_from(w).unwrap_or_default()),
+		height: info.height.map_or(0, |h| u32::try_from(h).unwrap_or_default()),
+		size,
+	})
+}
+
+pub async fn sync_sticker_pack(
+	ctx: &BridgeContext,
+	room: &Room,
+	set_name: &str,
+) -> anyhow::Result<usize> {
+	let sticker_set = ctx.bot.get_sticker_set(set_name).await?;
+	let old_stickers = ctx
+		.store
+		.get_sticker_packs()
+		.into_iter()
+		.find(|p| p.set_name == sticker_set.name && p.matrix_chat_id == room.room_id().as_str())
+		.map(|p| p.stickers)
+		.unwrap_or_default();
+	let mut old_stickers = old_stickers
+		.into_iter()
+		.map(|sticker| (sticker.file_unique_id.clone(), sticker))
+		.collect::<HashMap<String, PackSticker>>();
+
+	let mut stickers: Vec<PackSticker> = vec![];
+	for sticker in &sticker_set.stickers {
+		let emoji = sticker.emoji.clone();
+		let mut shortcode = emoji.clone().unwrap_or_else(|| "sticker".to_string());
+		if stickers.iter().any(|s| s.shortcode == shortcode) {
+			shortcode = format!("{shortcode}_{}", stickers.len());
+		}
+		if let Some(old_sticker) = old_stickers.remove(&sticker.file.unique_id) {
+			stickers.push(PackSticker {
+				shortcode,
+				emoji,
+				..old_sticker
+			});
+			continue;
+		}
+		// one broken sticker shouldn't keep the rest of the pack out
+		match upload_sticker(ctx, room, sticker, shortcode, emoji).await {
+			Ok(pack_sticker) => stickers.push(pack_sticker),
+			Err(e) => log::warn!("{set_name}: skipping {}: {e}", sticker.file.unique_id),
+		}
+	}
+
+	let content = room_emotes_content(&sticker_set.title, &stickers);
+	room.send_state_event_raw(ROOM_EMOTES_EVENT_TYPE, &sticker_set.name, content).await?;
+	let count = stickers.len();
+	ctx.store.update_sticker_pack(StickerPack {
+		set_name: sticker_set.name,
+		matrix_chat_id: room.room_id().to_string(),
+		stickers,
+	})?;
+	Ok(count)
+}
+
+pub async fn sync_sticker_packs_loop(ctx: Arc<BridgeContext>) {
+	loop {
+		tokio::time::sleep(SYNC_INTERVAL).await;
+		let sticker_packs = ctx
+			.store
+			.get_sticker_packs()
+			.into_iter()
+			.map(|p| (p.set_name, p.matrix_chat_id))
+			.collect::<Vec<(String, String)>>();
+		for (set_name, matrix_chat_id) in sticker_packs {
+			let room = RoomId::parse(&matrix_chat_id)
+				.ok()
+				.and_then(|room_id| ctx.client.get_room(&room_id))
+				.context("can't get matrix room");
+			let res = match room {
+				Ok(room) => sync_sticker_pack(&ctx, &room, &set_name).await,
+				Err(e) => Err(e),
+			};
+			if let Err(e) = res {
+				log::error!("{set_name}: {e}");
+			}
+		}
+	}
+}
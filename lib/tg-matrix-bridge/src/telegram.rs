@@ -0,0 +1,323 @@
+use std::sync::Mutex;
+
+use anyhow::bail;
+use futures_util::Stream;
+use image::ImageFormat;
+use teloxide::adaptors::Throttle;
+use teloxide::payloads::EditMessageTextSetters;
+use teloxide::payloads::SendDocumentSetters;
+use teloxide::payloads::SendLocationSetters;
+use teloxide::payloads::SendMessageSetters;
+use teloxide::payloads::SendPhotoSetters;
+use teloxide::payloads::SendStickerSetters;
+use teloxide::payloads::SendVideoSetters;
+use teloxide::payloads::SetMessageReactionSetters;
+use teloxide::prelude::Requester;
+use teloxide::types::ChatId;
+use teloxide::types::InputFile;
+use teloxide::types::LinkPreviewOptions;
+use teloxide::types::Message;
+use teloxide::types::MessageEntity;
+use teloxide::types::MessageEntityKind;
+use teloxide::types::MessageId;
+use teloxide::types::ReactionType;
+use teloxide::types::ReplyParameters;
+use teloxide::ApiError;
+use teloxide::Bot;
+use teloxide::RequestError;
+use tokio::sync::mpsc;
+
+use crate::bridge_utils::convert_image;
+use crate::bridge_utils::download_tg_file;
+use crate::bridge_utils::get_sent_file;
+use crate::convert::tg_link_preview;
+use crate::network::ContentKind;
+use crate::network::OutgoingMessage;
+use crate::network::RemoteEvent;
+use crate::network::RemoteFile;
+use crate::network::RemoteNetwork;
+use crate::network::ReplyQuote;
+use crate::network::SendOptions;
+use crate::network::SentMessage;
+use crate::templates::render_tg;
+use crate::templates::Sender;
+use crate::templates::SenderTemplates;
+
+pub type TelegramEvent = RemoteEvent<ChatId, (ChatId, MessageId)>;
+
+pub struct TelegramNetwork {
+	bot: Throttle<Bot>,
+	events: mpsc::UnboundedSender<TelegramEvent>,
+	receiver: Mutex<Option<mpsc::UnboundedReceiver<TelegramEvent>>>,
+}
+
+impl TelegramNetwork {
+	#[must_use]
+	pub fn new(bot: Throttle<Bot>) -> Self {
+		let (events, receiver) = mpsc::unbounded_channel();
+		Self {
+			bot,
+			events,
+			receiver: Mutex::new(Some(receiver)),
+		}
+	}
+
+	// updates that don't need telegram specific handling go through the shared core
+	pub fn push(&self, event: TelegramEvent) {
+		if self.events.send(event).is_err() {
+			log::error!("telegram events aren't consumed");
+		}
+	}
+}
+
+impl RemoteNetwork for TelegramNetwork {
+	type ChatId = ChatId;
+	type MessageId = (ChatId, MessageId);
+
+	const NAME: &'static str = "telegram";
+
+	async fn send(
+		&self,
+		chat_id: &ChatId,
+		message: &OutgoingMessage,
+		options: SendOptions<'_, Self::MessageId>,
+	) -> anyhow::Result<SentMessage<Self::MessageId>> {
+		// telegram only takes webp stickers
+		let converted;
+		let message = match (&message.kind, &message.file_id) {
+			(Some(ContentKind::Sticker), None) => {
+				converted = OutgoingMessage {
+					message: convert_image(&message.message, ImageFormat::WebP, Some(512))?.0,
+					..message.clone()
+				};
+				&converted
+			}
+			_ => message,
+		};
+		let null_id = MessageId(-1);
+		let reply_to_id = options.reply_to.map_or(null_id, |(_, message_id)| message_id);
+		let reply_params = ReplyParameters::new(reply_to_id).allow_sending_without_reply();
+		let link_preview =
+			tg_link_preview(options.link_previews, message.is_preview_disabled, &message.text());
+		let res = bot_send_request(
+			&self.bot,
+			message,
+			*chat_id,
+			&reply_params,
+			&link_preview,
+			&options.sender,
+			options.templates,
+		)
+		.await;
+		let t_msg = match res {
+			Ok(msg) => msg,
+			Err(e)
+				if matches!(
+					e.downcast_ref(),
+					Some(RequestError::Api(ApiError::RequestEntityTooLarge))
+				) =>
+			{
+				let message = OutgoingMessage {
+					message: "telegram sucks and cannot display this message"
+						.to_string()
+						.into_bytes(),
+					kind: Some(ContentKind::Text),
+					file_id: None,
+					spoilers: vec![],
+					..message.clone()
+				};
+				bot_send_request(
+					&self.bot,
+					&message,
+					*chat_id,
+					&reply_params,
+					&link_preview,
+					&options.sender,
+					options.templates,
+				)
+				.await?
+			}
+			Err(e) => return Err(e),
+		};
+		let file = get_sent_file(&t_msg).map(|file| RemoteFile {
+			id: file.id.clone(),
+			unique_id: file.unique_id.clone(),
+		});
+		Ok(SentMessage {
+			id: (t_msg.chat.id, t_msg.id),
+			file,
+		})
+	}
+
+	async fn edit(
+		&self,
+		id: &Self::MessageId,
+		text: &str,
+		sender: &Sender,
+		templates: &SenderTemplates,
+	) -> anyhow::Result<()> {
+		let (text, entities) =
+			render_tg(&templates.text, sender, text, &[], templates.bold_name, 0);
+		self.bot.edit_message_text(id.0, id.1, text).entities(entities).await?;
+		Ok(())
+	}
+
+	async fn delete(&self, id: &Self::MessageId) -> anyhow::Result<()> {
+		self.bot.delete_message(id.0, id.1).await?;
+		Ok(())
+	}
+
+	async fn react(&self, id: &Self::MessageId, emoji: &str) -> anyhow::Result<()> {
+		// telegram's reaction emoji come without variation selectors
+		let emoji = emoji.replace('\u{fe0f}', "");
+		self.bot
+			.set_message_reaction(id.0, id.1)
+			.reaction([ReactionType::Emoji {
+				emoji,
+			}])
+			.await?;
+		Ok(())
+	}
+
+	async fn fetch_media(&self, file_id: &str) -> anyhow::Result<Vec<u8>> {
+		download_tg_file(self.bot.inner(), file_id).await
+	}
+
+	fn incoming(&self) -> impl Stream<Item = TelegramEvent> + Send + 'static {
+		let receiver = self.receiver.lock().unwrap().take();
+		futures_util::stream::unfold(receiver, |receiver| async move {
+			let mut receiver = receiver?;
+			let event = receiver.recv().await?;
+			Some((event, Some(receiver)))
+		})
+	}
+}
+
+fn reply_quote_prefix(reply_quote: Option<&ReplyQuote>) -> (String, Vec<MessageEntity>) {
+	let Some(quote) = reply_quote else {
+		return (String::new(), vec![]);
+	};
+	let prefix = format!("{}: {}", quote.sender, quote.text);
+	let length = prefix.encode_utf16().count();
+	(format!("{prefix}\n"), vec![MessageEntity::new(MessageEntityKind::Blockquote, 0, length)])
+}
+
+fn spoiler_entities(text: &str, to_tg_data: &OutgoingMessage) -> Vec<MessageEntity> {
+	let utf16_len = |s: &str| s.encode_utf16().count();
+	if to_tg_data.has_spoiler && to_tg_data.spoilers.is_empty() {
+		return vec![MessageEntity::new(MessageEntityKind::Spoiler, 0, utf16_len(text))];
+	}
+	to_tg_data
+		.spoilers
+		.iter()
+		.filter_map(|range| {
+			let offset = utf16_len(text.get(..range.start)?);
+			let length = utf16_len(text.get(range.clone())?);
+			Some(MessageEntity::new(MessageEntityKind::Spoiler, offset, length))
+		})
+		.collect()
+}
+
+async fn bot_send_request(
+	bot: &Throttle<Bot>,
+	to_tg_data: &OutgoingMessage,
+	chat_id: ChatId,
+	reply_params: &ReplyParameters,
+	link_preview: &LinkPreviewOptions,
+	sender: &Sender,
+	templates: &SenderTemplates,
+) -> anyhow::Result<Message> {
+	let (quote, entities) = reply_quote_prefix(to_tg_data.reply_quote.as_ref());
+	let quote_len = quote.encode_utf16().count();
+	let caption = to_tg_data.caption.clone().unwrap_or_default();
+	let template = templates.for_caption(&caption);
+	let (caption, mut caption_entities) =
+		render_tg(template, sender, &caption, &[], templates.bold_name, quote_len);
+	let caption = format!("{quote}{caption}");
+	caption_entities.splice(0..0, entities.clone());
+	let input_file = match &to_tg_data.file_id {
+		Some(file_id) => InputFile::file_id(file_id.clone()),
+		None => InputFile::memory(to_tg_data.message.clone()),
+	};
+	let t_msg = loop {
+		let res = match to_tg_data.kind {
+			Some(ContentKind::Text) => {
+				let text = String::from_utf8_lossy(&to_tg_data.message);
+				let spoilers = spoiler_entities(&text, to_tg_data);
+				let (text, mut text_entities) = render_tg(
+					&templates.text,
+					sender,
+					&text,
+					&spoilers,
+					templates.bold_name,
+					quote_len,
+				);
+				text_entities.splice(0..0, entities.clone());
+				bot.send_message(chat_id, format!("{quote}{text}"))
+					.entities(text_entities)
+					.reply_parameters(reply_params.clone())
+					.link_preview_options(link_preview.clone())
+					.await
+			}
+			Some(ContentKind::Photo) => {
+				let input_file = input_file.clone();
+				bot.send_photo(chat_id, input_file)
+					.caption(&caption)
+					.caption_entities(caption_entities.clone())
+					.has_spoiler(to_tg_data.has_spoiler)
+					.reply_parameters(reply_params.clone())
+					.await
+			}
+			Some(ContentKind::Sticker) => {
+				let input_file = input_file.clone();
+				bot.send_sticker(chat_id, input_file).reply_parameters(reply_params.clone()).await
+			}
+			Some(ContentKind::Video) => {
+				let input_file = input_file.clone();
+				bot.send_video(chat_id, input_file)
+					.caption(&caption)
+					.caption_entities(caption_entities.clone())
+					.has_spoiler(to_tg_data.has_spoiler)
+					.reply_parameters(reply_params.clone())
+					.await
+			}
+			Some(ContentKind::Document) => {
+				let input_file = input_file.clone();
+				bot.send_document(chat_id, input_file)
+					.caption(&caption)
+					.caption_entities(caption_entities.clone())
+					.reply_parameters(reply_params.clone())
+					.await
+			}
+			Some(ContentKind::Location) => {
+				let Some((latitude, longitude)) = to_tg_data.location else {
+					bail!("location message without coordinates");
+				};
+				bot.send_location(chat_id, latitude, longitude)
+					.reply_parameters(reply_params.clone())
+					.await
+			}
+			None => bail!("message has no content kind"),
+		};
+		match res {
+			Err(RequestError::Network(e)) if e.is_timeout() => {
+				continue;
+			}
+			Err(RequestError::RetryAfter(seconds)) => {
+				tokio::time::sleep(seconds.duration()).await;
+				continue;
+			}
+			x => {
+				break x?;
+			}
+		}
+	};
+	// locations and stickers can't have a caption, attribute them with a reply instead
+	if let Some(ContentKind::Location | ContentKind::Sticker) = to_tg_data.kind {
+		bot.send_message(chat_id, caption)
+			.entities(caption_entities)
+			.reply_parameters(ReplyParameters::new(t_msg.id))
+			.await?;
+	}
+	Ok(t_msg)
+}
@@ -0,0 +1,136 @@
+use serde::Deserialize;
+use serde::Serialize;
+use teloxide::types::MessageEntity;
+
+use crate::bridge_utils::escape_html;
+
+// placeholders: {name}, {username} and {text}
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct SenderTemplates {
+	pub text: String,
+	pub caption: String,
+	pub media: String,
+	pub bold_name: bool,
+}
+
+impl Default for SenderTemplates {
+	fn default() -> Self {
+		Self {
+			text: "{name}: {text}".to_string(),
+			caption: "(from {name})\n{text}".to_string(),
+			media: "(from {name})".to_string(),
+			bold_name: false,
+		}
+	}
+}
+
+impl SenderTemplates {
+	#[must_use]
+	pub fn for_caption(&self, caption: &str) -> &str {
+		if caption.is_empty() {
+			&self.media
+		} else {
+			&self.caption
+		}
+	}
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Sender {
+	pub name: String,
+	pub username: String,
+}
+
+enum Part<'a> {
+	Literal(&'a str),
+	Name,
+	Username,
+	Text,
+}
+
+// single pass, so placeholders inside the substituted values are left alone
+fn parse(template: &str) -> Vec<Part<'_>> {
+	let mut parts = vec![];
+	let mut rest = template;
+	while let Some(start) = rest.find('{') {
+		let Some(len) = rest[start..].find('}') else {
+			break;
+		};
+		let part = match &rest[start + 1..start + len] {
+			"name" => Part::Name,
+			"username" => Part::Username,
+			"text" => Part::Text,
+			_ => {
+				parts.push(Part::Literal(&rest[..=start]));
+				rest = &rest[start + 1..];
+				continue;
+			}
+		};
+		parts.push(Part::Literal(&rest[..start]));
+		parts.push(part);
+		rest = &rest[start + len + 1..];
+	}
+	parts.push(Part::Literal(rest));
+	parts
+}
+
+#[must_use]
+pub fn render(template: &str, sender: &Sender, text: &str) -> String {
+	render_tg(template, sender, text, &[], false, 0).0
+}
+
+// offset is where the rendered text starts in the message, in utf-16 code units
+// text_entities are relative to text and moved to where it ends up
+#[must_use]
+pub fn render_tg(
+	template: &str,
+	sender: &Sender,
+	text: &str,
+	text_entities: &[MessageEntity],
+	bold_name: bool,
+	offset: usize,
+) -> (String, Vec<MessageEntity>) {
+	let mut rendered = String::new();
+	let mut entities = vec![];
+	for part in parse(template) {
+		let (value, is_name) = match part {
+			Part::Literal(literal) => (literal, false),
+			Part::Name => (sender.name.as_str(), true),
+			Part::Username => (sender.username.as_str(), true),
+			Part::Text => (text, false),
+		};
+		let start = offset + rendered.encode_utf16().count();
+		if is_name && bold_name && !value.is_empty() {
+			entities.push(MessageEntity::bold(start, value.encode_utf16().count()));
+		}
+		if let Part::Text = part {
+			entities.extend(text_entities.iter().map(|entity| MessageEntity {
+				offset: start + entity.offset,
+				..entity.clone()
+			}));
+		}
+		rendered.push_str(value);
+	}
+	(rendered, entities)
+}
+
+#[must_use]
+pub fn render_html(template: &str, sender: &Sender, html_text: &str, bold_name: bool) -> String {
+	let name = |name: &str| {
+		if bold_name {
+			format!("<b>{}</b>", escape_html(name))
+		} else {
+			escape_html(name)
+		}
+	};
+	parse(template)
+		.into_iter()
+		.map(|part| match part {
+			Part::Literal(literal) => escape_html(literal).replace('\n', "<br>"),
+			Part::Name => name(&sender.name),
+			Part::Username => name(&sender.username),
+			Part::Text => html_text.to_string(),
+		})
+		.collect()
+}
@@ -1,42 +1,71 @@
 use std::sync::Arc;
+use std::time::Duration;
 
 use anyhow::bail;
 use anyhow::Context;
-use matrix_sdk::ruma::events::room::message::AddMentions;
-use matrix_sdk::ruma::events::room::message::FileMessageEventContent;
-use matrix_sdk::ruma::events::room::message::ForwardThread;
-use matrix_sdk::ruma::events::room::message::ImageMessageEventContent;
-use matrix_sdk::ruma::events::room::message::MessageType;
+use futures_util::StreamExt;
+use matrix_sdk::ruma::events::poll::unstable_end::UnstablePollEndEventContent;
+use matrix_sdk::ruma::events::relation::InReplyTo;
 use matrix_sdk::ruma::events::room::message::OriginalRoomMessageEvent;
-use matrix_sdk::ruma::events::room::message::VideoMessageEventContent;
-use matrix_sdk::ruma::events::room::MediaSource;
+use matrix_sdk::ruma::events::room::message::Relation;
+use matrix_sdk::ruma::events::room::message::RoomMessageEventContent;
+use matrix_sdk::ruma::events::room::ImageInfo;
 use matrix_sdk::ruma::events::AnyMessageLikeEvent;
 use matrix_sdk::ruma::events::AnyTimelineEvent;
-use teloxide::adaptors::Throttle;
+use matrix_sdk::ruma::UInt;
 use teloxide::prelude::Requester;
+use teloxide::types::ChatId;
 use teloxide::types::MediaKind;
 use teloxide::types::Message;
 use teloxide::types::MessageKind;
-use teloxide::Bot;
+use teloxide::types::Poll;
+use teloxide::types::PollAnswer;
+use teloxide::types::Sticker;
+use teloxide::types::Voter;
+use tokio::task::JoinSet;
 
 use crate::bridge_structs::Bridge;
-use crate::bridge_utils::get_bms;
-use crate::bridge_utils::get_user_name;
-use crate::bridge_utils::update_bridged_messages;
-use matrix_sdk::ruma::events::room::message::RoomMessageEventContent;
-use matrix_sdk::ruma::OwnedEventId;
+use crate::bridge_structs::BridgeContext;
+use crate::bridge_structs::CONTENT_WARNING_KEY;
+use crate::bridge_structs::CONTENT_WARNING_SPOILER;
+use crate::bridge_utils::download_tg_file;
+use crate::bridge_utils::get_sticker_image;
+use crate::bridge_utils::get_tg_sender;
+use crate::bridge_utils::make_room_read_only;
+use crate::bridge_utils::poll_results_text;
+use crate::bridge_utils::poll_vote_text;
+use crate::bridge_utils::tg_sender_id;
+use crate::bridge_utils::tg_transaction_id;
+use crate::commands::run_tg_commands;
+use crate::convert::add_preview_block;
+use crate::convert::get_tg_upload;
+use crate::convert::tg_has_spoiler;
+use crate::convert::tg_poll_content;
+use crate::convert::tg_preview_url;
+use crate::convert::tg_sticker_content;
+use crate::convert::tg_to_mx_content;
+use crate::convert::TgUpload;
+use crate::db::BridgedPoll;
+use crate::network::ContentKind;
+use crate::network::RemoteEvent;
+use crate::network::RemoteNetwork;
+use crate::portals::migrate_bridge;
+use crate::relay::remote_to_mx;
+use crate::telegram::TelegramNetwork;
+use crate::templates::Sender;
 use matrix_sdk::ruma::RoomId;
-use matrix_sdk::Client;
 use matrix_sdk::Room;
 
-fn find_mx_event_id(tg_reply: &teloxide::types::Message, mx_chat: &str) -> Option<OwnedEventId> {
-	let bms = get_bms(mx_chat)?;
-	let bm = bms.iter().find(|t| t.telegram_id == (tg_reply.chat.id, tg_reply.id))?;
-	Some(bm.matrix_id.clone())
-}
+// a room the client never syncs must not hold up the others
+const READ_ONLY_SETUP_TIMEOUT: Duration = Duration::from_secs(60);
 
-async fn get_reply(msg: &Message, matrix_room: &Room) -> Option<AnyMessageLikeEvent> {
-	let event_id = find_mx_event_id(msg, matrix_room.room_id().as_str())?;
+async fn get_reply(
+	ctx: &BridgeContext,
+	msg: &Message,
+	matrix_room: &Room,
+) -> Option<AnyMessageLikeEvent> {
+	let event_id =
+		ctx.store.find_mx_event_id(matrix_room.room_id().as_str(), (msg.chat.id, msg.id))?;
 	let kind = matrix_room.event(&event_id, None).await.ok()?.kind;
 	let AnyTimelineEvent::MessageLike(ev) = kind.raw().deserialize_as::<AnyTimelineEvent>().ok()?
 	else {
@@ -45,75 +74,276 @@ async fn get_reply(msg: &Message, matrix_room: &Room) -> Option<AnyMessageLikeEv
 	Some(ev)
 }
 
-pub async fn tg_to_mx(
-	msg: Message,
-	bot: Throttle<Bot>,
-	client: Arc<Client>,
-	bridges: Arc<Vec<Bridge>>,
+async fn tg_poll_to_mx(
+	ctx: &BridgeContext,
+	msg: &Message,
+	poll: &Poll,
+	sender: &Sender,
+	bridge: &Bridge,
+	matrix_room: &Room,
+) -> anyhow::Result<()> {
+	let content = tg_poll_content(poll, sender, &bridge.templates)?;
+	let sent_mx_msg = matrix_room.send(content).with_transaction_id(tg_transaction_id(msg)).await?;
+	ctx.store.update_bridged_messages(
+		sent_mx_msg.event_id.clone(),
+		(msg.chat.id, msg.id),
+		matrix_room.room_id().as_str(),
+	)?;
+	ctx.store.update_bridged_polls(BridgedPoll {
+		telegram_poll_id: poll.id.clone(),
+		matrix_id: sent_mx_msg.event_id,
+		matrix_chat_id: matrix_room.room_id().to_string(),
+		telegram_id: (msg.chat.id, msg.id),
+		answers: poll
+			.options
+			.iter()
+			.enumerate()
+			.map(|(i, option)| (i.to_string(), option.text.clone()))
+			.collect(),
+	})?;
+	Ok(())
+}
+
+async fn tg_sticker_to_mx(
+	ctx: &BridgeContext,
+	msg: &Message,
+	sticker: &Sticker,
+	sender: &Sender,
+	bridge: &Bridge,
+	matrix_room: &Room,
 ) -> anyhow::Result<()> {
+	let (mxc_uri, info) = if let Some(mxc_uri) =
+		ctx.store.get_cached_mxc_uri(TelegramNetwork::NAME, &sticker.file.unique_id)
+	{
+		let mut info = ImageInfo::new();
+		info.mimetype = Some(mime::IMAGE_PNG.to_string());
+		info.width = Some(UInt::from(sticker.width));
+		info.height = Some(UInt::from(sticker.height));
+		(mxc_uri, info)
+	} else {
+		let (png, info) = get_sticker_image(ctx.bot.inner(), sticker).await?;
+		let mxc_uri = ctx.client.media().upload(&mime::IMAGE_PNG, png, None).await?.content_uri;
+		ctx.store.cache_media(
+			TelegramNetwork::NAME,
+			&mxc_uri,
+			Some(&sticker.file.unique_id),
+			Some((&ContentKind::Sticker, &sticker.file.id)),
+		)?;
+		(mxc_uri, info)
+	};
+	let reply_to = msg.reply_to_message().and_then(|msg_reply| {
+		let mx_chat = matrix_room.room_id().as_str();
+		ctx.store.find_mx_event_id(mx_chat, (msg_reply.chat.id, msg_reply.id))
+	});
+	let content = tg_sticker_content(sticker, sender, &bridge.templates, mxc_uri, info, reply_to);
+	let sent_mx_msg = matrix_room.send(content).with_transaction_id(tg_transaction_id(msg)).await?;
+	ctx.store.update_bridged_messages(
+		sent_mx_msg.event_id,
+		(msg.chat.id, msg.id),
+		matrix_room.room_id().as_str(),
+	)?;
+	Ok(())
+}
+
+pub async fn tg_poll_update(poll: Poll, ctx: Arc<BridgeContext>) -> anyhow::Result<()> {
+	if !poll.is_closed {
+		return Ok(());
+	}
+	let Some(bridged_poll) = ctx.store.take_bridged_poll(|p| p.telegram_poll_id == poll.id)? else {
+		return Ok(());
+	};
+	let matrix_room = ctx
+		.client
+		.get_room(&RoomId::parse(&bridged_poll.matrix_chat_id)?)
+		.context("can't get matrix room")?;
+	let content =
+		UnstablePollEndEventContent::new(poll_results_text(&poll), bridged_poll.matrix_id);
+	let txn_id = format!("tg_poll_end_{}", poll.id).into();
+	matrix_room.send(content).with_transaction_id(txn_id).await?;
+	Ok(())
+}
+
+// telegram only reports votes on non-anonymous polls the bot sent itself
+pub async fn tg_poll_answer(answer: PollAnswer, ctx: Arc<BridgeContext>) -> anyhow::Result<()> {
+	let Some(bridged_poll) = ctx.store.find_bridged_poll(|p| p.telegram_poll_id == answer.poll_id)
+	else {
+		return Ok(());
+	};
+	let (voter_id, name) = match &answer.voter {
+		Voter::User(user) => (user.id.to_string(), user.full_name()),
+		Voter::Chat(chat) => (chat.id.to_string(), chat.title().unwrap_or("anonymous").to_string()),
+	};
+	if ctx.store.is_opted_out(&format!("tg:{voter_id}")) {
+		return Ok(());
+	}
+	let chosen = answer
+		.option_ids
+		.iter()
+		.filter_map(|&i| bridged_poll.answers.get(usize::from(i)))
+		.map(|(id, _)| id.clone())
+		.collect::<Vec<_>>();
+	let matrix_room = ctx
+		.client
+		.get_room(&RoomId::parse(&bridged_poll.matrix_chat_id)?)
+		.context("can't get matrix room")?;
+	let text = format!("{name} {}", poll_vote_text(&bridged_poll.answers, &chosen));
+	let mut content = RoomMessageEventContent::notice_plain(text);
+	content.relates_to = Some(Relation::Reply {
+		in_reply_to: InReplyTo::new(bridged_poll.matrix_id),
+	});
+	// a redelivered vote has the same voter and options
+	let options = answer.option_ids.iter().map(u8::to_string).collect::<Vec<_>>().join("-");
+	let txn_id = format!("tg_vote_{}_{voter_id}_{options}", answer.poll_id).into();
+	utils::matrix::send_with_transaction_id(matrix_room.into(), content, txn_id).await?;
+	Ok(())
+}
+
+// channels are mirrored one way, the matrix room only lets moderators post
+pub async fn setup_read_only_bridges(ctx: Arc<BridgeContext>) {
+	let mut setups = JoinSet::new();
+	for bridge in ctx.bridges() {
+		let ctx = ctx.clone();
+		setups.spawn(async move {
+			let setup = setup_read_only_bridge(&ctx, &bridge);
+			match tokio::time::timeout(READ_ONLY_SETUP_TIMEOUT, setup).await {
+				Ok(Ok(())) => (),
+				Ok(Err(e)) => log::error!("{}: {e}", bridge.mx_id),
+				Err(_) => log::error!("{}: read-only setup timed out", bridge.mx_id),
+			}
+		});
+	}
+	while setups.join_next().await.is_some() {}
+}
+
+async fn setup_read_only_bridge(ctx: &BridgeContext, bridge: &Bridge) -> anyhow::Result<()> {
+	if !bridge.read_only {
+		if !ctx.bot.get_chat(ChatId(bridge.tg_id)).await?.is_channel() {
+			return Ok(());
+		}
+		ctx.add_bridge(Bridge {
+			read_only: true,
+			..bridge.clone()
+		});
+	}
+	let room = ctx.client.await_room_remote_echo(&RoomId::parse(&bridge.mx_id)?).await;
+	make_room_read_only(&room).await
+}
+
+pub async fn tg_edit_to_mx(msg: Message, ctx: Arc<BridgeContext>) -> anyhow::Result<()> {
+	if ctx.store.is_opted_out(&tg_sender_id(&msg)) {
+		return Ok(());
+	}
+	let Some(text) = msg.text() else {
+		log::debug!("only text edits are supported");
+		return Ok(());
+	};
+	ctx.network.push(RemoteEvent::Edit {
+		chat_id: msg.chat.id,
+		id: (msg.chat.id, msg.id),
+		edit_id: msg.edit_date().map(|date| date.timestamp().to_string()),
+		sender: get_tg_sender(&msg)?,
+		text: text.to_string(),
+	});
+	Ok(())
+}
+
+// bridges the events telegram hands to the shared core
+pub async fn tg_incoming(ctx: Arc<BridgeContext>) {
+	let mut events = std::pin::pin!(ctx.network.incoming());
+	while let Some(event) = events.next().await {
+		let chat_id = *event.chat_id();
+		let queue_ctx = ctx.clone();
+		ctx.queues
+			.enqueue(chat_id, async move {
+				let bridge = queue_ctx.bridge_by_tg(chat_id).context("chat isn't bridged")?;
+				let matrix_room = queue_ctx
+					.client
+					.get_room(&RoomId::parse(&bridge.mx_id)?)
+					.context("can't get matrix room")?;
+				let ctx = &queue_ctx;
+				remote_to_mx(
+					&ctx.network,
+					&ctx.client,
+					&ctx.store,
+					&matrix_room,
+					&bridge.templates,
+					event,
+				)
+				.await
+			})
+			.await;
+	}
+}
+
+pub async fn tg_to_mx(msg: Message, ctx: Arc<BridgeContext>) -> anyhow::Result<()> {
+	if let Some(&new_tg_id) = msg.migrate_to_chat_id() {
+		return migrate_bridge(&ctx, msg.chat.id, new_tg_id);
+	}
+	if ctx.bridge_by_tg(msg.chat.id).is_none() || ctx.store.is_opted_out(&tg_sender_id(&msg)) {
+		return Ok(());
+	}
+	ctx.queues.enqueue(msg.chat.id.0, bridge_tg_message(msg, ctx.clone())).await;
+	Ok(())
+}
+
+async fn bridge_tg_message(msg: Message, ctx: Arc<BridgeContext>) -> anyhow::Result<()> {
 	//crate::timer::timer!();
-	let user = get_user_name(&msg)?;
-	let bot = <Throttle<Bot> as Clone>::clone(&bot).into_inner();
+	let sender = get_tg_sender(&msg)?;
+	let bot = ctx.bot.inner();
+	let client = &ctx.client;
 	let MessageKind::Common(ref msg_common) = msg.kind else {
 		bail!("");
 	};
 
-	let bridge: &Bridge = {
+	let bridge: Arc<Bridge> = {
 		//crate::timer::timer!();
-		bridges.iter().find(|b| b.tg_id == msg.chat.id.0).context("chat isn't bridged")?
+		ctx.bridge_by_tg(msg.chat.id).context("chat isn't bridged")?
 	};
 
 	let matrix_room =
 		client.get_room(&RoomId::parse(&bridge.mx_id)?).context("can't get matrix room")?;
 
-	let tg_file: Option<(String, mime::Mime)> = match msg_common.media_kind {
-		MediaKind::Photo(ref m) => {
-			let Some(photo) = &m.photo.last() else {
-				bail!("")
-			};
-			let file_path = bot.get_file(&photo.file.id).await?.path;
-			let file_url = format!("https://api.telegram.org/file/bot{}/{file_path}", bot.token());
-			Some((file_url, mime::IMAGE_JPEG))
-		}
-		MediaKind::Animation(ref m) => {
-			let file_path = bot.get_file(&m.animation.file.id).await?.path;
-			let file_url = format!("https://api.telegram.org/file/bot{}/{file_path}", bot.token());
-			Some((file_url, "video/mp4".parse::<mime::Mime>()?))
+	if ctx.store.find_mx_event_id(matrix_room.room_id().as_str(), (msg.chat.id, msg.id)).is_some() {
+		log::debug!("{}:{} already bridged", msg.chat.id, msg.id);
+		return Ok(());
+	}
+
+	if let MediaKind::Poll(ref m) = msg_common.media_kind {
+		return tg_poll_to_mx(&ctx, &msg, &m.poll, &sender, &bridge, &matrix_room).await;
+	}
+	if let MediaKind::Sticker(ref m) = msg_common.media_kind {
+		if !m.sticker.is_video() {
+			return tg_sticker_to_mx(&ctx, &msg, &m.sticker, &sender, &bridge, &matrix_room).await;
 		}
-		MediaKind::Sticker(ref m) => {
-			let mime = if m.sticker.is_video() {
-				"video/webm".parse::<mime::Mime>()?
+	}
+
+	let mxc_uri = match get_tg_upload(&msg)? {
+		Some(TgUpload::File(file, mime, kind)) => {
+			if let Some(mxc_uri) =
+				ctx.store.get_cached_mxc_uri(TelegramNetwork::NAME, &file.unique_id)
+			{
+				Some(mxc_uri)
 			} else {
-				"image/webp".parse::<mime::Mime>()?
-			};
-			let file_path = bot.get_file(&m.sticker.file.id).await?.path;
-			let file_url = format!("https://api.telegram.org/file/bot{}/{file_path}", bot.token());
-			Some((file_url, mime))
-		}
-		MediaKind::Video(ref m) => {
-			let file_path = bot.get_file(&m.video.file.id).await?.path;
-			let file_url = format!("https://api.telegram.org/file/bot{}/{file_path}", bot.token());
-			Some((file_url, "video/mp4".parse::<mime::Mime>()?))
+				let media = download_tg_file(bot, &file.id).await?;
+				let mxc_uri = client.media().upload(&mime, media, None).await?.content_uri;
+				ctx.store.cache_media(
+					TelegramNetwork::NAME,
+					&mxc_uri,
+					Some(&file.unique_id),
+					Some((&kind, &file.id)),
+				)?;
+				Some(mxc_uri)
+			}
 		}
-		MediaKind::Document(ref m) => {
-			let file_path = bot.get_file(&m.document.file.id).await?.path;
-			let file_url = format!("https://api.telegram.org/file/bot{}/{file_path}", bot.token());
-			Some((file_url, mime::APPLICATION_OCTET_STREAM))
+		Some(TgUpload::Vcard(vcard)) => {
+			let mime = "text/vcard".parse::<mime::Mime>()?;
+			Some(client.media().upload(&mime, vcard.into_bytes(), None).await?.content_uri)
 		}
-		_ => None,
-	};
-
-	let mxc_uri = if let Some(ref tg_file) = tg_file {
-		let file_url = &tg_file.0;
-		let mime = &tg_file.1;
-		let file = reqwest::get(file_url).await?.bytes().await?.to_vec();
-		Some(client.media().upload(mime, file, None).await?.content_uri)
-	} else {
-		None
+		None => None,
 	};
 
 	let reply_owned_event_id = if let Some(msg_reply) = &msg_common.reply_to_message {
-		if let Some(ev) = get_reply(msg_reply, &matrix_room).await {
+		if let Some(ev) = get_reply(&ctx, msg_reply, &matrix_room).await {
 			let event_id = match ev {
 				AnyMessageLikeEvent::RoomMessage(ref m) => {
 					m.as_original().context("redacted")?.event_id.clone()
@@ -130,99 +360,40 @@ pub async fn tg_to_mx(
 	} else {
 		None
 	};
+	let reply_to = if let Some(event_id) = reply_owned_event_id {
+		let event = matrix_room.event(&event_id, None).await?;
+		Some(event.kind.raw().deserialize_as::<OriginalRoomMessageEvent>()?)
+	} else {
+		None
+	};
 
-	let caption = format!("(from {user}\n{})", msg.caption().unwrap_or(""));
-	let message =
-		match &msg_common.media_kind {
-			MediaKind::Text(t) => {
-				let text = format!("{}: {}", user, t.text);
-				if let Some(event_id) = reply_owned_event_id {
-					let event = matrix_room.event(&event_id, None).await?;
-					let msg = event.kind.raw().deserialize_as::<OriginalRoomMessageEvent>()?;
-					RoomMessageEventContent::text_plain(text).make_reply_to(
-						&msg,
-						ForwardThread::No,
-						AddMentions::No,
-					)
-				} else {
-					RoomMessageEventContent::text_plain(text)
-				}
-			}
-			MediaKind::Photo(_) | MediaKind::Sticker(_) => {
-				let Some(tg_file) = tg_file else {
-					bail!("")
-				};
-				let Some(mxc_uri) = mxc_uri else {
-					bail!("")
-				};
-				if tg_file.1.type_() == "video" {
-					let event_content =
-						VideoMessageEventContent::new(caption, MediaSource::Plain(mxc_uri));
-					if let Some(event_id) = reply_owned_event_id {
-						let event = matrix_room.event(&event_id, None).await?;
-						let msg = event.kind.raw().deserialize_as::<OriginalRoomMessageEvent>()?;
-						RoomMessageEventContent::new(MessageType::Video(event_content))
-							.make_reply_to(&msg, ForwardThread::No, AddMentions::No)
-					} else {
-						RoomMessageEventContent::new(MessageType::Video(event_content))
-					}
-				} else {
-					let event_content =
-						ImageMessageEventContent::new(caption, MediaSource::Plain(mxc_uri));
-					if let Some(event_id) = reply_owned_event_id {
-						let event = matrix_room.event(&event_id, None).await?;
-						let msg = event.kind.raw().deserialize_as::<OriginalRoomMessageEvent>()?;
-						RoomMessageEventContent::new(MessageType::Image(event_content))
-							.make_reply_to(&msg, ForwardThread::No, AddMentions::No)
-					} else {
-						RoomMessageEventContent::new(MessageType::Image(event_content))
-					}
-				}
-			}
-			MediaKind::Animation(_) | MediaKind::Video(_) => {
-				let Some(mxc_uri) = mxc_uri else {
-					bail!("")
-				};
-				let event_content =
-					VideoMessageEventContent::new(caption, MediaSource::Plain(mxc_uri));
-				if let Some(event_id) = reply_owned_event_id {
-					let event = matrix_room.event(&event_id, None).await?;
-					let msg = event.kind.raw().deserialize_as::<OriginalRoomMessageEvent>()?;
-					RoomMessageEventContent::new(MessageType::Video(event_content)).make_reply_to(
-						&msg,
-						ForwardThread::No,
-						AddMentions::No,
-					)
-				} else {
-					RoomMessageEventContent::new(MessageType::Video(event_content))
-				}
-			}
-			MediaKind::Document(_) => {
-				let Some(mxc_uri) = mxc_uri else {
-					bail!("")
-				};
-				let event_content =
-					FileMessageEventContent::new(caption, MediaSource::Plain(mxc_uri));
-				if let Some(event_id) = reply_owned_event_id {
-					let event = matrix_room.event(&event_id, None).await?;
-					let msg = event.kind.raw().deserialize_as::<OriginalRoomMessageEvent>()?;
-					RoomMessageEventContent::new(MessageType::File(event_content)).make_reply_to(
-						&msg,
-						ForwardThread::No,
-						AddMentions::No,
-					)
-				} else {
-					RoomMessageEventContent::new(MessageType::File(event_content))
-				}
-			}
-			_ => bail!("unsupported media_kind"),
-		};
-	let sent_mx_msg = utils::matrix::send(matrix_room.clone().into(), message).await?;
-	update_bridged_messages(
+	let message = tg_to_mx_content(&msg, &sender, &bridge.templates, mxc_uri, reply_to.as_ref())?;
+	let message = match tg_preview_url(&msg) {
+		Some(url) if bridge.preview_blocks => add_preview_block(message, &url),
+		_ => message,
+	};
+	let has_spoiler = tg_has_spoiler(&msg);
+	let sent_mx_msg = if has_spoiler {
+		let mut content = serde_json::to_value(&message)?;
+		content[CONTENT_WARNING_KEY] = serde_json::json!({ "type": CONTENT_WARNING_SPOILER });
+		matrix_room
+			.send_raw("m.room.message", content)
+			.with_transaction_id(&tg_transaction_id(&msg))
+			.await?
+	} else {
+		let txn_id = tg_transaction_id(&msg);
+		utils::matrix::send_with_transaction_id(matrix_room.clone().into(), message, txn_id).await?
+	};
+	ctx.store.update_bridged_messages(
 		sent_mx_msg.event_id,
 		(msg.chat.id, msg.id),
 		matrix_room.room_id().as_str(),
 	)?;
 
+	if !bridge.read_only {
+		if let Err(e) = run_tg_commands(&ctx, &msg, &matrix_room).await {
+			log::error!("{e}");
+		}
+	}
 	Ok(())
 }
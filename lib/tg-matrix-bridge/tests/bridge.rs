@@ -0,0 +1,980 @@
+mod harness;
+
+use axum::http::StatusCode;
+use matrix_sdk::ruma::EventId;
+use serde_json::json;
+use teloxide::types::ChatId;
+use teloxide::types::ChatMemberUpdated;
+use teloxide::types::Message;
+use teloxide::types::MessageId;
+
+use harness::bridge;
+use harness::homeserver::MODERATOR_USER_ID;
+use harness::mx_bridged;
+use harness::mx_event;
+use harness::send_mx_event;
+use harness::tg_message;
+use harness::wait_until;
+use harness::Harness;
+use harness::ADMIN_USER_ID;
+use harness::BRIDGE_USER_ID;
+use harness::ROOM_ID;
+use harness::TG_ADMIN_ID;
+use harness::TG_CHAT_ID;
+use tg_matrix_bridge::bot_commands::tg_command;
+use tg_matrix_bridge::bot_commands::BridgeCommand;
+use tg_matrix_bridge::bridge_structs::Bridge;
+use tg_matrix_bridge::bridge_structs::LinkPreviewPolicy;
+use tg_matrix_bridge::portals::tg_member_update;
+use tg_matrix_bridge::templates::SenderTemplates;
+use tg_matrix_bridge::tg_handlers::setup_read_only_bridges;
+use tg_matrix_bridge::tg_handlers::tg_edit_to_mx;
+use tg_matrix_bridge::tg_handlers::tg_poll_answer;
+use tg_matrix_bridge::tg_handlers::tg_to_mx;
+
+fn tg_bridged(h: &Harness, message_id: i32) -> bool {
+	h.ctx.store.find_mx_event_id(ROOM_ID, (ChatId(TG_CHAT_ID), MessageId(message_id))).is_some()
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn telegram_text_reaches_matrix() {
+	let h = Harness::new().await;
+	tg_to_mx(tg_message(10, json!({ "text": "hello matrix" })), h.ctx.clone()).await.unwrap();
+	wait_until(|| tg_bridged(&h, 10)).await;
+
+	let sends = h.homeserver.sends();
+	assert_eq!(sends.len(), 1);
+	assert_eq!(sends[0].event_type, "m.room.message");
+	assert_eq!(sends[0].txn_id, format!("tg_{TG_CHAT_ID}_10"));
+	assert_eq!(sends[0].content["body"], "Alice: hello matrix");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn redelivered_telegram_message_is_bridged_once() {
+	let h = Harness::new().await;
+	let msg = tg_message(10, json!({ "text": "only once" }));
+	tg_to_mx(msg.clone(), h.ctx.clone()).await.unwrap();
+	tg_to_mx(msg, h.ctx.clone()).await.unwrap();
+	tg_to_mx(tg_message(11, json!({ "text": "next" })), h.ctx.clone()).await.unwrap();
+	wait_until(|| tg_bridged(&h, 11)).await;
+
+	assert_eq!(h.homeserver.sends().len(), 2);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn redelivered_telegram_message_is_retried_after_failure() {
+	let h = Harness::new().await;
+	h.homeserver.fail_next_send(
+		StatusCode::FORBIDDEN,
+		json!({ "errcode": "M_FORBIDDEN", "error": "not allowed" }),
+	);
+	let msg = tg_message(10, json!({ "text": "second try" }));
+	tg_to_mx(msg.clone(), h.ctx.clone()).await.unwrap();
+	wait_until(|| h.homeserver.send_attempts().len() == 1).await;
+	tg_to_mx(msg, h.ctx.clone()).await.unwrap();
+	wait_until(|| tg_bridged(&h, 10)).await;
+
+	assert_eq!(h.homeserver.sends().len(), 1);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn telegram_photo_is_uploaded_once() {
+	let h = Harness::new().await;
+	h.bot_api.add_file("photo", b"jpeg bytes");
+	let photo =
+		json!([{ "file_id": "photo", "file_unique_id": "u_photo", "width": 800, "height": 600 }]);
+	tg_to_mx(tg_message(10, json!({ "photo": photo, "caption": "a" })), h.ctx.clone())
+		.await
+		.unwrap();
+	tg_to_mx(tg_message(11, json!({ "photo": photo, "caption": "b" })), h.ctx.clone())
+		.await
+		.unwrap();
+	wait_until(|| tg_bridged(&h, 11)).await;
+
+	let sends = h.homeserver.sends();
+	assert_eq!(sends[0].content["msgtype"], "m.image");
+	assert_eq!(sends[0].content["url"], sends[1].content["url"]);
+	assert!(sends[0].content["url"].as_str().unwrap().starts_with("mxc://fake.server/"));
+	assert_eq!(h.bot_api.requests("getFile").len(), 1);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn telegram_reply_becomes_matrix_reply() {
+	let h = Harness::new().await;
+	let original = mx_event(
+		"$original:example.org",
+		"m.room.message",
+		json!({ "msgtype": "m.text", "body": "original" }),
+	);
+	h.homeserver.add_event(original);
+	h.ctx
+		.store
+		.update_bridged_messages(
+			EventId::parse("$original:example.org").unwrap(),
+			(ChatId(TG_CHAT_ID), MessageId(9)),
+			ROOM_ID,
+		)
+		.unwrap();
+	let reply_to = json!({
+		"message_id": 9,
+		"date": 1_700_000_000,
+		"chat": { "id": TG_CHAT_ID, "type": "supergroup", "title": "bridge test" },
+		"text": "original",
+	});
+	let msg = tg_message(10, json!({ "text": "agreed", "reply_to_message": reply_to }));
+	tg_to_mx(msg, h.ctx.clone()).await.unwrap();
+	wait_until(|| tg_bridged(&h, 10)).await;
+
+	let content = &h.homeserver.sends()[0].content;
+	assert_eq!(content["m.relates_to"]["m.in_reply_to"]["event_id"], "$original:example.org");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn telegram_reply_to_unbridged_message_is_quoted() {
+	let h = Harness::new().await;
+	let reply_to = json!({
+		"message_id": 9,
+		"date": 1_700_000_000,
+		"chat": { "id": TG_CHAT_ID, "type": "supergroup", "title": "bridge test" },
+		"from": { "id": 2222, "is_bot": false, "first_name": "Carol" },
+		"text": "an old message",
+	});
+	let msg = tg_message(10, json!({ "text": "agreed", "reply_to_message": reply_to }));
+	tg_to_mx(msg, h.ctx.clone()).await.unwrap();
+	wait_until(|| tg_bridged(&h, 10)).await;
+
+	let content = &h.homeserver.sends()[0].content;
+	assert!(content.get("m.relates_to").is_none());
+	assert_eq!(content["body"], "> Carol: an old message\n\nAlice: agreed");
+	assert_eq!(
+		content["formatted_body"],
+		"<blockquote><b>Carol</b>: an old message</blockquote>Alice: agreed"
+	);
+}
+
+fn tg_channel_post(message_id: i32, fields: serde_json::Value) -> Message {
+	let chat = json!({ "id": TG_CHAT_ID, "type": "channel", "title": "news" });
+	let mut post = json!({
+		"message_id": message_id,
+		"date": 1_700_000_000,
+		"chat": chat,
+		"sender_chat": chat,
+		"author_signature": "Alice",
+	});
+	for (key, value) in fields.as_object().unwrap() {
+		post[key] = value.clone();
+	}
+	serde_json::from_value(post).unwrap()
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn telegram_channel_edit_replaces_matrix_event() {
+	let h = Harness::new().await;
+	tg_to_mx(tg_channel_post(10, json!({ "text": "first" })), h.ctx.clone()).await.unwrap();
+	let edit = tg_channel_post(10, json!({ "text": "second", "edit_date": 1_700_000_100 }));
+	tg_edit_to_mx(edit.clone(), h.ctx.clone()).await.unwrap();
+	// redelivered, then edited again
+	tg_edit_to_mx(edit, h.ctx.clone()).await.unwrap();
+	let edit = tg_channel_post(10, json!({ "text": "third", "edit_date": 1_700_000_200 }));
+	tg_edit_to_mx(edit, h.ctx.clone()).await.unwrap();
+	wait_until(|| h.homeserver.sends().len() == 3).await;
+
+	let sends = h.homeserver.sends();
+	let content = &sends[1].content;
+	assert_eq!(content["m.relates_to"]["rel_type"], "m.replace");
+	assert_eq!(content["m.relates_to"]["event_id"], sends[0].event_id);
+	assert_eq!(content["m.new_content"]["body"], "news (Alice): second");
+	assert_eq!(sends[2].content["m.new_content"]["body"], "news (Alice): third");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn telegram_channel_bridges_are_read_only() {
+	let h = Harness::new().await;
+	h.bot_api.add_channel(TG_CHAT_ID);
+	setup_read_only_bridges(h.ctx.clone()).await;
+
+	assert!(h.ctx.bridge_by_tg(ChatId(TG_CHAT_ID)).unwrap().read_only);
+	let state = h.homeserver.state_events();
+	let power_levels = state.iter().find(|e| e.event_type == "m.room.power_levels").unwrap();
+	assert_eq!(power_levels.room_id, ROOM_ID);
+	assert_eq!(power_levels.content["events_default"], 50);
+	assert_eq!(power_levels.content["users"][BRIDGE_USER_ID], 100);
+
+	let event = mx_event(
+		"$m1:example.org",
+		"m.room.message",
+		json!({ "msgtype": "m.text", "body": "hello channel" }),
+	);
+	send_mx_event(&h, &event).await;
+	assert!(h.bot_api.requests("sendMessage").is_empty());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn matrix_edit_redaction_and_reaction_reach_telegram() {
+	let h = Harness::new().await;
+	let original = mx_event(
+		"$m1:example.org",
+		"m.room.message",
+		json!({ "msgtype": "m.text", "body": "tpyo" }),
+	);
+	send_mx_event(&h, &original).await;
+	wait_until(|| mx_bridged(&h, "$m1:example.org")).await;
+
+	let edit = mx_event(
+		"$m2:example.org",
+		"m.room.message",
+		json!({
+			"msgtype": "m.text",
+			"body": "* typo",
+			"m.new_content": { "msgtype": "m.text", "body": "typo" },
+			"m.relates_to": { "rel_type": "m.replace", "event_id": "$m1:example.org" },
+		}),
+	);
+	send_mx_event(&h, &edit).await;
+	let reaction = mx_event(
+		"$m3:example.org",
+		"m.reaction",
+		json!({
+			"m.relates_to": { "rel_type": "m.annotation", "event_id": "$m1:example.org", "key": "👍️" },
+		}),
+	);
+	send_mx_event(&h, &reaction).await;
+	let mut redaction =
+		mx_event("$m4:example.org", "m.room.redaction", json!({ "redacts": "$m1:example.org" }));
+	redaction["redacts"] = json!("$m1:example.org");
+	send_mx_event(&h, &redaction).await;
+	wait_until(|| !h.bot_api.requests("deleteMessage").is_empty()).await;
+
+	assert_eq!(h.bot_api.requests("sendMessage").len(), 1);
+	let edits = h.bot_api.requests("editMessageText");
+	assert_eq!(edits.len(), 1);
+	assert_eq!(edits[0].params["text"], "@bob:example.org: typo");
+	let reactions = h.bot_api.requests("setMessageReaction");
+	assert_eq!(reactions[0].params["reaction"], json!([{ "type": "emoji", "emoji": "👍" }]));
+	let deletes = h.bot_api.requests("deleteMessage");
+	assert_eq!(deletes[0].params["message_id"], edits[0].params["message_id"]);
+	assert_eq!(deletes[0].params["chat_id"], TG_CHAT_ID);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn matrix_text_reaches_telegram() {
+	let h = Harness::new().await;
+	let event = mx_event(
+		"$m1:example.org",
+		"m.room.message",
+		json!({ "msgtype": "m.text", "body": "hello telegram" }),
+	);
+	send_mx_event(&h, &event).await;
+	wait_until(|| mx_bridged(&h, "$m1:example.org")).await;
+
+	let requests = h.bot_api.requests("sendMessage");
+	assert_eq!(requests.len(), 1);
+	assert_eq!(requests[0].params["chat_id"], TG_CHAT_ID);
+	assert_eq!(requests[0].params["text"], "@bob:example.org: hello telegram");
+	// bridges without a link preview policy keep telegram's default previews
+	let link_preview = &requests[0].params["link_preview_options"];
+	assert_ne!(link_preview["prefer_large_media"], true);
+	assert_ne!(link_preview["is_disabled"], true);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn matrix_text_spoilers_become_telegram_spoilers() {
+	let h = Harness::new().await;
+	let event = mx_event(
+		"$m1:example.org",
+		"m.room.message",
+		json!({
+			"msgtype": "m.text",
+			"body": "🙂 ending: it's me",
+			"format": "org.matrix.custom.html",
+			"formatted_body": "🙂 ending: <span data-mx-spoiler>it's me</span>",
+		}),
+	);
+	send_mx_event(&h, &event).await;
+	wait_until(|| mx_bridged(&h, "$m1:example.org")).await;
+
+	let requests = h.bot_api.requests("sendMessage");
+	let prefix = "@bob:example.org: 🙂 ending: ".encode_utf16().count();
+	assert_eq!(
+		requests[0].params["entities"],
+		json!([{ "type": "spoiler", "offset": prefix, "length": 7 }])
+	);
+}
+
+fn mx_poll(event_id: &str) -> serde_json::Value {
+	mx_event(
+		event_id,
+		"org.matrix.msc3381.poll.start",
+		json!({
+			"org.matrix.msc1767.text": "lunch?\n1. pizza\n2. sushi",
+			"org.matrix.msc3381.poll.start": {
+				"question": { "org.matrix.msc1767.text": "lunch?" },
+				"kind": "org.matrix.msc3381.poll.disclosed",
+				"max_selections": 1,
+				"answers": [
+					{ "id": "p", "org.matrix.msc1767.text": "pizza" },
+					{ "id": "s", "org.matrix.msc1767.text": "sushi" },
+				],
+			},
+		}),
+	)
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn matrix_poll_votes_reach_telegram() {
+	let h = Harness::new().await;
+	send_mx_event(&h, &mx_poll("$p1:example.org")).await;
+	wait_until(|| mx_bridged(&h, "$p1:example.org")).await;
+	let polls = h.bot_api.requests("sendPoll");
+	assert_eq!(polls[0].params["is_anonymous"], false);
+
+	let vote = mx_event(
+		"$v1:example.org",
+		"org.matrix.msc3381.poll.response",
+		json!({
+			"org.matrix.msc3381.poll.response": { "answers": ["s"] },
+			"m.relates_to": { "rel_type": "m.reference", "event_id": "$p1:example.org" },
+		}),
+	);
+	send_mx_event(&h, &vote).await;
+	wait_until(|| !h.bot_api.requests("sendMessage").is_empty()).await;
+
+	let requests = h.bot_api.requests("sendMessage");
+	assert_eq!(requests[0].params["text"], "@bob:example.org: voted for sushi");
+	let poll_msg_id =
+		h.ctx.store.find_tg_msg_id(ROOM_ID, &EventId::parse("$p1:example.org").unwrap()).unwrap();
+	assert_eq!(requests[0].params["reply_parameters"]["message_id"], poll_msg_id.0);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn telegram_poll_votes_reach_matrix() {
+	let h = Harness::new().await;
+	send_mx_event(&h, &mx_poll("$p1:example.org")).await;
+	wait_until(|| mx_bridged(&h, "$p1:example.org")).await;
+
+	let poll_id = h.ctx.store.find_bridged_poll(|_| true).unwrap().telegram_poll_id;
+	let answer = |option_ids: serde_json::Value| {
+		serde_json::from_value(json!({
+			"poll_id": poll_id,
+			"user": { "id": 7, "is_bot": false, "first_name": "Alice" },
+			"option_ids": option_ids,
+		}))
+		.unwrap()
+	};
+	tg_poll_answer(answer(json!([0])), h.ctx.clone()).await.unwrap();
+	// redelivered
+	tg_poll_answer(answer(json!([0])), h.ctx.clone()).await.unwrap();
+	tg_poll_answer(answer(json!([])), h.ctx.clone()).await.unwrap();
+
+	let sends = h.homeserver.sends();
+	assert_eq!(sends.len(), 2);
+	assert_eq!(sends[0].content["body"], "Alice voted for pizza");
+	assert_eq!(sends[1].content["body"], "Alice retracted their vote");
+	assert_eq!(sends[0].content["m.relates_to"]["m.in_reply_to"]["event_id"], "$p1:example.org");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn matrix_reply_becomes_telegram_reply() {
+	let h = Harness::new().await;
+	h.ctx
+		.store
+		.update_bridged_messages(
+			EventId::parse("$original:example.org").unwrap(),
+			(ChatId(TG_CHAT_ID), MessageId(9)),
+			ROOM_ID,
+		)
+		.unwrap();
+	let event = mx_event(
+		"$m1:example.org",
+		"m.room.message",
+		json!({
+			"msgtype": "m.text",
+			"body": "> <@alice:example.org> original\n\nreply body",
+			"m.relates_to": { "m.in_reply_to": { "event_id": "$original:example.org" } },
+		}),
+	);
+	send_mx_event(&h, &event).await;
+	wait_until(|| mx_bridged(&h, "$m1:example.org")).await;
+
+	let params = &h.bot_api.requests("sendMessage")[0].params;
+	assert_eq!(params["reply_parameters"]["message_id"], 9);
+	assert_eq!(params["text"], "@bob:example.org: reply body");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn matrix_reply_to_unbridged_event_is_quoted() {
+	let h = Harness::new().await;
+	let mut original = mx_event(
+		"$old:example.org",
+		"m.room.message",
+		json!({ "msgtype": "m.text", "body": "an old message" }),
+	);
+	original["sender"] = json!("@carol:example.org");
+	h.homeserver.add_event(original);
+	let event = mx_event(
+		"$m1:example.org",
+		"m.room.message",
+		json!({
+			"msgtype": "m.text",
+			"body": "> <@carol:example.org> an old message\n\nreply body",
+			"m.relates_to": { "m.in_reply_to": { "event_id": "$old:example.org" } },
+		}),
+	);
+	send_mx_event(&h, &event).await;
+	wait_until(|| mx_bridged(&h, "$m1:example.org")).await;
+
+	let params = &h.bot_api.requests("sendMessage")[0].params;
+	assert_eq!(params["text"], "@carol:example.org: an old message\n@bob:example.org: reply body");
+	assert_eq!(params["entities"][0]["type"], "blockquote");
+	assert_eq!(params["entities"][0]["offset"], 0);
+	assert_eq!(params["entities"][0]["length"], 34);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn matrix_image_reaches_telegram() {
+	let h = Harness::new().await;
+	h.homeserver.add_media("mxc://example.org/cat", b"png bytes");
+	let event = mx_event(
+		"$m1:example.org",
+		"m.room.message",
+		json!({ "msgtype": "m.image", "body": "cat.png", "url": "mxc://example.org/cat" }),
+	);
+	send_mx_event(&h, &event).await;
+	wait_until(|| mx_bridged(&h, "$m1:example.org")).await;
+
+	let requests = h.bot_api.requests("sendPhoto");
+	assert_eq!(requests.len(), 1);
+	assert_eq!(requests[0].params["chat_id"], TG_CHAT_ID);
+	assert_eq!(requests[0].params["photo"], "<file 9 bytes>");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn too_large_matrix_media_falls_back_to_text() {
+	let h = Harness::new().await;
+	h.homeserver.add_media("mxc://example.org/huge", b"png bytes");
+	h.bot_api.fail_next(
+		"sendPhoto",
+		StatusCode::PAYLOAD_TOO_LARGE,
+		json!({ "ok": false, "error_code": 413, "description": "Request Entity Too Large" }),
+	);
+	let event = mx_event(
+		"$m1:example.org",
+		"m.room.message",
+		json!({ "msgtype": "m.image", "body": "huge.png", "url": "mxc://example.org/huge" }),
+	);
+	send_mx_event(&h, &event).await;
+	wait_until(|| mx_bridged(&h, "$m1:example.org")).await;
+
+	assert_eq!(h.bot_api.requests("sendPhoto").len(), 1);
+	let requests = h.bot_api.requests("sendMessage");
+	assert_eq!(
+		requests[0].params["text"],
+		"@bob:example.org: telegram sucks and cannot display this message"
+	);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn matrix_location_is_attributed() {
+	let h = Harness::new().await;
+	let event = mx_event(
+		"$m1:example.org",
+		"m.room.message",
+		json!({ "msgtype": "m.location", "body": "here", "geo_uri": "geo:51.5,-0.12" }),
+	);
+	send_mx_event(&h, &event).await;
+	wait_until(|| mx_bridged(&h, "$m1:example.org")).await;
+
+	let locations = h.bot_api.requests("sendLocation");
+	assert_eq!(locations[0].params["latitude"], 51.5);
+	let sent_id = h.ctx.store.find_tg_msg_id(ROOM_ID, &EventId::parse("$m1:example.org").unwrap());
+	let attributions = h.bot_api.requests("sendMessage");
+	assert_eq!(attributions.len(), 1);
+	assert_eq!(attributions[0].params["text"], "(from @bob:example.org)");
+	assert_eq!(attributions[0].params["reply_parameters"]["message_id"], sent_id.unwrap().0);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn matrix_sticker_is_attributed() {
+	let h = Harness::new().await;
+	let mut png = std::io::Cursor::new(vec![]);
+	image::RgbaImage::new(1, 1).write_to(&mut png, image::ImageFormat::Png).unwrap();
+	h.homeserver.add_media("mxc://example.org/wave", png.get_ref());
+	let event = mx_event(
+		"$m1:example.org",
+		"m.sticker",
+		json!({
+			"body": "wave",
+			"url": "mxc://example.org/wave",
+			"info": { "mimetype": "image/png", "w": 256, "h": 256 },
+		}),
+	);
+	send_mx_event(&h, &event).await;
+	wait_until(|| mx_bridged(&h, "$m1:example.org")).await;
+
+	assert_eq!(h.bot_api.requests("sendSticker").len(), 1);
+	let sent_id = h.ctx.store.find_tg_msg_id(ROOM_ID, &EventId::parse("$m1:example.org").unwrap());
+	let attributions = h.bot_api.requests("sendMessage");
+	assert_eq!(attributions.len(), 1);
+	assert_eq!(attributions[0].params["text"], "(from @bob:example.org)\nwave");
+	assert_eq!(attributions[0].params["reply_parameters"]["message_id"], sent_id.unwrap().0);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn sender_templates_are_applied_in_both_directions() {
+	let h = Harness::with_bridge(Bridge {
+		templates: SenderTemplates {
+			text: "[{username}] {text}".to_string(),
+			bold_name: true,
+			..SenderTemplates::default()
+		},
+		..bridge()
+	})
+	.await;
+	let from = json!({ "id": 1111, "is_bot": false, "first_name": "Alice", "username": "alice" });
+	tg_to_mx(tg_message(10, json!({ "text": "hi {name}", "from": from })), h.ctx.clone())
+		.await
+		.unwrap();
+	wait_until(|| tg_bridged(&h, 10)).await;
+	let content = &h.homeserver.sends()[0].content;
+	assert_eq!(content["body"], "[@alice] hi {name}");
+	assert_eq!(content["formatted_body"], "[<b>@alice</b>] hi {name}");
+
+	let event = mx_event(
+		"$m1:example.org",
+		"m.room.message",
+		json!({ "msgtype": "m.text", "body": "hello" }),
+	);
+	send_mx_event(&h, &event).await;
+	wait_until(|| mx_bridged(&h, "$m1:example.org")).await;
+	let params = &h.bot_api.requests("sendMessage")[0].params;
+	assert_eq!(params["text"], "[@bob:example.org] hello");
+	assert_eq!(params["entities"], json!([{ "type": "bold", "offset": 1, "length": 16 }]));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn link_preview_policy_is_applied() {
+	let h = Harness::with_bridge(Bridge {
+		link_previews: LinkPreviewPolicy::FirstLink,
+		preview_blocks: true,
+		..bridge()
+	})
+	.await;
+	let text = "see https://example.org/a and https://example.org/b";
+	let entities = json!([
+		{ "type": "url", "offset": 4, "length": 21 },
+		{ "type": "url", "offset": 30, "length": 21 },
+	]);
+	tg_to_mx(tg_message(10, json!({ "text": text, "entities": entities })), h.ctx.clone())
+		.await
+		.unwrap();
+	wait_until(|| tg_bridged(&h, 10)).await;
+	let content = &h.homeserver.sends()[0].content;
+	assert_eq!(content["body"], format!("Alice: {text}"));
+	assert_eq!(
+		content["formatted_body"],
+		format!(
+			"Alice: {text}<blockquote><a href=\"https://example.org/a\">https://example.org/a</a></blockquote>"
+		)
+	);
+
+	let event = mx_event(
+		"$m1:example.org",
+		"m.room.message",
+		json!({ "msgtype": "m.text", "body": "look https://example.org/c https://example.org/d" }),
+	);
+	send_mx_event(&h, &event).await;
+	let event = mx_event(
+		"$m2:example.org",
+		"m.room.message",
+		json!({ "msgtype": "m.text", "body": "https://example.org/e", "com.beeper.linkpreviews": [] }),
+	);
+	send_mx_event(&h, &event).await;
+	wait_until(|| mx_bridged(&h, "$m2:example.org")).await;
+	let requests = h.bot_api.requests("sendMessage");
+	assert_eq!(requests[0].params["link_preview_options"]["url"], "https://example.org/c");
+	assert_eq!(requests[1].params["link_preview_options"]["is_disabled"], true);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn telegram_commands_are_answered_and_mirrored() {
+	let h = Harness::new().await;
+	tg_to_mx(tg_message(10, json!({ "text": "!ping" })), h.ctx.clone()).await.unwrap();
+	wait_until(|| h.homeserver.sends().len() == 2).await;
+
+	let requests = h.bot_api.requests("sendMessage");
+	assert_eq!(requests.len(), 1);
+	assert_eq!(requests[0].params["text"], "pong");
+	assert_eq!(requests[0].params["reply_parameters"]["message_id"], 10);
+	let sends = h.homeserver.sends();
+	assert_eq!(sends[1].content["msgtype"], "m.notice");
+	assert_eq!(sends[1].content["body"], "pong");
+	assert_eq!(sends[1].content["m.relates_to"]["m.in_reply_to"]["event_id"], sends[0].event_id);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn mute_command_stops_matrix_messages() {
+	let h = Harness::new().await;
+	let mute = tg_message(10, json!({ "text": "/mute" }));
+	tg_command(mute.clone(), BridgeCommand::Mute, h.ctx.clone()).await.unwrap();
+	let muted = mx_event(
+		"$m1:example.org",
+		"m.room.message",
+		json!({ "msgtype": "m.text", "body": "muted" }),
+	);
+	send_mx_event(&h, &muted).await;
+	tg_command(mute, BridgeCommand::Mute, h.ctx.clone()).await.unwrap();
+	let event = mx_event(
+		"$m2:example.org",
+		"m.room.message",
+		json!({ "msgtype": "m.text", "body": "bridged" }),
+	);
+	send_mx_event(&h, &event).await;
+	wait_until(|| mx_bridged(&h, "$m2:example.org")).await;
+
+	assert!(!mx_bridged(&h, "$m1:example.org"));
+	assert!(h.homeserver.sends().is_empty());
+	let texts = h
+		.bot_api
+		.requests("sendMessage")
+		.iter()
+		.map(|request| request.params["text"].as_str().unwrap().to_string())
+		.collect::<Vec<String>>();
+	assert_eq!(texts.len(), 3);
+	assert!(texts[0].starts_with("matrix messages won't be bridged"));
+	assert_eq!(texts[1], "matrix messages are bridged again");
+	assert_eq!(texts[2], "@bob:example.org: bridged");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn opted_out_telegram_users_are_not_bridged() {
+	let h = Harness::new().await;
+	let no_bridge = tg_message(10, json!({ "text": "/nobridge" }));
+	tg_command(no_bridge, BridgeCommand::NoBridge, h.ctx.clone()).await.unwrap();
+	tg_to_mx(tg_message(11, json!({ "text": "private" })), h.ctx.clone()).await.unwrap();
+	let other = tg_message(
+		12,
+		json!({ "text": "public", "from": { "id": 2222, "is_bot": false, "first_name": "Carol" } }),
+	);
+	tg_to_mx(other, h.ctx.clone()).await.unwrap();
+	wait_until(|| tg_bridged(&h, 12)).await;
+
+	assert!(!tg_bridged(&h, 11));
+	assert_eq!(h.homeserver.sends().len(), 1);
+	let requests = h.bot_api.requests("sendMessage");
+	assert!(requests[0].params["text"].as_str().unwrap().starts_with("your messages won't"));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn opted_out_matrix_users_are_not_bridged() {
+	let h = Harness::new().await;
+	let no_bridge = mx_event(
+		"$m1:example.org",
+		"m.room.message",
+		json!({ "msgtype": "m.text", "body": "!nobridge" }),
+	);
+	send_mx_event(&h, &no_bridge).await;
+	wait_until(|| h.homeserver.sends().len() == 1).await;
+	let private = mx_event(
+		"$m2:example.org",
+		"m.room.message",
+		json!({ "msgtype": "m.text", "body": "private" }),
+	);
+	send_mx_event(&h, &private).await;
+	send_mx_event(&h, &no_bridge).await;
+	wait_until(|| h.homeserver.sends().len() == 2).await;
+	let event = mx_event(
+		"$m3:example.org",
+		"m.room.message",
+		json!({ "msgtype": "m.text", "body": "public" }),
+	);
+	send_mx_event(&h, &event).await;
+	wait_until(|| mx_bridged(&h, "$m3:example.org")).await;
+
+	assert!(!mx_bridged(&h, "$m2:example.org"));
+	let sends = h.homeserver.sends();
+	assert_eq!(sends[0].content["msgtype"], "m.notice");
+	assert!(sends[0].content["body"].as_str().unwrap().contains("won't be bridged"));
+	assert!(sends[1].content["body"].as_str().unwrap().contains("bridged again"));
+	let requests = h.bot_api.requests("sendMessage");
+	assert_eq!(requests.len(), 1);
+	assert_eq!(requests[0].params["text"], "@bob:example.org: public");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn portal_room_is_created_when_bot_is_added() {
+	let h = Harness::new().await;
+	h.bot_api.add_file("chat_photo", b"jpeg");
+	let chat_id = -1009876543210i64;
+	let bot = json!({ "id": 42, "is_bot": true, "first_name": "bridge" });
+	let update: ChatMemberUpdated = serde_json::from_value(json!({
+		"chat": { "id": chat_id, "type": "supergroup", "title": "new group" },
+		"from": { "id": 1111, "is_bot": false, "first_name": "Alice" },
+		"date": 1_700_000_000,
+		"old_chat_member": { "user": bot, "status": "left" },
+		"new_chat_member": { "user": bot, "status": "member" },
+	}))
+	.unwrap();
+	tg_member_update(update.clone(), h.ctx.clone()).await.unwrap();
+	tg_member_update(update, h.ctx.clone()).await.unwrap();
+
+	let created = h.homeserver.created_rooms();
+	assert_eq!(created.len(), 1);
+	assert_eq!(created[0]["name"], "new group");
+	assert_eq!(created[0]["invite"], json!([ADMIN_USER_ID]));
+	assert_eq!(created[0]["power_level_content_override"]["users"][ADMIN_USER_ID], 100);
+	let portal_id = "!portal1:fake.server";
+	let state_events = h.homeserver.state_events();
+	assert_eq!(state_events.len(), 1);
+	assert_eq!(state_events[0].room_id, portal_id);
+	assert_eq!(state_events[0].event_type, "m.room.avatar");
+	let bridge = h.ctx.bridge_by_tg(ChatId(chat_id)).unwrap();
+	assert_eq!(bridge.mx_id, portal_id);
+	assert_eq!(h.ctx.store.get_portals().len(), 1);
+	let requests = h.bot_api.requests("sendMessage");
+	assert_eq!(requests[0].params["text"], format!("bridged to matrix room {portal_id}"));
+
+	let mut msg = json!({ "text": "hello portal" });
+	msg["chat"] = json!({ "id": chat_id, "type": "supergroup", "title": "new group" });
+	tg_to_mx(tg_message(10, msg), h.ctx.clone()).await.unwrap();
+	wait_until(|| !h.homeserver.sends().is_empty()).await;
+	assert_eq!(h.homeserver.sends()[0].room_id, portal_id);
+}
+
+fn bot_added(chat: serde_json::Value, from_id: u64) -> ChatMemberUpdated {
+	let bot = json!({ "id": 42, "is_bot": true, "first_name": "bridge" });
+	serde_json::from_value(json!({
+		"chat": chat,
+		"from": { "id": from_id, "is_bot": false, "first_name": "Mallory" },
+		"date": 1_700_000_000,
+		"old_chat_member": { "user": bot, "status": "left" },
+		"new_chat_member": { "user": bot, "status": "member" },
+	}))
+	.unwrap()
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn portals_are_only_created_by_allowed_users() {
+	let h = Harness::new().await;
+	let chat = json!({ "id": -1009876543210i64, "type": "supergroup", "title": "spam" });
+	tg_member_update(bot_added(chat, 2222), h.ctx.clone()).await.unwrap();
+
+	assert!(h.homeserver.created_rooms().is_empty());
+	assert!(h.ctx.store.get_portals().is_empty());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn portal_follows_group_upgraded_to_supergroup() {
+	let h = Harness::new().await;
+	let (group_id, supergroup_id) = (-4001i64, -1004001i64);
+	let group = json!({ "id": group_id, "type": "group", "title": "small group" });
+	tg_member_update(bot_added(group.clone(), TG_ADMIN_ID), h.ctx.clone()).await.unwrap();
+	let portal_id = h.ctx.bridge_by_tg(ChatId(group_id)).unwrap().mx_id.clone();
+
+	let mut migration = json!({ "migrate_to_chat_id": supergroup_id });
+	migration["chat"] = group;
+	tg_to_mx(tg_message(5, migration), h.ctx.clone()).await.unwrap();
+
+	assert!(h.ctx.bridge_by_tg(ChatId(group_id)).is_none());
+	assert_eq!(h.ctx.bridge_by_tg(ChatId(supergroup_id)).unwrap().mx_id, portal_id);
+	let portals = h.ctx.store.get_portals();
+	assert_eq!(portals.len(), 1);
+	assert_eq!(portals[0].tg_id, supergroup_id);
+
+	let mut msg = json!({ "text": "hello again" });
+	msg["chat"] = json!({ "id": supergroup_id, "type": "supergroup", "title": "small group" });
+	tg_to_mx(tg_message(1, msg), h.ctx.clone()).await.unwrap();
+	wait_until(|| !h.homeserver.sends().is_empty()).await;
+	assert_eq!(h.homeserver.sends()[0].room_id, portal_id);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn verify_command_is_only_answered_for_admins() {
+	let h = Harness::new().await;
+	let body = json!({ "msgtype": "m.text", "body": "!verify" });
+	let mut admin = mx_event("$m1:example.org", "m.room.message", body.clone());
+	admin["sender"] = json!(ADMIN_USER_ID);
+	send_mx_event(&h, &admin).await;
+	send_mx_event(&h, &mx_event("$m2:example.org", "m.room.message", body)).await;
+	wait_until(|| mx_bridged(&h, "$m2:example.org")).await;
+
+	let sends = h.homeserver.sends();
+	assert_eq!(sends.len(), 1);
+	assert_eq!(sends[0].content["msgtype"], "m.notice");
+	assert!(sends[0].content["body"].as_str().unwrap().starts_with("no verification is waiting"));
+	assert!(!mx_bridged(&h, "$m1:example.org"));
+	assert_eq!(h.bot_api.requests("sendMessage").len(), 1);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn verify_command_needs_the_exact_word() {
+	let h = Harness::new().await;
+	let body = json!({ "msgtype": "m.text", "body": "!verifying the backups" });
+	let mut admin = mx_event("$m1:example.org", "m.room.message", body);
+	admin["sender"] = json!(ADMIN_USER_ID);
+	send_mx_event(&h, &admin).await;
+	wait_until(|| mx_bridged(&h, "$m1:example.org")).await;
+
+	assert!(h.homeserver.sends().is_empty());
+}
+
+fn sticker_json(file_id: &str, emoji: &str) -> serde_json::Value {
+	json!({
+		"file_id": file_id,
+		"file_unique_id": format!("u_{file_id}"),
+		"type": "regular",
+		"width": 1,
+		"height": 1,
+		"is_animated": false,
+		"is_video": false,
+		"emoji": emoji,
+	})
+}
+
+fn stickerpack_command(sender: &str) -> serde_json::Value {
+	let mut event = mx_event(
+		"$s1:example.org",
+		"m.room.message",
+		json!({ "msgtype": "m.text", "body": "!stickerpack cats" }),
+	);
+	event["sender"] = json!(sender);
+	event
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn stickerpack_import_skips_broken_stickers() {
+	let h = Harness::new().await;
+	let mut png = std::io::Cursor::new(vec![]);
+	image::RgbaImage::new(1, 1).write_to(&mut png, image::ImageFormat::Png).unwrap();
+	h.bot_api.add_file("cat", &png.into_inner());
+	h.bot_api.add_file("broken", b"not an image");
+	h.bot_api.add_sticker_set(json!({
+		"name": "cats",
+		"title": "Cats",
+		"sticker_type": "regular",
+		"stickers": [sticker_json("broken", "😿"), sticker_json("cat", "🐱")],
+	}));
+	send_mx_event(&h, &stickerpack_command(MODERATOR_USER_ID)).await;
+	wait_until(|| !h.homeserver.sends().is_empty()).await;
+
+	assert_eq!(h.homeserver.sends()[0].content["body"], "imported 1 stickers from cats");
+	let state = h.homeserver.state_events();
+	let pack = state.iter().find(|e| e.event_type == "im.ponies.room_emotes").unwrap();
+	assert_eq!(pack.state_key, "cats");
+	let images = pack.content["images"].as_object().unwrap();
+	assert_eq!(images.keys().collect::<Vec<_>>(), ["🐱"]);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn stickerpack_import_needs_permission() {
+	let h = Harness::new().await;
+	send_mx_event(&h, &stickerpack_command("@bob:example.org")).await;
+	wait_until(|| !h.homeserver.sends().is_empty()).await;
+
+	let sends = h.homeserver.sends();
+	assert_eq!(sends[0].content["body"], "only room moderators can import sticker packs");
+	assert!(h.bot_api.requests("getStickerSet").is_empty());
+
+	// bridge admins aren't room moderators
+	send_mx_event(&h, &stickerpack_command(ADMIN_USER_ID)).await;
+	wait_until(|| h.homeserver.sends().len() == 2).await;
+	assert!(h.bot_api.requests("getStickerSet").is_empty());
+
+	send_mx_event(&h, &stickerpack_command(MODERATOR_USER_ID)).await;
+	wait_until(|| h.homeserver.sends().len() == 3).await;
+	assert_eq!(h.bot_api.requests("getStickerSet").len(), 1);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn own_matrix_events_are_ignored() {
+	let h = Harness::new().await;
+	let mut own = mx_event(
+		"$own:fake.server",
+		"m.room.message",
+		json!({ "msgtype": "m.text", "body": "echo" }),
+	);
+	own["sender"] = json!(BRIDGE_USER_ID);
+	send_mx_event(&h, &own).await;
+	let event = mx_event(
+		"$m1:example.org",
+		"m.room.message",
+		json!({ "msgtype": "m.text", "body": "real" }),
+	);
+	send_mx_event(&h, &event).await;
+	wait_until(|| mx_bridged(&h, "$m1:example.org")).await;
+
+	assert_eq!(h.bot_api.requests("sendMessage").len(), 1);
+	assert!(!mx_bridged(&h, "$own:fake.server"));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn telegram_api_failure_does_not_stop_the_queue() {
+	let h = Harness::new().await;
+	h.bot_api.fail_next(
+		"sendMessage",
+		StatusCode::BAD_REQUEST,
+		json!({ "ok": false, "error_code": 400, "description": "Bad Request: chat not found" }),
+	);
+	for (event_id, body) in [("$m1:example.org", "lost"), ("$m2:example.org", "delivered")] {
+		let content = json!({ "msgtype": "m.text", "body": body });
+		send_mx_event(&h, &mx_event(event_id, "m.room.message", content)).await;
+	}
+	wait_until(|| mx_bridged(&h, "$m2:example.org")).await;
+
+	assert_eq!(h.bot_api.requests("sendMessage").len(), 2);
+	assert!(!mx_bridged(&h, "$m1:example.org"));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn matrix_send_failure_does_not_stop_the_queue() {
+	let h = Harness::new().await;
+	h.homeserver.fail_next_send(
+		StatusCode::FORBIDDEN,
+		json!({ "errcode": "M_FORBIDDEN", "error": "not allowed" }),
+	);
+	tg_to_mx(tg_message(10, json!({ "text": "lost" })), h.ctx.clone()).await.unwrap();
+	tg_to_mx(tg_message(11, json!({ "text": "delivered" })), h.ctx.clone()).await.unwrap();
+	wait_until(|| tg_bridged(&h, 11)).await;
+
+	assert_eq!(h.homeserver.sends().len(), 1);
+	assert!(!tg_bridged(&h, 10));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn telegram_rate_limit_is_retried() {
+	let h = Harness::new().await;
+	h.bot_api.fail_next(
+		"sendMessage",
+		StatusCode::TOO_MANY_REQUESTS,
+		json!({
+			"ok": false,
+			"error_code": 429,
+			"description": "Too Many Requests: retry after 1",
+			"parameters": { "retry_after": 1 },
+		}),
+	);
+	let event = mx_event(
+		"$m1:example.org",
+		"m.room.message",
+		json!({ "msgtype": "m.text", "body": "eventually" }),
+	);
+	send_mx_event(&h, &event).await;
+	wait_until(|| mx_bridged(&h, "$m1:example.org")).await;
+
+	assert_eq!(h.bot_api.requests("sendMessage").len(), 2);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn matrix_rate_limit_is_retried_with_same_transaction() {
+	let h = Harness::new().await;
+	h.homeserver.fail_next_send(
+		StatusCode::TOO_MANY_REQUESTS,
+		json!({ "errcode": "M_LIMIT_EXCEEDED", "error": "slow down", "retry_after_ms": 100 }),
+	);
+	tg_to_mx(tg_message(10, json!({ "text": "eventually" })), h.ctx.clone()).await.unwrap();
+	wait_until(|| tg_bridged(&h, 10)).await;
+
+	let attempts = h.homeserver.send_attempts();
+	assert_eq!(attempts.len(), 2);
+	assert_eq!(attempts[0], attempts[1]);
+	assert_eq!(h.homeserver.sends().len(), 1);
+}
@@ -0,0 +1,122 @@
+use std::fs;
+use std::path::Path;
+use std::path::PathBuf;
+
+use matrix_sdk::ruma::events::room::message::OriginalRoomMessageEvent;
+use matrix_sdk::ruma::events::AnyMessageLikeEvent;
+use matrix_sdk::ruma::owned_mxc_uri;
+use serde_json::json;
+use serde_json::Value;
+use teloxide::types::Message;
+
+use tg_matrix_bridge::bridge_utils::get_tg_sender;
+use tg_matrix_bridge::convert::get_tg_upload;
+use tg_matrix_bridge::convert::mx_to_outgoing;
+use tg_matrix_bridge::convert::tg_has_spoiler;
+use tg_matrix_bridge::convert::tg_poll_content;
+use tg_matrix_bridge::convert::tg_preview_url;
+use tg_matrix_bridge::convert::tg_to_mx_content;
+use tg_matrix_bridge::templates::SenderTemplates;
+
+// run with UPDATE_GOLDEN=1 to rewrite tests/golden after an intended change
+fn fixtures(kind: &str) -> Vec<PathBuf> {
+	let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures").join(kind);
+	let mut paths = fs::read_dir(dir)
+		.unwrap()
+		.map(|entry| entry.unwrap().path())
+		.filter(|path| path.extension().is_some_and(|ext| ext == "json"))
+		.collect::<Vec<PathBuf>>();
+	paths.sort();
+	paths
+}
+
+fn read_json(path: &Path) -> Value {
+	serde_json::from_str(&fs::read_to_string(path).unwrap()).unwrap()
+}
+
+fn check_golden(kind: &str, fixture: &Path, output: &Value) -> Option<String> {
+	let golden = Path::new(env!("CARGO_MANIFEST_DIR"))
+		.join("tests/golden")
+		.join(kind)
+		.join(fixture.file_name().unwrap());
+	let output = format!("{}\n", serde_json::to_string_pretty(output).unwrap());
+	if std::env::var_os("UPDATE_GOLDEN").is_some() {
+		fs::write(&golden, output).unwrap();
+		return None;
+	}
+	match fs::read_to_string(&golden) {
+		Ok(expected) if expected == output => None,
+		Ok(expected) => {
+			Some(format!("{}:\nexpected:\n{expected}\ngot:\n{output}", golden.display()))
+		}
+		Err(e) => Some(format!("{}: {e}", golden.display())),
+	}
+}
+
+// replies are to the matrix text fixture unless the fixture name says they aren't bridged
+fn tg_to_mx_output(msg: &Message, reply_bridged: bool) -> anyhow::Result<Value> {
+	let sender = get_tg_sender(msg)?;
+	let templates = SenderTemplates::default();
+	if let Some(poll) = msg.poll() {
+		return Ok(json!({ "poll": tg_poll_content(poll, &sender, &templates)? }));
+	}
+	let mxc_uri = get_tg_upload(msg)?.map(|_| owned_mxc_uri!("mxc://example.org/upload"));
+	let reply_to = match msg.reply_to_message() {
+		Some(_) if reply_bridged => {
+			let reply_fixture =
+				Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/matrix/text.json");
+			Some(serde_json::from_value::<OriginalRoomMessageEvent>(read_json(&reply_fixture))?)
+		}
+		_ => None,
+	};
+	let content = tg_to_mx_content(msg, &sender, &templates, mxc_uri, reply_to.as_ref())?;
+	Ok(json!({
+		"content": content,
+		"has_spoiler": tg_has_spoiler(msg),
+		"preview_url": tg_preview_url(msg),
+	}))
+}
+
+fn mx_to_tg_output(raw_event: &Value) -> anyhow::Result<Value> {
+	let event = serde_json::from_value::<AnyMessageLikeEvent>(raw_event.clone())?;
+	let content = event.original_content().unwrap();
+	let tg_data = mx_to_outgoing(&content, raw_event)?;
+	Ok(json!({
+		"kind": tg_data.kind.map(|kind| format!("{kind:?}")),
+		"text": String::from_utf8_lossy(&tg_data.message),
+		"caption": tg_data.caption,
+		"source": tg_data.source,
+		"mxc_uri": tg_data.mxc_uri,
+		"location": tg_data.location,
+		"reply_to": tg_data.reply_to,
+		"has_spoiler": tg_data.has_spoiler,
+		"spoilers": tg_data.spoilers,
+		"is_preview_disabled": tg_data.is_preview_disabled,
+	}))
+}
+
+fn error_output(e: &anyhow::Error) -> Value {
+	json!({ "error": e.to_string() })
+}
+
+#[test]
+fn telegram_to_matrix_golden() {
+	let mut failures = vec![];
+	for fixture in fixtures("telegram") {
+		let msg = serde_json::from_value::<Message>(read_json(&fixture)).unwrap();
+		let reply_bridged = !fixture.to_string_lossy().contains("unbridged_reply");
+		let output = tg_to_mx_output(&msg, reply_bridged).unwrap_or_else(|e| error_output(&e));
+		failures.extend(check_golden("telegram", &fixture, &output));
+	}
+	assert!(failures.is_empty(), "{}", failures.join("\n"));
+}
+
+#[test]
+fn matrix_to_telegram_golden() {
+	let mut failures = vec![];
+	for fixture in fixtures("matrix") {
+		let output = mx_to_tg_output(&read_json(&fixture)).unwrap_or_else(|e| error_output(&e));
+		failures.extend(check_golden("matrix", &fixture, &output));
+	}
+	assert!(failures.is_empty(), "{}", failures.join("\n"));
+}
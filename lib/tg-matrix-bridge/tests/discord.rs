@@ -0,0 +1,362 @@
+mod harness;
+
+use matrix_sdk::ruma::EventId;
+use serde_json::json;
+use serde_json::Value;
+
+use harness::discord::APPLICATION_ID;
+use harness::discord::CHANNEL_ID;
+use harness::discord::GUILD_ID;
+use harness::mx_bridged;
+use harness::mx_event;
+use harness::send_mx_event;
+use harness::wait_until;
+use harness::FakeDiscord;
+use harness::Harness;
+use harness::ROOM_ID;
+use tg_matrix_bridge::discord::DiscordNetwork;
+
+fn discord(h: &Harness) -> &FakeDiscord {
+	h.discord.as_ref().unwrap()
+}
+
+fn message_id(id: &str) -> (String, String) {
+	(CHANNEL_ID.to_string(), id.to_string())
+}
+
+fn discord_bridged(h: &Harness, id: &str) -> bool {
+	h.ctx.store.find_mx_event_for::<DiscordNetwork>(ROOM_ID, &message_id(id)).is_some()
+}
+
+fn discord_message(id: &str, fields: Value) -> Value {
+	let mut message = json!({
+		"id": id,
+		"channel_id": CHANNEL_ID,
+		"guild_id": GUILD_ID,
+		"author": { "id": "8001", "username": "alice", "global_name": "Alice" },
+		"member": { "nick": null },
+		"content": "",
+		"attachments": [],
+	});
+	for (key, value) in fields.as_object().unwrap() {
+		message[key] = value.clone();
+	}
+	message
+}
+
+fn map_original(h: &Harness) {
+	let original = mx_event(
+		"$original:example.org",
+		"m.room.message",
+		json!({ "msgtype": "m.text", "body": "original" }),
+	);
+	h.homeserver.add_event(original);
+	h.ctx
+		.store
+		.update_remote_messages::<DiscordNetwork>(
+			EventId::parse("$original:example.org").unwrap(),
+			message_id("7000"),
+			ROOM_ID,
+		)
+		.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn discord_text_reaches_matrix() {
+	let h = Harness::with_discord().await;
+	let message = discord_message("7001", json!({ "content": "hello matrix" }));
+	discord(&h).dispatch("MESSAGE_CREATE", message);
+	wait_until(|| discord_bridged(&h, "7001")).await;
+
+	let sends = h.homeserver.sends();
+	assert_eq!(sends.len(), 1);
+	assert_eq!(sends[0].content["body"], "Alice: hello matrix");
+	let identify = discord(&h).identify().unwrap();
+	assert_eq!(identify["token"], "discord_token");
+	assert_eq!(identify["intents"], (1 << 9) | (1 << 10) | (1 << 15));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn discord_attachment_reply_reaches_matrix() {
+	let h = Harness::with_discord().await;
+	map_original(&h);
+	let url = discord(&h).add_attachment("cat.png", b"png bytes");
+	let attachment = json!({
+		"id": "9001",
+		"filename": "cat.png",
+		"content_type": "image/png",
+		"size": 9,
+		"url": url,
+	});
+	let message = discord_message(
+		"7001",
+		json!({
+			"content": "look",
+			"attachments": [attachment],
+			"message_reference": { "message_id": "7000", "channel_id": CHANNEL_ID },
+		}),
+	);
+	discord(&h).dispatch("MESSAGE_CREATE", message);
+	wait_until(|| discord_bridged(&h, "7001")).await;
+
+	let content = &h.homeserver.sends()[0].content;
+	assert_eq!(content["msgtype"], "m.image");
+	assert_eq!(content["body"], "(from Alice)\nlook");
+	assert_eq!(content["filename"], "cat.png");
+	assert!(content["url"].as_str().unwrap().starts_with("mxc://fake.server/"));
+	assert_eq!(content["m.relates_to"]["m.in_reply_to"]["event_id"], "$original:example.org");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn discord_attachment_without_text_is_attributed() {
+	let h = Harness::with_discord().await;
+	let url = discord(&h).add_attachment("cat.png", b"png bytes");
+	let attachment = json!({
+		"id": "9001",
+		"filename": "cat.png",
+		"content_type": "image/png",
+		"size": 9,
+		"url": url,
+	});
+	let message = discord_message("7001", json!({ "attachments": [attachment] }));
+	discord(&h).dispatch("MESSAGE_CREATE", message);
+	wait_until(|| discord_bridged(&h, "7001")).await;
+
+	let sends = h.homeserver.sends();
+	assert_eq!(sends[0].content["body"], "(from Alice)");
+	assert_eq!(sends[0].content["filename"], "cat.png");
+	assert_eq!(sends[0].txn_id, "discord___5000___7001__");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn discord_edit_and_reaction_reach_matrix() {
+	let h = Harness::with_discord().await;
+	let discord = discord(&h);
+	discord.dispatch("MESSAGE_CREATE", discord_message("7001", json!({ "content": "tpyo" })));
+	discord.dispatch("MESSAGE_UPDATE", discord_message("7001", json!({ "content": "typo" })));
+	let reaction = json!({
+		"user_id": "8001",
+		"channel_id": CHANNEL_ID,
+		"message_id": "7001",
+		"emoji": { "id": null, "name": "👍" },
+	});
+	discord.dispatch("MESSAGE_REACTION_ADD", reaction);
+	wait_until(|| h.homeserver.sends().len() == 3).await;
+
+	let sends = h.homeserver.sends();
+	assert_eq!(sends[1].content["m.relates_to"]["rel_type"], "m.replace");
+	assert_eq!(sends[1].content["m.relates_to"]["event_id"], sends[0].event_id);
+	assert_eq!(sends[1].content["m.new_content"]["body"], "Alice: typo");
+	assert_eq!(sends[2].event_type, "m.reaction");
+	assert_eq!(sends[2].content["m.relates_to"]["key"], "👍");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn own_discord_messages_are_ignored() {
+	let h = Harness::with_discord().await;
+	let echo = discord_message(
+		"7001",
+		json!({
+			"content": "from matrix",
+			"webhook_id": "4300",
+			"application_id": APPLICATION_ID,
+			"author": { "id": "4300", "username": "bob" },
+		}),
+	);
+	discord(&h).dispatch("MESSAGE_CREATE", echo);
+	discord(&h).dispatch("MESSAGE_CREATE", discord_message("7002", json!({ "content": "hi" })));
+	wait_until(|| discord_bridged(&h, "7002")).await;
+
+	assert_eq!(h.homeserver.sends().len(), 1);
+	assert!(!discord_bridged(&h, "7001"));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn opted_out_discord_users_are_not_bridged() {
+	let h = Harness::with_discord().await;
+	h.ctx.store.toggle_opted_out("discord:8001").unwrap();
+	let carol = json!({ "id": "8002", "username": "carol", "global_name": "Carol" });
+	discord(&h).dispatch("MESSAGE_CREATE", discord_message("7001", json!({ "content": "secret" })));
+	let message = discord_message("7002", json!({ "content": "hi", "author": carol }));
+	discord(&h).dispatch("MESSAGE_CREATE", message);
+	wait_until(|| discord_bridged(&h, "7002")).await;
+
+	assert_eq!(h.homeserver.sends().len(), 1);
+	assert!(!discord_bridged(&h, "7001"));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn nobridge_works_in_discord_rooms() {
+	let h = Harness::with_discord().await;
+	let no_bridge = mx_event(
+		"$m1:example.org",
+		"m.room.message",
+		json!({ "msgtype": "m.text", "body": "!nobridge" }),
+	);
+	send_mx_event(&h, &no_bridge).await;
+	wait_until(|| h.homeserver.sends().len() == 1).await;
+	let private = mx_event(
+		"$m2:example.org",
+		"m.room.message",
+		json!({ "msgtype": "m.text", "body": "private" }),
+	);
+	send_mx_event(&h, &private).await;
+	send_mx_event(&h, &no_bridge).await;
+	wait_until(|| h.homeserver.sends().len() == 2).await;
+	let event = mx_event(
+		"$m3:example.org",
+		"m.room.message",
+		json!({ "msgtype": "m.text", "body": "public" }),
+	);
+	send_mx_event(&h, &event).await;
+	wait_until(|| mx_bridged(&h, "$m3:example.org")).await;
+
+	assert!(!mx_bridged(&h, "$m1:example.org"));
+	assert!(!mx_bridged(&h, "$m2:example.org"));
+	let sends = h.homeserver.sends();
+	assert!(sends[0].content["body"].as_str().unwrap().contains("won't be bridged"));
+	assert!(sends[1].content["body"].as_str().unwrap().contains("bridged again"));
+	let sent = discord(&h).requests("POST", "/webhooks/");
+	assert_eq!(sent.len(), 1);
+	assert_eq!(sent[0].body["content"], "public");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn matrix_text_reaches_discord_through_webhook() {
+	let h = Harness::with_discord().await;
+	let event = mx_event(
+		"$m1:example.org",
+		"m.room.message",
+		json!({ "msgtype": "m.text", "body": "hello discord" }),
+	);
+	send_mx_event(&h, &event).await;
+	wait_until(|| mx_bridged(&h, "$m1:example.org")).await;
+
+	let discord = discord(&h);
+	let created = discord.requests("POST", &format!("/channels/{CHANNEL_ID}/webhooks"));
+	assert_eq!(created.len(), 1);
+	assert_eq!(created[0].authorization.as_deref(), Some("Bot discord_token"));
+	let sent = discord.requests("POST", "/webhooks/");
+	assert_eq!(sent.len(), 1);
+	assert_eq!(sent[0].path, "/webhooks/4300/webhook_token");
+	assert_eq!(sent[0].body["content"], "hello discord");
+	assert_eq!(sent[0].body["username"], "bob example.org");
+	assert_eq!(sent[0].body["allowed_mentions"], json!({ "parse": [] }));
+
+	let second = mx_event(
+		"$m2:example.org",
+		"m.room.message",
+		json!({ "msgtype": "m.text", "body": "again" }),
+	);
+	send_mx_event(&h, &second).await;
+	wait_until(|| mx_bridged(&h, "$m2:example.org")).await;
+	assert_eq!(discord.requests("GET", "/channels/").len(), 1);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn matrix_image_reaches_discord_as_attachment() {
+	let h = Harness::with_discord().await;
+	h.homeserver.add_media("mxc://example.org/cat", b"png bytes");
+	let event = mx_event(
+		"$m1:example.org",
+		"m.room.message",
+		json!({ "msgtype": "m.image", "body": "cat.png", "url": "mxc://example.org/cat" }),
+	);
+	send_mx_event(&h, &event).await;
+	wait_until(|| mx_bridged(&h, "$m1:example.org")).await;
+
+	let sent = discord(&h).requests("POST", "/webhooks/");
+	assert_eq!(sent[0].body["files[0]"], "<file 9 bytes>");
+	assert_eq!(sent[0].body["payload_json"]["content"], "");
+	assert_eq!(sent[0].body["payload_json"]["username"], "bob example.org");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn matrix_reply_links_discord_message() {
+	let h = Harness::with_discord().await;
+	map_original(&h);
+	let event = mx_event(
+		"$m1:example.org",
+		"m.room.message",
+		json!({
+			"msgtype": "m.text",
+			"body": "agreed",
+			"m.relates_to": { "m.in_reply_to": { "event_id": "$original:example.org" } },
+		}),
+	);
+	send_mx_event(&h, &event).await;
+	wait_until(|| mx_bridged(&h, "$m1:example.org")).await;
+
+	let sent = discord(&h).requests("POST", "/webhooks/");
+	let link = format!("https://discord.com/channels/{GUILD_ID}/{CHANNEL_ID}/7000");
+	assert_eq!(sent[0].body["content"], format!("> ↪ {link}\nagreed"));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn matrix_edit_redaction_and_reaction_reach_discord() {
+	let h = Harness::with_discord().await;
+	let original = mx_event(
+		"$m1:example.org",
+		"m.room.message",
+		json!({ "msgtype": "m.text", "body": "tpyo" }),
+	);
+	send_mx_event(&h, &original).await;
+	wait_until(|| mx_bridged(&h, "$m1:example.org")).await;
+
+	let edit = mx_event(
+		"$m2:example.org",
+		"m.room.message",
+		json!({
+			"msgtype": "m.text",
+			"body": "* typo",
+			"m.new_content": { "msgtype": "m.text", "body": "typo" },
+			"m.relates_to": { "rel_type": "m.replace", "event_id": "$m1:example.org" },
+		}),
+	);
+	send_mx_event(&h, &edit).await;
+	let reaction = mx_event(
+		"$m3:example.org",
+		"m.reaction",
+		json!({
+			"m.relates_to": { "rel_type": "m.annotation", "event_id": "$m1:example.org", "key": "👍" },
+		}),
+	);
+	send_mx_event(&h, &reaction).await;
+	let mut redaction =
+		mx_event("$m4:example.org", "m.room.redaction", json!({ "redacts": "$m1:example.org" }));
+	redaction["redacts"] = json!("$m1:example.org");
+	send_mx_event(&h, &redaction).await;
+	let discord = discord(&h);
+	wait_until(|| !discord.requests("DELETE", "/webhooks/").is_empty()).await;
+
+	let (_, id) = h
+		.ctx
+		.store
+		.find_remote_id::<DiscordNetwork>(ROOM_ID, &EventId::parse("$m1:example.org").unwrap())
+		.unwrap();
+	let edits = discord.requests("PATCH", "/webhooks/");
+	assert_eq!(edits[0].path, format!("/webhooks/4300/webhook_token/messages/{id}"));
+	assert_eq!(edits[0].body["content"], "typo");
+	let reactions = discord.requests("PUT", "/channels/");
+	assert_eq!(
+		reactions[0].path,
+		format!("/channels/{CHANNEL_ID}/messages/{id}/reactions/%F0%9F%91%8D/@me")
+	);
+	let deletes = discord.requests("DELETE", "/webhooks/");
+	assert_eq!(deletes[0].path, edits[0].path);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn discord_rate_limit_is_retried() {
+	let h = Harness::with_discord().await;
+	discord(&h).rate_limit_next();
+	let event = mx_event(
+		"$m1:example.org",
+		"m.room.message",
+		json!({ "msgtype": "m.text", "body": "eventually" }),
+	);
+	send_mx_event(&h, &event).await;
+	wait_until(|| mx_bridged(&h, "$m1:example.org")).await;
+
+	assert_eq!(discord(&h).requests("POST", "/webhooks/").len(), 2);
+}
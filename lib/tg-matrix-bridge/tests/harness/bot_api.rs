@@ -0,0 +1,243 @@
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+use axum::body::Bytes;
+use axum::extract::Path;
+use axum::extract::State;
+use axum::http::header::CONTENT_TYPE;
+use axum::http::HeaderMap;
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use axum::response::Response;
+use axum::routing::get;
+use axum::routing::post;
+use axum::Json;
+use axum::Router;
+use serde_json::json;
+use serde_json::Value;
+
+#[derive(Clone, Debug)]
+pub struct BotApiRequest {
+	pub method: String,
+	pub params: Value,
+}
+
+#[derive(Default)]
+struct BotApiState {
+	requests: Vec<BotApiRequest>,
+	failures: HashMap<String, VecDeque<(StatusCode, Value)>>,
+	files: HashMap<String, Vec<u8>>,
+	channels: HashSet<i64>,
+	sticker_sets: HashMap<String, Value>,
+	next_message_id: i32,
+}
+
+type SharedState = Arc<Mutex<BotApiState>>;
+
+#[derive(Clone)]
+pub struct FakeBotApi {
+	pub url: url::Url,
+	state: SharedState,
+}
+
+impl FakeBotApi {
+	pub async fn start() -> Self {
+		let state = Arc::new(Mutex::new(BotApiState {
+			next_message_id: 1000,
+			..Default::default()
+		}));
+		let app = Router::new()
+			.route("/:bot_token/:method", post(handle_method))
+			.route("/file/:bot_token/*path", get(handle_file))
+			.with_state(state.clone());
+		let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let url = format!("http://{}/", listener.local_addr().unwrap()).parse().unwrap();
+		tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+		Self {
+			url,
+			state,
+		}
+	}
+
+	pub fn add_file(&self, file_id: &str, data: &[u8]) {
+		self.state.lock().unwrap().files.insert(file_id.to_string(), data.to_vec());
+	}
+
+	pub fn add_sticker_set(&self, sticker_set: Value) {
+		let name = sticker_set["name"].as_str().unwrap().to_string();
+		self.state.lock().unwrap().sticker_sets.insert(name, sticker_set);
+	}
+
+	pub fn add_channel(&self, chat_id: i64) {
+		self.state.lock().unwrap().channels.insert(chat_id);
+	}
+
+	pub fn fail_next(&self, method: &str, status: StatusCode, body: Value) {
+		let mut state = self.state.lock().unwrap();
+		state.failures.entry(method.to_lowercase()).or_default().push_back((status, body));
+	}
+
+	pub fn requests(&self, method: &str) -> Vec<BotApiRequest> {
+		let state = self.state.lock().unwrap();
+		state.requests.iter().filter(|r| r.method.eq_ignore_ascii_case(method)).cloned().collect()
+	}
+}
+
+pub fn parse_multipart(content_type: &str, body: &[u8]) -> Value {
+	let Some(boundary) = content_type.split("boundary=").nth(1) else {
+		return Value::Null;
+	};
+	let body = String::from_utf8_lossy(body);
+	let mut params = serde_json::Map::new();
+	for part in body.split(&format!("--{boundary}")) {
+		let Some((headers, content)) = part.split_once("\r\n\r\n") else {
+			continue;
+		};
+		let Some(name) = headers.split("name=\"").nth(1).and_then(|s| s.split('"').next()) else {
+			continue;
+		};
+		let content = content.strip_suffix("\r\n").unwrap_or(content);
+		let value = if headers.contains("filename=") {
+			json!(format!("<file {} bytes>", content.len()))
+		} else {
+			serde_json::from_str(content).unwrap_or_else(|_| json!(content))
+		};
+		params.insert(name.to_string(), value);
+	}
+	let attachments = params.clone();
+	for value in params.values_mut() {
+		let attachment = value.as_str().and_then(|v| v.strip_prefix("attach://"));
+		if let Some(attachment) = attachment.and_then(|name| attachments.get(name)) {
+			*value = attachment.clone();
+		}
+	}
+	Value::Object(params)
+}
+
+fn parse_params(headers: &HeaderMap, body: &[u8]) -> Value {
+	let content_type = headers.get(CONTENT_TYPE).and_then(|v| v.to_str().ok()).unwrap_or_default();
+	if content_type.starts_with("multipart/form-data") {
+		parse_multipart(content_type, body)
+	} else {
+		serde_json::from_slice(body).unwrap_or(Value::Null)
+	}
+}
+
+fn chat_id(params: &Value) -> i64 {
+	match &params["chat_id"] {
+		Value::Number(n) => n.as_i64().unwrap_or_default(),
+		Value::String(s) => s.parse().unwrap_or_default(),
+		_ => 0,
+	}
+}
+
+fn poll_json(id: &str, params: &Value, is_closed: bool) -> Value {
+	let options = params["options"]
+		.as_array()
+		.map(|options| {
+			options
+				.iter()
+				.map(|o| json!({ "text": o.as_str().or(o["text"].as_str()), "voter_count": 0 }))
+				.collect::<Vec<Value>>()
+		})
+		.unwrap_or_default();
+	json!({
+		"id": id,
+		"question": params["question"].as_str().unwrap_or("poll"),
+		"options": options,
+		"total_voter_count": 0,
+		"is_closed": is_closed,
+		"is_anonymous": true,
+		"type": "regular",
+		"allows_multiple_answers": params["allows_multiple_answers"].as_bool().unwrap_or(false),
+	})
+}
+
+fn respond(state: &mut BotApiState, method: &str, params: &Value) -> Value {
+	match method.to_lowercase().as_str() {
+		"getfile" => {
+			let file_id = params["file_id"].as_str().unwrap_or_default();
+			let size = state.files.get(file_id).map_or(0, Vec::len);
+			json!({
+				"file_id": file_id,
+				"file_unique_id": format!("u_{file_id}"),
+				"file_size": size,
+				"file_path": format!("files/{file_id}"),
+			})
+		}
+		"stoppoll" => poll_json("stopped", params, true),
+		"getstickerset" => {
+			let name = params["name"].as_str().unwrap_or_default();
+			state.sticker_sets.get(name).cloned().unwrap_or(Value::Null)
+		}
+		"deletemessage" | "setmessagereaction" => json!(true),
+		"getchat" => json!({
+			"id": chat_id(params),
+			"type": if state.channels.contains(&chat_id(params)) { "channel" } else { "supergroup" },
+			"title": "fake chat",
+			"photo": {
+				"small_file_id": "chat_photo_small",
+				"small_file_unique_id": "u_chat_photo_small",
+				"big_file_id": "chat_photo",
+				"big_file_unique_id": "u_chat_photo",
+			},
+			"accent_color_id": 0,
+		}),
+		"getchatmember" => json!({
+			"status": "creator",
+			"user": { "id": params["user_id"], "is_bot": false, "first_name": "Alice" },
+			"is_anonymous": false,
+		}),
+		_ => {
+			state.next_message_id += 1;
+			let mut message = json!({
+				"message_id": state.next_message_id,
+				"date": 1_700_000_000,
+				"chat": { "id": chat_id(params), "type": "supergroup", "title": "fake chat" },
+				"from": { "id": 42, "is_bot": true, "first_name": "bridge" },
+			});
+			if method.eq_ignore_ascii_case("sendpoll") {
+				let poll_id = format!("poll_{}", state.next_message_id);
+				message["poll"] = poll_json(&poll_id, params, false);
+			} else {
+				let text = params["text"].as_str().or(params["caption"].as_str()).unwrap_or("");
+				message["text"] = json!(text);
+			}
+			message
+		}
+	}
+}
+
+async fn handle_method(
+	State(state): State<SharedState>,
+	Path((_bot_token, method)): Path<(String, String)>,
+	headers: HeaderMap,
+	body: Bytes,
+) -> Response {
+	let params = parse_params(&headers, &body);
+	let mut state = state.lock().unwrap();
+	state.requests.push(BotApiRequest {
+		method: method.clone(),
+		params: params.clone(),
+	});
+	let failure = state.failures.get_mut(&method.to_lowercase()).and_then(VecDeque::pop_front);
+	if let Some((status, body)) = failure {
+		return (status, Json(body)).into_response();
+	}
+	let result = respond(&mut state, &method, &params);
+	Json(json!({ "ok": true, "result": result })).into_response()
+}
+
+async fn handle_file(
+	State(state): State<SharedState>,
+	Path((_bot_token, path)): Path<(String, String)>,
+) -> Response {
+	let file_id = path.trim_start_matches("files/");
+	match state.lock().unwrap().files.get(file_id) {
+		Some(data) => data.clone().into_response(),
+		None => StatusCode::NOT_FOUND.into_response(),
+	}
+}
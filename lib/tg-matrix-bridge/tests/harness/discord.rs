@@ -0,0 +1,231 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+use axum::body::Bytes;
+use axum::extract::State;
+use axum::http::header::AUTHORIZATION;
+use axum::http::header::CONTENT_TYPE;
+use axum::http::HeaderMap;
+use axum::http::Method;
+use axum::http::StatusCode;
+use axum::http::Uri;
+use axum::response::IntoResponse;
+use axum::response::Response;
+use axum::Json;
+use axum::Router;
+use futures_util::SinkExt;
+use futures_util::StreamExt;
+use serde_json::json;
+use serde_json::Value;
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio_tungstenite::tungstenite::Message;
+
+use super::bot_api::parse_multipart;
+
+pub const GUILD_ID: &str = "4000";
+pub const CHANNEL_ID: &str = "5000";
+pub const BOT_USER_ID: &str = "4100";
+pub const APPLICATION_ID: &str = "4200";
+pub const WEBHOOK_ID: &str = "4300";
+
+#[derive(Clone, Debug)]
+pub struct DiscordRequest {
+	pub method: String,
+	pub path: String,
+	pub authorization: Option<String>,
+	pub body: Value,
+}
+
+#[derive(Default)]
+struct DiscordState {
+	gateway_url: String,
+	requests: Vec<DiscordRequest>,
+	attachments: HashMap<String, Vec<u8>>,
+	identify: Option<Value>,
+	rate_limits: usize,
+	next_id: u64,
+}
+
+type SharedState = Arc<Mutex<DiscordState>>;
+
+type Dispatches = Arc<tokio::sync::Mutex<mpsc::UnboundedReceiver<Value>>>;
+
+pub struct FakeDiscord {
+	pub url: String,
+	state: SharedState,
+	dispatches: mpsc::UnboundedSender<Value>,
+}
+
+impl FakeDiscord {
+	pub async fn start() -> Self {
+		let gateway = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let state = Arc::new(Mutex::new(DiscordState {
+			gateway_url: format!("ws://{}", gateway.local_addr().unwrap()),
+			next_id: 6000,
+			..Default::default()
+		}));
+		let (dispatches, receiver) = mpsc::unbounded_channel();
+		let receiver: Dispatches = Arc::new(tokio::sync::Mutex::new(receiver));
+		let gateway_state = state.clone();
+		tokio::spawn(async move {
+			while let Ok((stream, _)) = gateway.accept().await {
+				tokio::spawn(gateway_connection(stream, gateway_state.clone(), receiver.clone()));
+			}
+		});
+
+		let app = Router::new().fallback(handle_rest).with_state(state.clone());
+		let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let url = format!("http://{}", listener.local_addr().unwrap());
+		tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+		Self {
+			url,
+			state,
+			dispatches,
+		}
+	}
+
+	pub fn add_attachment(&self, name: &str, data: &[u8]) -> String {
+		self.state.lock().unwrap().attachments.insert(name.to_string(), data.to_vec());
+		format!("{}/attachments/{name}", self.url)
+	}
+
+	pub fn rate_limit_next(&self) {
+		self.state.lock().unwrap().rate_limits += 1;
+	}
+
+	// sends a gateway dispatch event to the connected bridge
+	pub fn dispatch(&self, event_type: &str, data: Value) {
+		self.dispatches.send(json!({ "op": 0, "t": event_type, "d": data })).unwrap();
+	}
+
+	pub fn identify(&self) -> Option<Value> {
+		self.state.lock().unwrap().identify.clone()
+	}
+
+	pub fn requests(&self, method: &str, path_prefix: &str) -> Vec<DiscordRequest> {
+		let state = self.state.lock().unwrap();
+		state
+			.requests
+			.iter()
+			.filter(|r| r.method == method && r.path.starts_with(path_prefix))
+			.cloned()
+			.collect()
+	}
+}
+
+async fn gateway_connection(stream: TcpStream, state: SharedState, dispatches: Dispatches) {
+	let mut socket = tokio_tungstenite::accept_async(stream).await.unwrap();
+	let hello = json!({ "op": 10, "d": { "heartbeat_interval": 45000 } });
+	socket.send(Message::text(hello.to_string())).await.unwrap();
+	while let Some(Ok(message)) = socket.next().await {
+		let Message::Text(text) = message else {
+			continue;
+		};
+		let payload: Value = serde_json::from_str(&text).unwrap();
+		if payload["op"] == 2 {
+			state.lock().unwrap().identify = Some(payload["d"].clone());
+			break;
+		}
+	}
+	let ready = json!({
+		"op": 0,
+		"t": "READY",
+		"s": 1,
+		"d": {
+			"user": { "id": BOT_USER_ID, "username": "bridge" },
+			"application": { "id": APPLICATION_ID },
+		},
+	});
+	socket.send(Message::text(ready.to_string())).await.unwrap();
+	let mut dispatches = dispatches.lock().await;
+	let mut sequence = 1;
+	loop {
+		tokio::select! {
+			dispatch = dispatches.recv() => {
+				let Some(mut dispatch) = dispatch else {
+					return;
+				};
+				sequence += 1;
+				dispatch["s"] = json!(sequence);
+				if socket.send(Message::text(dispatch.to_string())).await.is_err() {
+					return;
+				}
+			}
+			message = socket.next() => {
+				if !matches!(message, Some(Ok(_))) {
+					return;
+				}
+			}
+		}
+	}
+}
+
+fn message_json(id: String, body: &Value) -> Value {
+	json!({
+		"id": id,
+		"channel_id": CHANNEL_ID,
+		"webhook_id": WEBHOOK_ID,
+		"application_id": APPLICATION_ID,
+		"content": body["content"],
+		"author": { "id": WEBHOOK_ID, "username": body["username"] },
+		"attachments": [],
+	})
+}
+
+async fn handle_rest(
+	State(state): State<SharedState>,
+	method: Method,
+	uri: Uri,
+	headers: HeaderMap,
+	body: Bytes,
+) -> Response {
+	let path = uri.path().to_string();
+	let mut state = state.lock().unwrap();
+	if let Some(name) = path.strip_prefix("/attachments/") {
+		return match state.attachments.get(name) {
+			Some(data) => data.clone().into_response(),
+			None => StatusCode::NOT_FOUND.into_response(),
+		};
+	}
+	let content_type = headers.get(CONTENT_TYPE).and_then(|v| v.to_str().ok()).unwrap_or_default();
+	let body = if content_type.starts_with("multipart/form-data") {
+		parse_multipart(content_type, &body)
+	} else {
+		serde_json::from_slice(&body).unwrap_or(Value::Null)
+	};
+	state.requests.push(DiscordRequest {
+		method: method.to_string(),
+		path: path.clone(),
+		authorization: headers.get(AUTHORIZATION).and_then(|v| v.to_str().ok()).map(String::from),
+		body: body.clone(),
+	});
+	if path.starts_with("/webhooks/") && state.rate_limits > 0 {
+		state.rate_limits -= 1;
+		let body = json!({ "message": "rate limited", "retry_after": 0.05, "global": false });
+		return (StatusCode::TOO_MANY_REQUESTS, Json(body)).into_response();
+	}
+	let segments = path.trim_start_matches('/').split('/').collect::<Vec<_>>();
+	match (method.as_str(), segments.as_slice()) {
+		("GET", ["gateway", "bot"]) => Json(json!({ "url": state.gateway_url })).into_response(),
+		("GET", ["channels", _, "webhooks"]) => Json(json!([])).into_response(),
+		("POST", ["channels", channel_id, "webhooks"]) => Json(json!({
+			"id": WEBHOOK_ID,
+			"token": "webhook_token",
+			"guild_id": GUILD_ID,
+			"channel_id": channel_id,
+			"name": body["name"],
+		}))
+		.into_response(),
+		("POST", ["webhooks", _, _]) => {
+			state.next_id += 1;
+			let payload = body.get("payload_json").unwrap_or(&body);
+			Json(message_json(state.next_id.to_string(), payload)).into_response()
+		}
+		("PATCH", ["webhooks", _, _, "messages", message_id]) => {
+			Json(message_json((*message_id).to_string(), &body)).into_response()
+		}
+		_ => StatusCode::NO_CONTENT.into_response(),
+	}
+}
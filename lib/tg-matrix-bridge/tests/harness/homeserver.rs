@@ -0,0 +1,290 @@
+use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+use axum::body::Bytes;
+use axum::extract::Path;
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use axum::response::Response;
+use axum::routing::get;
+use axum::routing::post;
+use axum::routing::put;
+use axum::Json;
+use axum::Router;
+use serde_json::json;
+use serde_json::Value;
+
+pub const SERVER_NAME: &str = "fake.server";
+pub const ROOM_ID: &str = "!room:fake.server";
+pub const BRIDGE_USER_ID: &str = "@bridge:fake.server";
+pub const MODERATOR_USER_ID: &str = "@mod:example.org";
+
+#[derive(Clone, Debug)]
+pub struct SentEvent {
+	pub room_id: String,
+	pub event_type: String,
+	pub txn_id: String,
+	pub event_id: String,
+	pub content: Value,
+}
+
+#[derive(Clone, Debug)]
+pub struct StateEvent {
+	pub room_id: String,
+	pub event_type: String,
+	pub state_key: String,
+	pub content: Value,
+}
+
+#[derive(Default)]
+struct HomeserverState {
+	created_rooms: Vec<Value>,
+	state_events: Vec<StateEvent>,
+	sends: Vec<SentEvent>,
+	send_attempts: Vec<String>,
+	send_failures: VecDeque<(StatusCode, Value)>,
+	events: HashMap<String, Value>,
+	txns: HashMap<(String, String), String>,
+	media: HashMap<String, Vec<u8>>,
+	counter: u64,
+}
+
+type SharedState = Arc<Mutex<HomeserverState>>;
+
+#[derive(Clone)]
+pub struct FakeHomeserver {
+	pub url: String,
+	state: SharedState,
+}
+
+impl FakeHomeserver {
+	pub async fn start() -> Self {
+		let state = SharedState::default();
+		let app = Router::new()
+			.route("/_matrix/client/versions", get(versions))
+			.route("/_matrix/client/v3/sync", get(sync))
+			.route("/_matrix/client/v3/keys/upload", post(keys_upload))
+			.route("/_matrix/client/v3/keys/query", post(keys_query))
+			.route("/_matrix/client/v3/rooms/:room_id/send/:event_type/:txn_id", put(send))
+			.route("/_matrix/client/v3/rooms/:room_id/event/:event_id", get(event))
+			.route(
+				"/_matrix/client/v3/rooms/:room_id/state/:event_type/",
+				put(send_state).get(not_found),
+			)
+			.route(
+				"/_matrix/client/v3/rooms/:room_id/state/:event_type/:state_key",
+				put(send_state_for_key).get(not_found),
+			)
+			.route("/_matrix/client/v3/createRoom", post(create_room))
+			.route("/_matrix/media/v3/upload", post(upload))
+			.route("/_matrix/media/v3/download/:server_name/:media_id", get(download))
+			.fallback(not_found)
+			.with_state(state.clone());
+		let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let url = format!("http://{}", listener.local_addr().unwrap());
+		tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+		Self {
+			url,
+			state,
+		}
+	}
+
+	pub fn add_event(&self, event: Value) {
+		let event_id = event["event_id"].as_str().unwrap().to_string();
+		self.state.lock().unwrap().events.insert(event_id, event);
+	}
+
+	pub fn add_media(&self, mxc_uri: &str, data: &[u8]) {
+		let media_id = mxc_uri.rsplit('/').next().unwrap().to_string();
+		self.state.lock().unwrap().media.insert(media_id, data.to_vec());
+	}
+
+	pub fn fail_next_send(&self, status: StatusCode, body: Value) {
+		self.state.lock().unwrap().send_failures.push_back((status, body));
+	}
+
+	pub fn sends(&self) -> Vec<SentEvent> {
+		self.state.lock().unwrap().sends.clone()
+	}
+
+	pub fn send_attempts(&self) -> Vec<String> {
+		self.state.lock().unwrap().send_attempts.clone()
+	}
+
+	pub fn created_rooms(&self) -> Vec<Value> {
+		self.state.lock().unwrap().created_rooms.clone()
+	}
+
+	pub fn state_events(&self) -> Vec<StateEvent> {
+		self.state.lock().unwrap().state_events.clone()
+	}
+}
+
+fn error(status: StatusCode, errcode: &str) -> Response {
+	(status, Json(json!({ "errcode": errcode, "error": errcode }))).into_response()
+}
+
+async fn not_found() -> Response {
+	error(StatusCode::NOT_FOUND, "M_NOT_FOUND")
+}
+
+async fn versions() -> Json<Value> {
+	Json(json!({ "versions": ["v1.1", "v1.2", "v1.3", "v1.4", "v1.5", "v1.6", "v1.7", "v1.8"] }))
+}
+
+async fn sync() -> Json<Value> {
+	Json(json!({
+		"next_batch": "s1",
+		"rooms": {
+			"join": {
+				(ROOM_ID): {
+					"state": {
+						"events": [
+							{
+								"type": "m.room.create",
+								"state_key": "",
+								"sender": BRIDGE_USER_ID,
+								"event_id": "$create",
+								"origin_server_ts": 0,
+								"content": { "creator": BRIDGE_USER_ID, "room_version": "10" },
+							},
+							{
+								"type": "m.room.power_levels",
+								"state_key": "",
+								"sender": BRIDGE_USER_ID,
+								"event_id": "$power_levels",
+								"origin_server_ts": 0,
+								"content": { "users": { (BRIDGE_USER_ID): 100, (MODERATOR_USER_ID): 50 } },
+							},
+							{
+								"type": "m.room.member",
+								"state_key": BRIDGE_USER_ID,
+								"sender": BRIDGE_USER_ID,
+								"event_id": "$member",
+								"origin_server_ts": 0,
+								"content": { "membership": "join" },
+							},
+						],
+					},
+					"timeline": { "events": [], "limited": false },
+				},
+			},
+		},
+	}))
+}
+
+async fn keys_upload() -> Json<Value> {
+	Json(json!({ "one_time_key_counts": {} }))
+}
+
+async fn keys_query() -> Json<Value> {
+	Json(json!({ "device_keys": {}, "failures": {} }))
+}
+
+async fn send(
+	State(state): State<SharedState>,
+	Path((room_id, event_type, txn_id)): Path<(String, String, String)>,
+	body: Bytes,
+) -> Response {
+	let content: Value = serde_json::from_slice(&body).unwrap_or(Value::Null);
+	let mut state = state.lock().unwrap();
+	state.send_attempts.push(txn_id.clone());
+	if let Some((status, body)) = state.send_failures.pop_front() {
+		return (status, Json(body)).into_response();
+	}
+	if let Some(event_id) = state.txns.get(&(room_id.clone(), txn_id.clone())) {
+		return Json(json!({ "event_id": event_id })).into_response();
+	}
+	state.counter += 1;
+	let event_id = format!("${}:{SERVER_NAME}", state.counter);
+	let event = json!({
+		"type": event_type,
+		"event_id": event_id,
+		"room_id": room_id,
+		"sender": BRIDGE_USER_ID,
+		"origin_server_ts": 1_700_000_000_000u64 + state.counter,
+		"content": content,
+	});
+	state.events.insert(event_id.clone(), event);
+	state.txns.insert((room_id.clone(), txn_id.clone()), event_id.clone());
+	state.sends.push(SentEvent {
+		room_id,
+		event_type,
+		txn_id,
+		event_id: event_id.clone(),
+		content,
+	});
+	Json(json!({ "event_id": event_id })).into_response()
+}
+
+async fn create_room(State(state): State<SharedState>, body: Bytes) -> Json<Value> {
+	let request: Value = serde_json::from_slice(&body).unwrap_or(Value::Null);
+	let mut state = state.lock().unwrap();
+	state.created_rooms.push(request);
+	Json(json!({ "room_id": format!("!portal{}:{SERVER_NAME}", state.created_rooms.len()) }))
+}
+
+fn record_state(
+	state: &SharedState,
+	room_id: String,
+	event_type: String,
+	state_key: String,
+	body: &[u8],
+) -> Json<Value> {
+	let mut state = state.lock().unwrap();
+	state.counter += 1;
+	state.state_events.push(StateEvent {
+		room_id,
+		event_type,
+		state_key,
+		content: serde_json::from_slice(body).unwrap_or(Value::Null),
+	});
+	Json(json!({ "event_id": format!("${}:{SERVER_NAME}", state.counter) }))
+}
+
+async fn send_state(
+	State(state): State<SharedState>,
+	Path((room_id, event_type)): Path<(String, String)>,
+	body: Bytes,
+) -> Json<Value> {
+	record_state(&state, room_id, event_type, String::new(), &body)
+}
+
+async fn send_state_for_key(
+	State(state): State<SharedState>,
+	Path((room_id, event_type, state_key)): Path<(String, String, String)>,
+	body: Bytes,
+) -> Json<Value> {
+	record_state(&state, room_id, event_type, state_key, &body)
+}
+
+async fn event(
+	State(state): State<SharedState>,
+	Path((_room_id, event_id)): Path<(String, String)>,
+) -> Response {
+	match state.lock().unwrap().events.get(&event_id) {
+		Some(event) => Json(event.clone()).into_response(),
+		None => error(StatusCode::NOT_FOUND, "M_NOT_FOUND"),
+	}
+}
+
+async fn upload(State(state): State<SharedState>, body: Bytes) -> Json<Value> {
+	let mut state = state.lock().unwrap();
+	state.counter += 1;
+	let media_id = format!("m{}", state.counter);
+	state.media.insert(media_id.clone(), body.to_vec());
+	Json(json!({ "content_uri": format!("mxc://{SERVER_NAME}/{media_id}") }))
+}
+
+async fn download(
+	State(state): State<SharedState>,
+	Path((_server_name, media_id)): Path<(String, String)>,
+) -> Response {
+	match state.lock().unwrap().media.get(&media_id) {
+		Some(data) => data.clone().into_response(),
+		None => error(StatusCode::NOT_FOUND, "M_NOT_FOUND"),
+	}
+}
@@ -0,0 +1,120 @@
+use std::sync::Arc;
+use std::sync::Mutex;
+
+use tokio::io::AsyncBufReadExt;
+use tokio::io::AsyncWriteExt;
+use tokio::io::BufReader;
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+
+pub const NICK: &str = "bridge";
+pub const PASSWORD: &str = "hunter2";
+pub const CHANNEL: &str = "#bridge";
+pub const MEDIA_URL: &str = "https://media.example.org";
+
+#[derive(Default)]
+struct IrcState {
+	// lines the bridge sent, without the line ending
+	lines: Vec<String>,
+	registered: bool,
+}
+
+type SharedState = Arc<Mutex<IrcState>>;
+
+type ServerLines = Arc<tokio::sync::Mutex<mpsc::UnboundedReceiver<String>>>;
+
+pub struct FakeIrc {
+	pub port: u16,
+	state: SharedState,
+	server_lines: mpsc::UnboundedSender<String>,
+}
+
+impl FakeIrc {
+	pub async fn start() -> Self {
+		let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let port = listener.local_addr().unwrap().port();
+		let state = SharedState::default();
+		let (server_lines, receiver) = mpsc::unbounded_channel();
+		let receiver: ServerLines = Arc::new(tokio::sync::Mutex::new(receiver));
+		let connection_state = state.clone();
+		tokio::spawn(async move {
+			while let Ok((stream, _)) = listener.accept().await {
+				tokio::spawn(connection(stream, connection_state.clone(), receiver.clone()));
+			}
+		});
+		Self {
+			port,
+			state,
+			server_lines,
+		}
+	}
+
+	// a channel message from another irc user
+	pub fn say(&self, nick: &str, text: &str) {
+		let line = format!(":{nick}!{nick}@irc.test PRIVMSG {CHANNEL} :{text}");
+		self.server_lines.send(line).unwrap();
+	}
+
+	pub fn is_registered(&self) -> bool {
+		self.state.lock().unwrap().registered
+	}
+
+	pub fn lines(&self, command: &str) -> Vec<String> {
+		let state = self.state.lock().unwrap();
+		state.lines.iter().filter(|line| line.split(' ').next() == Some(command)).cloned().collect()
+	}
+
+	// the texts of the bridge's channel messages
+	pub fn privmsgs(&self) -> Vec<String> {
+		let prefix = format!("PRIVMSG {CHANNEL} :");
+		let lines = self.lines("PRIVMSG");
+		lines.iter().filter_map(|line| line.strip_prefix(&prefix).map(String::from)).collect()
+	}
+}
+
+async fn connection(stream: TcpStream, state: SharedState, server_lines: ServerLines) {
+	let (reader, mut writer) = stream.into_split();
+	let mut lines = BufReader::new(reader).lines();
+	let mut server_lines = server_lines.lock().await;
+	let mut capabilities = false;
+	loop {
+		let reply = tokio::select! {
+			line = lines.next_line() => {
+				let Ok(Some(line)) = line else {
+					return;
+				};
+				state.lock().unwrap().lines.push(line.clone());
+				let (command, params) = line.split_once(' ').unwrap_or((&line, ""));
+				match command {
+					"CAP" if params == "REQ :sasl" => {
+						capabilities = true;
+						vec![":irc.test CAP * ACK :sasl".to_string()]
+					}
+					"AUTHENTICATE" if params == "PLAIN" => vec!["AUTHENTICATE +".to_string()],
+					"AUTHENTICATE" => vec![format!(":irc.test 903 {NICK} :SASL authentication successful")],
+					"USER" if !capabilities => welcome(&state),
+					"CAP" if params == "END" => welcome(&state),
+					"JOIN" => vec![format!(":{NICK}!{NICK}@irc.test JOIN {params}")],
+					"PING" => vec![format!(":irc.test PONG {params}")],
+					_ => vec![],
+				}
+			}
+			line = server_lines.recv(), if state.lock().unwrap().registered => {
+				let Some(line) = line else {
+					return;
+				};
+				vec![line]
+			}
+		};
+		for line in reply {
+			if writer.write_all(format!("{line}\r\n").as_bytes()).await.is_err() {
+				return;
+			}
+		}
+	}
+}
+
+fn welcome(state: &SharedState) -> Vec<String> {
+	state.lock().unwrap().registered = true;
+	vec![format!(":irc.test 001 {NICK} :Welcome to the test network")]
+}
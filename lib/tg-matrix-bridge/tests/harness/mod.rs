@@ -0,0 +1,263 @@
+#![allow(dead_code)]
+
+mod bot_api;
+pub mod discord;
+pub mod homeserver;
+pub mod irc;
+
+use std::path::PathBuf;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+
+use matrix_sdk::config::RequestConfig;
+use matrix_sdk::config::SyncSettings;
+use matrix_sdk::event_handler::RawEvent;
+use matrix_sdk::matrix_auth::MatrixSession;
+use matrix_sdk::matrix_auth::MatrixSessionTokens;
+use matrix_sdk::ruma::events::AnySyncMessageLikeEvent;
+use matrix_sdk::ruma::EventId;
+use matrix_sdk::ruma::RoomId;
+use matrix_sdk::ruma::UserId;
+use matrix_sdk::Client;
+use matrix_sdk::Room;
+use matrix_sdk::SessionMeta;
+use serde_json::json;
+use serde_json::Value;
+use teloxide::adaptors::throttle::Limits;
+use teloxide::prelude::RequesterExt;
+use teloxide::types::Message;
+use teloxide::Bot;
+
+use tg_matrix_bridge::bridge_structs::Bridge;
+use tg_matrix_bridge::bridge_structs::BridgeConfig;
+use tg_matrix_bridge::bridge_structs::BridgeContext;
+use tg_matrix_bridge::bridge_structs::DiscordBridge;
+use tg_matrix_bridge::bridge_structs::DiscordConfig;
+use tg_matrix_bridge::bridge_structs::EncryptionConfig;
+use tg_matrix_bridge::bridge_structs::IrcBridge;
+use tg_matrix_bridge::bridge_structs::IrcConfig;
+use tg_matrix_bridge::bridge_structs::IrcSasl;
+use tg_matrix_bridge::bridge_structs::PortalConfig;
+use tg_matrix_bridge::db::Store;
+use tg_matrix_bridge::discord::discord_incoming;
+use tg_matrix_bridge::discord::DiscordNetwork;
+use tg_matrix_bridge::irc::irc_incoming;
+use tg_matrix_bridge::irc::IrcNetwork;
+use tg_matrix_bridge::matrix_handlers::client_event_handler;
+use tg_matrix_bridge::telegram::TelegramNetwork;
+use tg_matrix_bridge::tg_handlers;
+
+pub use bot_api::FakeBotApi;
+pub use discord::FakeDiscord;
+pub use homeserver::FakeHomeserver;
+pub use homeserver::BRIDGE_USER_ID;
+pub use homeserver::ROOM_ID;
+pub use irc::FakeIrc;
+
+pub const TG_CHAT_ID: i64 = -1001234567890;
+pub const ADMIN_USER_ID: &str = "@admin:example.org";
+// the telegram user tg_message sends as, allowed to create portals
+pub const TG_ADMIN_ID: u64 = 1111;
+
+static STORE_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+pub struct Harness {
+	pub bot_api: FakeBotApi,
+	pub homeserver: FakeHomeserver,
+	pub discord: Option<FakeDiscord>,
+	pub irc: Option<FakeIrc>,
+	pub ctx: Arc<BridgeContext>,
+	pub room: Room,
+	store_path: PathBuf,
+}
+
+impl Harness {
+	pub async fn new() -> Self {
+		Self::with_bridge(bridge()).await
+	}
+
+	pub async fn with_bridge(bridge: Bridge) -> Self {
+		Self::start(vec![bridge], |_| ()).await
+	}
+
+	// bridges ROOM_ID to a discord channel instead of telegram
+	pub async fn with_discord() -> Self {
+		let discord = FakeDiscord::start().await;
+		let config = DiscordConfig {
+			token: "discord_token".to_string(),
+			bridges: vec![DiscordBridge {
+				mx_id: ROOM_ID.to_string(),
+				channel_id: discord::CHANNEL_ID.to_string(),
+				..DiscordBridge::default()
+			}],
+			api_url: discord.url.clone(),
+		};
+		let mut harness =
+			Self::start(vec![], |bridge_config| bridge_config.discord = Some(config)).await;
+		harness.discord = Some(discord);
+		harness
+	}
+
+	// bridges ROOM_ID to an irc channel instead of telegram
+	pub async fn with_irc(bridge: IrcBridge) -> Self {
+		let irc = FakeIrc::start().await;
+		let config = IrcConfig {
+			server: "127.0.0.1".to_string(),
+			port: irc.port,
+			tls: false,
+			nick: irc::NICK.to_string(),
+			sasl: Some(IrcSasl {
+				username: irc::NICK.to_string(),
+				password: irc::PASSWORD.to_string(),
+			}),
+			media_url: irc::MEDIA_URL.to_string(),
+			bridges: vec![bridge],
+		};
+		let mut harness =
+			Self::start(vec![], |bridge_config| bridge_config.irc = Some(config)).await;
+		harness.irc = Some(irc);
+		harness
+	}
+
+	async fn start(bridges: Vec<Bridge>, configure: impl FnOnce(&mut BridgeConfig)) -> Self {
+		let bot_api = FakeBotApi::start().await;
+		let homeserver = FakeHomeserver::start().await;
+
+		let bot = Bot::new("123:TEST").set_api_url(bot_api.url.clone()).throttle(Limits::default());
+		let request_config = RequestConfig::new().retry_timeout(Duration::from_secs(5));
+		let client = Client::builder()
+			.homeserver_url(&homeserver.url)
+			.request_config(request_config)
+			.build()
+			.await
+			.unwrap();
+		let session = MatrixSession {
+			meta: SessionMeta {
+				user_id: UserId::parse(BRIDGE_USER_ID).unwrap(),
+				device_id: "BRIDGEDEVICE".into(),
+			},
+			tokens: MatrixSessionTokens {
+				access_token: "access_token".to_string(),
+				refresh_token: None,
+			},
+		};
+		client.matrix_auth().restore_session(session).await.unwrap();
+		client.sync_once(SyncSettings::default()).await.unwrap();
+		let room = client.get_room(&RoomId::parse(ROOM_ID).unwrap()).unwrap();
+
+		let store_path = std::env::temp_dir().join(format!(
+			"tg-matrix-bridge-test-{}-{}",
+			std::process::id(),
+			STORE_COUNTER.fetch_add(1, Ordering::SeqCst)
+		));
+		let mut config = BridgeConfig {
+			bridges,
+			webhook_url: String::new(),
+			portals: Some(PortalConfig {
+				space_id: None,
+				admins: vec![ADMIN_USER_ID.to_string()],
+				allowed_tg_users: vec![TG_ADMIN_ID],
+			}),
+			encryption: EncryptionConfig {
+				recovery_key: None,
+				admins: vec![ADMIN_USER_ID.to_string()],
+			},
+			discord: None,
+			irc: None,
+		};
+		configure(&mut config);
+		let store = Store::new(&store_path).unwrap();
+		let ctx = Arc::new(BridgeContext::new(bot, Arc::new(client), store, config));
+		tokio::spawn(tg_handlers::tg_incoming(ctx.clone()));
+		tokio::spawn(discord_incoming(ctx.clone()));
+		tokio::spawn(irc_incoming(ctx.clone()));
+		Self {
+			bot_api,
+			homeserver,
+			discord: None,
+			irc: None,
+			ctx,
+			room,
+			store_path,
+		}
+	}
+}
+
+impl Drop for Harness {
+	fn drop(&mut self) {
+		let _ = std::fs::remove_dir_all(&self.store_path);
+	}
+}
+
+pub fn bridge() -> Bridge {
+	Bridge {
+		mx_id: ROOM_ID.to_string(),
+		tg_id: TG_CHAT_ID,
+		..Bridge::default()
+	}
+}
+
+pub fn irc_bridge() -> IrcBridge {
+	IrcBridge {
+		mx_id: ROOM_ID.to_string(),
+		channel: irc::CHANNEL.to_string(),
+		..IrcBridge::default()
+	}
+}
+
+pub fn tg_message(message_id: i32, fields: Value) -> Message {
+	let mut message = json!({
+		"message_id": message_id,
+		"date": 1_700_000_000,
+		"chat": { "id": TG_CHAT_ID, "type": "supergroup", "title": "bridge test" },
+		"from": { "id": 1111, "is_bot": false, "first_name": "Alice" },
+	});
+	for (key, value) in fields.as_object().unwrap() {
+		message[key] = value.clone();
+	}
+	serde_json::from_value(message).unwrap()
+}
+
+pub fn mx_event(event_id: &str, event_type: &str, content: Value) -> Value {
+	json!({
+		"type": event_type,
+		"event_id": event_id,
+		"room_id": ROOM_ID,
+		"sender": "@bob:example.org",
+		"origin_server_ts": 1_700_000_000_000u64,
+		"content": content,
+	})
+}
+
+pub fn sync_event(event: &Value) -> (AnySyncMessageLikeEvent, RawEvent) {
+	let ev = serde_json::from_value(event.clone()).unwrap();
+	let raw = RawEvent(serde_json::value::to_raw_value(event).unwrap());
+	(ev, raw)
+}
+
+pub async fn send_mx_event(h: &Harness, event: &Value) {
+	h.homeserver.add_event(event.clone());
+	let (ev, raw) = sync_event(event);
+	client_event_handler(ev, raw, h.room.clone(), h.ctx.clone()).await;
+}
+
+// whether a matrix event in ROOM_ID was bridged to any network
+pub fn mx_bridged(h: &Harness, event_id: &str) -> bool {
+	let event_id = EventId::parse(event_id).unwrap();
+	let store = &h.ctx.store;
+	store.find_remote_id::<TelegramNetwork>(ROOM_ID, &event_id).is_some()
+		|| store.find_remote_id::<DiscordNetwork>(ROOM_ID, &event_id).is_some()
+		|| store.find_remote_id::<IrcNetwork>(ROOM_ID, &event_id).is_some()
+}
+
+pub async fn wait_until(condition: impl Fn() -> bool) {
+	for _ in 0..200 {
+		if condition() {
+			return;
+		}
+		tokio::time::sleep(Duration::from_millis(50)).await;
+	}
+	panic!("condition not met in time");
+}
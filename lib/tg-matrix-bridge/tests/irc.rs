@@ -0,0 +1,189 @@
+mod harness;
+
+use serde_json::json;
+
+use harness::irc::CHANNEL;
+use harness::irc::MEDIA_URL;
+use harness::irc_bridge;
+use harness::mx_event;
+use harness::send_mx_event;
+use harness::wait_until;
+use harness::FakeIrc;
+use harness::Harness;
+use tg_matrix_bridge::bridge_structs::IrcBridge;
+use tg_matrix_bridge::templates::SenderTemplates;
+
+fn irc(h: &Harness) -> &FakeIrc {
+	h.irc.as_ref().unwrap()
+}
+
+async fn send_mx_text(h: &Harness, event_id: &str, body: &str) {
+	let event = mx_event(event_id, "m.room.message", json!({ "msgtype": "m.text", "body": body }));
+	send_mx_event(h, &event).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn irc_registers_with_sasl_and_joins() {
+	let h = Harness::with_irc(irc_bridge()).await;
+	let irc = irc(&h);
+	wait_until(|| !irc.lines("JOIN").is_empty()).await;
+
+	assert_eq!(irc.lines("CAP"), ["CAP REQ :sasl", "CAP END"]);
+	assert_eq!(
+		irc.lines("AUTHENTICATE"),
+		["AUTHENTICATE PLAIN", "AUTHENTICATE AGJyaWRnZQBodW50ZXIy"]
+	);
+	assert_eq!(irc.lines("NICK"), ["NICK bridge"]);
+	assert_eq!(irc.lines("JOIN"), [format!("JOIN {CHANNEL}")]);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn irc_messages_reach_matrix() {
+	let h = Harness::with_irc(irc_bridge()).await;
+	irc(&h).say("alice", "hello \x02matrix\x02 \x0304,01in red\x03");
+	irc(&h).say("alice", "\x01ACTION waves\x01");
+	wait_until(|| h.homeserver.sends().len() == 2).await;
+
+	let sends = h.homeserver.sends();
+	assert_eq!(sends[0].content["body"], "alice: hello matrix in red");
+	assert_eq!(sends[1].content["body"], "alice: * waves");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn irc_channels_match_case_insensitively() {
+	let bridge = IrcBridge {
+		channel: CHANNEL.to_uppercase(),
+		..irc_bridge()
+	};
+	let h = Harness::with_irc(bridge).await;
+	irc(&h).say("alice", "hello");
+	wait_until(|| h.homeserver.sends().len() == 1).await;
+
+	assert_eq!(h.homeserver.sends()[0].content["body"], "alice: hello");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn matrix_text_reaches_irc() {
+	let h = Harness::with_irc(irc_bridge()).await;
+	send_mx_text(&h, "$m1:example.org", "hello irc\nsecond line").await;
+	wait_until(|| irc(&h).privmsgs().len() == 2).await;
+
+	assert_eq!(
+		irc(&h).privmsgs(),
+		["@bob:example.org: hello irc", "@bob:example.org: second line"]
+	);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn long_matrix_message_is_split_into_lines() {
+	let h = Harness::with_irc(irc_bridge()).await;
+	let words = (0..200).map(|i| format!("word{i}")).collect::<Vec<_>>();
+	send_mx_text(&h, "$m1:example.org", &words.join(" ")).await;
+	wait_until(|| irc(&h).privmsgs().len() > 1).await;
+	tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+
+	let lines = irc(&h).lines("PRIVMSG");
+	assert!(lines.iter().all(|line| line.len() <= 410));
+	let texts = irc(&h)
+		.privmsgs()
+		.iter()
+		.map(|line| line.strip_prefix("@bob:example.org: ").unwrap().to_string())
+		.collect::<Vec<_>>();
+	assert_eq!(texts.join(" "), words.join(" "));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn long_sender_names_are_truncated() {
+	let bridge = IrcBridge {
+		templates: SenderTemplates {
+			text: "{name} ({username}): {text}".to_string(),
+			..SenderTemplates::default()
+		},
+		..irc_bridge()
+	};
+	let h = Harness::with_irc(bridge).await;
+	let mut event = mx_event(
+		"$m1:example.org",
+		"m.room.message",
+		json!({ "msgtype": "m.text", "body": "héllo wörld" }),
+	);
+	event["sender"] = json!(format!("@{}:example.org", "a".repeat(200)));
+	send_mx_event(&h, &event).await;
+	wait_until(|| !irc(&h).privmsgs().is_empty()).await;
+
+	let lines = irc(&h).lines("PRIVMSG");
+	assert_eq!(lines.len(), 1);
+	assert!(lines[0].len() <= 410);
+	assert!(lines[0].ends_with("…): héllo wörld"));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn tiny_line_budget_still_splits_multibyte_text() {
+	let bridge = IrcBridge {
+		templates: SenderTemplates {
+			text: format!("{}{{text}}", "x".repeat(450)),
+			..SenderTemplates::default()
+		},
+		..irc_bridge()
+	};
+	let h = Harness::with_irc(bridge).await;
+	send_mx_text(&h, "$m1:example.org", "héllo wörld, héllo").await;
+	wait_until(|| irc(&h).privmsgs().len() == 10).await;
+
+	let privmsgs = irc(&h).privmsgs();
+	assert!(privmsgs[1].ends_with('é'));
+	assert!(privmsgs[9].ends_with("(message truncated)"));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn matrix_media_is_linked() {
+	let h = Harness::with_irc(irc_bridge()).await;
+	let event = mx_event(
+		"$m1:example.org",
+		"m.room.message",
+		json!({ "msgtype": "m.image", "body": "cat.png", "url": "mxc://example.org/cat" }),
+	);
+	send_mx_event(&h, &event).await;
+	wait_until(|| !irc(&h).privmsgs().is_empty()).await;
+
+	let link = format!("{MEDIA_URL}/example.org/cat");
+	assert_eq!(irc(&h).privmsgs(), [format!("@bob:example.org: cat.png {link}")]);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn matrix_edit_is_sent_as_correction() {
+	let h = Harness::with_irc(irc_bridge()).await;
+	send_mx_text(&h, "$m1:example.org", "tpyo").await;
+	let edit = mx_event(
+		"$m2:example.org",
+		"m.room.message",
+		json!({
+			"msgtype": "m.text",
+			"body": "* typo",
+			"m.new_content": { "msgtype": "m.text", "body": "typo" },
+			"m.relates_to": { "rel_type": "m.replace", "event_id": "$m1:example.org" },
+		}),
+	);
+	send_mx_event(&h, &edit).await;
+	wait_until(|| irc(&h).privmsgs().len() == 2).await;
+
+	assert_eq!(irc(&h).privmsgs()[1], "@bob:example.org: * typo");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn bold_names_are_coloured_on_irc() {
+	let bridge = IrcBridge {
+		templates: SenderTemplates {
+			bold_name: true,
+			..SenderTemplates::default()
+		},
+		..irc_bridge()
+	};
+	let h = Harness::with_irc(bridge).await;
+	send_mx_text(&h, "$m1:example.org", "hi").await;
+	wait_until(|| !irc(&h).privmsgs().is_empty()).await;
+
+	let line = &irc(&h).privmsgs()[0];
+	assert!(line.starts_with("\x02\x03"));
+	assert!(line.ends_with("@bob:example.org\x03\x02: hi"));
+}
@@ -0,0 +1,70 @@
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::sync::oneshot;
+
+use tg_matrix_bridge::queue::ChatQueues;
+use tg_matrix_bridge::queue::QUEUE_CAPACITY;
+
+async fn wait_for(count: &AtomicUsize, expected: usize) {
+	for _ in 0..200 {
+		if count.load(Ordering::SeqCst) == expected {
+			return;
+		}
+		tokio::time::sleep(Duration::from_millis(10)).await;
+	}
+	panic!("expected {expected} jobs, ran {}", count.load(Ordering::SeqCst));
+}
+
+#[tokio::test]
+async fn panicking_job_does_not_stop_the_queue() {
+	let queues = ChatQueues::default();
+	let done = Arc::new(AtomicUsize::new(0));
+	queues.enqueue("chat", async { panic!("broken job") }).await;
+	let counter = done.clone();
+	queues
+		.enqueue("chat", async move {
+			counter.fetch_add(1, Ordering::SeqCst);
+			Ok(())
+		})
+		.await;
+	wait_for(&done, 1).await;
+}
+
+#[tokio::test]
+async fn full_queue_waits_instead_of_dropping_jobs() {
+	let queues = Arc::new(ChatQueues::default());
+	let done = Arc::new(AtomicUsize::new(0));
+	let (unblock, blocked) = oneshot::channel::<()>();
+	let (started, running) = oneshot::channel::<()>();
+	queues
+		.enqueue("chat", async move {
+			let _ = started.send(());
+			let _ = blocked.await;
+			Ok(())
+		})
+		.await;
+	running.await.unwrap();
+	let enqueuer = tokio::spawn({
+		let (queues, done) = (queues.clone(), done.clone());
+		async move {
+			for _ in 0..QUEUE_CAPACITY + 10 {
+				let counter = done.clone();
+				queues
+					.enqueue("chat", async move {
+						counter.fetch_add(1, Ordering::SeqCst);
+						Ok(())
+					})
+					.await;
+			}
+		}
+	});
+	tokio::time::sleep(Duration::from_millis(50)).await;
+	assert!(!enqueuer.is_finished());
+
+	unblock.send(()).unwrap();
+	enqueuer.await.unwrap();
+	wait_for(&done, QUEUE_CAPACITY + 10).await;
+}
@@ -2,6 +2,8 @@ use anyhow::bail;
 use matrix_sdk::matrix_auth::LoginBuilder;
 use matrix_sdk::ruma::api::client::message::send_message_event::v3::Response;
 use matrix_sdk::ruma::events::room::message::RoomMessageEventContent;
+use matrix_sdk::ruma::OwnedTransactionId;
+use matrix_sdk::ruma::TransactionId;
 use matrix_sdk::Room;
 use std::fs::File;
 use std::io::Read;
@@ -27,8 +29,16 @@ pub async fn read_or_create_device_id(
 }
 
 pub async fn send(room: Arc<Room>, content: RoomMessageEventContent) -> anyhow::Result<Response> {
+	send_with_transaction_id(room, content, TransactionId::new()).await
+}
+
+pub async fn send_with_transaction_id(
+	room: Arc<Room>,
+	content: RoomMessageEventContent,
+	txn_id: OwnedTransactionId,
+) -> anyhow::Result<Response> {
 	loop {
-		match room.send(content.clone()).await {
+		match room.send(content.clone()).with_transaction_id(txn_id.clone()).await {
 			Ok(response) => return Ok(response),
 			Err(err) => match err {
 				matrix_sdk::Error::Http(matrix_sdk::HttpError::Reqwest(err)) => {